@@ -0,0 +1,114 @@
+/*
+ * C interface to the scimitar Game Boy emulator, for driving it from scripts
+ * and other languages. Link against the scimitar library built by
+ * cargo build --release -p scimitar_capi.
+ *
+ * A scimitar_t runs without a window, sound or debugger console. Nothing
+ * happens between calls, the host drives emulation with scimitar_run_frame
+ * or scimitar_run_cycles.
+ *
+ * The emulator's own messages still go to stdout as they do for the
+ * binary, e.g. scimitar_new reports the symbols, patches and cheats it picks
+ * up, and a panic is printed to stderr before it's caught. Don't parse them:
+ * calls that fail say so in their return value, with the reason kept for
+ * scimitar_new_error or scimitar_last_error. If emulation crashes, e.g. on an
+ * illegal opcode, the machine is left stopped: scimitar_run_frame,
+ * scimitar_run_cycles and scimitar_write_memory do nothing and return 0 or
+ * false until a state is loaded with scimitar_load_state.
+ */
+
+#ifndef SCIMITAR_H
+#define SCIMITAR_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define SCIMITAR_WIDTH 160
+#define SCIMITAR_HEIGHT 144
+
+/* Button masks for scimitar_set_buttons */
+#define SCIMITAR_BUTTON_RIGHT  0x01
+#define SCIMITAR_BUTTON_LEFT   0x02
+#define SCIMITAR_BUTTON_UP     0x04
+#define SCIMITAR_BUTTON_DOWN   0x08
+#define SCIMITAR_BUTTON_A      0x10
+#define SCIMITAR_BUTTON_B      0x20
+#define SCIMITAR_BUTTON_SELECT 0x40
+#define SCIMITAR_BUTTON_START  0x80
+
+typedef struct scimitar scimitar_t;
+
+/*
+ * Loads a ROM and a boot ROM from the given paths, returning NULL if either
+ * can't be loaded. ROMs can be zipped or gzipped, and patches, cheats and
+ * symbols next to the ROM are picked up as they are by the emulator binary.
+ */
+scimitar_t *scimitar_new(const char *rom, const char *bootrom);
+
+/*
+ * Why the last scimitar_new on this thread returned NULL, or NULL if it
+ * succeeded. The string is valid until the next scimitar_new on the thread.
+ */
+const char *scimitar_new_error(void);
+void scimitar_free(scimitar_t *gb);
+
+/*
+ * Runs until the next VBlank starts, or for a frame's worth of cycles if the
+ * LCD is off, returning the number of cycles run, or 0 if emulation crashed.
+ */
+uint32_t scimitar_run_frame(scimitar_t *gb);
+
+/*
+ * Runs for at least the given number of cycles, returning how many were run,
+ * or 0 if emulation crashed
+ */
+uint32_t scimitar_run_cycles(scimitar_t *gb, uint32_t cycles);
+
+/* Sets the buttons held down, a combination of SCIMITAR_BUTTON_ masks */
+void scimitar_set_buttons(scimitar_t *gb, uint8_t buttons);
+
+/*
+ * The frame being drawn, SCIMITAR_WIDTH * SCIMITAR_HEIGHT 0xAARRGGBB pixels
+ * in rows. Holds a complete frame after scimitar_run_frame. The pointer is
+ * valid until gb is next run or freed.
+ */
+const uint32_t *scimitar_framebuffer(const scimitar_t *gb);
+
+/*
+ * Reads or writes len bytes of memory starting at addr, as the CPU sees it.
+ * Addresses wrap around at 0xffff, and writes to ROM go to the MBC
+ * registers. scimitar_write_memory returns false if data is NULL or
+ * emulation crashed.
+ */
+void scimitar_read_memory(const scimitar_t *gb, uint16_t addr, uint8_t *buf, size_t len);
+bool scimitar_write_memory(scimitar_t *gb, uint16_t addr, const uint8_t *data, size_t len);
+
+/* The size of buffer scimitar_save_state needs, fixed for a given ROM */
+size_t scimitar_state_size(const scimitar_t *gb);
+
+/*
+ * Saves the whole machine into buf, returning false if len is less than
+ * scimitar_state_size. States can only be loaded with the same ROM.
+ * scimitar_load_state returns false if the state can't be loaded, and
+ * clears a crash when it succeeds.
+ */
+bool scimitar_save_state(const scimitar_t *gb, uint8_t *buf, size_t len);
+bool scimitar_load_state(scimitar_t *gb, const uint8_t *buf, size_t len);
+
+/*
+ * Why the last call that failed did, e.g. a state for another ROM or the
+ * message emulation crashed with, or NULL if nothing has failed. The string
+ * is valid until the next call that fails or gb is freed.
+ */
+const char *scimitar_last_error(const scimitar_t *gb);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
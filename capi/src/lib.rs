@@ -0,0 +1,252 @@
+// The C interface declared in include/scimitar.h. Every function takes the
+// handle returned by scimitar_new, which the caller must not use after
+// passing it to scimitar_free.
+
+// The contracts are documented in the header rather than here
+#![allow(clippy::missing_safety_doc)]
+
+extern crate gameboy;
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic;
+use std::ptr;
+use std::slice;
+use gameboy::Gameboy;
+use gameboy::device::{Device, Key};
+use gameboy::input::Buttons;
+use gameboy::vm::VM;
+
+thread_local! {
+    // Why the last scimitar_new on this thread failed, for
+    // scimitar_new_error, as there's no machine to keep it in
+    static NEW_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_new_error(message: Option<&str>) {
+    NEW_ERROR.with(|e| *e.borrow_mut() = message.and_then(c_message));
+}
+
+fn c_message(message: &str) -> Option<CString> {
+    // A message with a nul in it would be cut short in C anyway
+    CString::new(message.split('\0').next().unwrap_or("")).ok()
+}
+
+pub struct Scimitar {
+    vm: VM,
+    device: ScriptDevice,
+    // Why the last call failed, for scimitar_last_error
+    error: Option<CString>,
+    // Set when emulation panicked, e.g. on an illegal opcode. The machine
+    // may be half way through an instruction, so it isn't run again until a
+    // state is loaded.
+    crashed: bool,
+}
+
+impl Scimitar {
+    fn set_error(&mut self, message: &str) {
+        self.error = c_message(message);
+    }
+
+    // Runs f with panics caught rather than unwinding into the host,
+    // returning failed if it panics or the machine has already crashed
+    fn guard<T, F: FnOnce(&mut Scimitar) -> T>(&mut self, failed: T, f: F) -> T {
+        if self.crashed {
+            return failed;
+        }
+
+        match panic::catch_unwind(panic::AssertUnwindSafe(|| f(self))) {
+            Ok(result) => result,
+            Err(payload) => {
+                self.crashed = true;
+                self.set_error(&panic_message(&*payload));
+                failed
+            }
+        }
+    }
+}
+
+fn panic_message(payload: &(Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "emulation panicked".to_owned()
+    }
+}
+
+// Reports whatever buttons the host last set
+struct ScriptDevice {
+    buttons: Buttons,
+}
+
+impl Device for ScriptDevice {
+    fn update(&mut self) {}
+
+    fn set_frame_buffer(&mut self, _: &[u32]) {}
+
+    fn key_down(&self, _: Key) -> bool {
+        false
+    }
+
+    fn buttons(&mut self) -> Option<Buttons> {
+        Some(self.buttons)
+    }
+
+    fn running(&self) -> bool {
+        true
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn scimitar_new(rom: *const c_char, bootrom: *const c_char) -> *mut Scimitar {
+    if rom.is_null() || bootrom.is_null() {
+        set_new_error(Some("No ROM or boot ROM path given"));
+        return ptr::null_mut();
+    }
+    let (rom, bootrom) = match (CStr::from_ptr(rom).to_str(), CStr::from_ptr(bootrom).to_str()) {
+        (Ok(rom), Ok(bootrom)) => (rom, bootrom),
+        _ => {
+            set_new_error(Some("ROM and boot ROM paths must be UTF-8"));
+            return ptr::null_mut();
+        }
+    };
+
+    // The builder panics on files it can't load, which mustn't unwind into
+    // the host
+    let vm = panic::catch_unwind(|| {
+        Gameboy::default()
+            .with_cartridge(Some(rom))
+            .with_boot_rom(Some(bootrom))
+            .without_console(true)
+            .build()
+    });
+
+    match vm {
+        Ok(vm) => {
+            set_new_error(None);
+            Box::into_raw(Box::new(Scimitar {
+                vm: vm,
+                device: ScriptDevice { buttons: Buttons::default() },
+                error: None,
+                crashed: false,
+            }))
+        }
+        Err(payload) => {
+            set_new_error(Some(&panic_message(&*payload)));
+            ptr::null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn scimitar_free(gb: *mut Scimitar) {
+    if !gb.is_null() {
+        drop(Box::from_raw(gb));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn scimitar_run_frame(gb: *mut Scimitar) -> u32 {
+    (*gb).guard(0, |gb| gb.vm.run_frame(&mut gb.device).cycles)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn scimitar_run_cycles(gb: *mut Scimitar, cycles: u32) -> u32 {
+    (*gb).guard(0, |gb| gb.vm.run_cycles(&mut gb.device, cycles).cycles)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn scimitar_set_buttons(gb: *mut Scimitar, buttons: u8) {
+    (*gb).device.buttons = Buttons(buttons);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn scimitar_framebuffer(gb: *const Scimitar) -> *const u32 {
+    (*gb).vm.frame_buffer().as_ptr()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn scimitar_read_memory(gb: *const Scimitar, addr: u16, buf: *mut u8, len: usize) {
+    if buf.is_null() {
+        return;
+    }
+
+    let buf = slice::from_raw_parts_mut(buf, len);
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = (*gb).vm.read_byte(addr.wrapping_add(i as u16));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn scimitar_write_memory(gb: *mut Scimitar, addr: u16, data: *const u8, len: usize) -> bool {
+    if data.is_null() {
+        return false;
+    }
+
+    let data = slice::from_raw_parts(data, len);
+    (*gb).guard(false, |gb| {
+        for (i, byte) in data.iter().enumerate() {
+            gb.vm.write_byte(addr.wrapping_add(i as u16), *byte);
+        }
+        true
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn scimitar_state_size(gb: *const Scimitar) -> usize {
+    (*gb).vm.save_state().len()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn scimitar_save_state(gb: *const Scimitar, buf: *mut u8, len: usize) -> bool {
+    let state = (*gb).vm.save_state();
+    if buf.is_null() || len < state.len() {
+        return false;
+    }
+
+    ptr::copy_nonoverlapping(state.as_ptr(), buf, state.len());
+    true
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn scimitar_load_state(gb: *mut Scimitar, buf: *const u8, len: usize) -> bool {
+    if buf.is_null() {
+        return false;
+    }
+
+    // Loading replaces the whole machine, so a good state recovers from a
+    // crash
+    let gb = &mut *gb;
+    let crashed = gb.crashed;
+    gb.crashed = false;
+    let data = slice::from_raw_parts(buf, len);
+    match gb.guard(None, |gb| Some(gb.vm.load_state(data))) {
+        Some(Ok(())) => true,
+        Some(Err(e)) => {
+            gb.crashed = crashed;
+            gb.set_error(&format!("Failed to load state: {}", e));
+            false
+        }
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn scimitar_new_error() -> *const c_char {
+    NEW_ERROR.with(|e| match *e.borrow() {
+        Some(ref error) => error.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn scimitar_last_error(gb: *const Scimitar) -> *const c_char {
+    match (*gb).error {
+        Some(ref error) => error.as_ptr(),
+        None => ptr::null(),
+    }
+}
@@ -0,0 +1,563 @@
+// A libretro core wrapping the emulator, so it can be run by any libretro
+// frontend. Build it with cargo build -p scimitar_libretro and load the
+// resulting scimitar_libretro library into the frontend. The boot ROM is
+// looked for in the frontend's system directory under the names lookup uses,
+// e.g. dmg_boot.bin.
+
+// The exported functions are only called by frontends, which follow the
+// contracts in libretro.h
+#![allow(clippy::missing_safety_doc)]
+
+extern crate gameboy;
+
+mod api;
+
+use std::any::Any;
+use std::cmp;
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io::Read;
+use std::mem;
+use std::os::raw::{c_char, c_uint, c_void};
+use std::panic;
+use std::path::Path;
+use std::ptr;
+use std::slice;
+use std::sync::Mutex;
+use gameboy::bootrom::Bootrom;
+use gameboy::cartridge::Cartridge;
+use gameboy::cheats::Cheats;
+use gameboy::config::model::DEFAULT_MODEL_PRIORITY;
+use gameboy::device::{Device, Key};
+use gameboy::input::{Button, Buttons};
+use gameboy::interconnect::Interconnect;
+use gameboy::symbols::Symbols;
+use gameboy::vm::VM;
+use api::*;
+
+const WIDTH: c_uint = 160;
+const HEIGHT: c_uint = 144;
+
+const CLOCK_RATE: u64 = 4194304;
+const CYCLES_PER_FRAME: u64 = 70224;
+const SAMPLE_RATE: u32 = 44100;
+
+// Anything shorter can't hold a cartridge header
+const MIN_ROM_SIZE: usize = 0x150;
+
+// The cartridge RAM window at 0xa000
+const CART_RAM_START: usize = 0xa000;
+const CART_RAM_WINDOW: usize = 0x2000;
+const WORK_RAM_START: usize = 0xc000;
+
+static JOYPAD_BUTTONS: [(c_uint, Button); 8] = [
+    (RETRO_DEVICE_ID_JOYPAD_A, Button::A),
+    (RETRO_DEVICE_ID_JOYPAD_B, Button::B),
+    (RETRO_DEVICE_ID_JOYPAD_START, Button::Start),
+    (RETRO_DEVICE_ID_JOYPAD_SELECT, Button::Select),
+    (RETRO_DEVICE_ID_JOYPAD_UP, Button::Up),
+    (RETRO_DEVICE_ID_JOYPAD_DOWN, Button::Down),
+    (RETRO_DEVICE_ID_JOYPAD_LEFT, Button::Left),
+    (RETRO_DEVICE_ID_JOYPAD_RIGHT, Button::Right),
+];
+
+#[derive(Clone, Copy)]
+struct Callbacks {
+    environment: Option<EnvironmentFn>,
+    video_refresh: Option<VideoRefreshFn>,
+    audio_sample_batch: Option<AudioSampleBatchFn>,
+    input_poll: Option<InputPollFn>,
+    input_state: Option<InputStateFn>,
+    log: Option<LogPrintfFn>,
+}
+
+struct Core {
+    vm: VM,
+    // The state straight after loading, which retro_reset goes back to
+    power_on: Vec<u8>,
+    // Set once emulation panics, after which the core stops running until
+    // it's reset or a state is loaded
+    crashed: bool,
+}
+
+// Frontends call into the core from a single thread, the locks are only here
+// because statics have to be Sync
+static CALLBACKS: Mutex<Callbacks> = Mutex::new(Callbacks {
+    environment: None,
+    video_refresh: None,
+    audio_sample_batch: None,
+    input_poll: None,
+    input_state: None,
+    log: None,
+});
+static CORE: Mutex<Option<Core>> = Mutex::new(None);
+
+fn callbacks() -> Callbacks {
+    *CALLBACKS.lock().unwrap()
+}
+
+// Sends a line to the frontend's log, or stdout if it doesn't have one
+fn log(level: c_uint, message: &str) {
+    match (callbacks().log, CString::new(message)) {
+        (Some(log), Ok(line)) => unsafe { log(level, b"%s\n\0".as_ptr() as *const c_char, line.as_ptr()) },
+        _ => println!("{}", message),
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "emulation panicked".to_owned()
+    }
+}
+
+// Reports the frontend's joypad 1 as the Game Boy's buttons
+struct RetroDevice {
+    input_state: Option<InputStateFn>,
+}
+
+impl Device for RetroDevice {
+    fn update(&mut self) {}
+
+    fn set_frame_buffer(&mut self, _: &[u32]) {}
+
+    fn key_down(&self, _: Key) -> bool {
+        false
+    }
+
+    fn buttons(&mut self) -> Option<Buttons> {
+        let mut buttons = Buttons::default();
+        if let Some(input_state) = self.input_state {
+            for &(id, button) in &JOYPAD_BUTTONS {
+                if input_state(0, RETRO_DEVICE_JOYPAD, 0, id) != 0 {
+                    buttons.insert(button);
+                }
+            }
+        }
+        Some(buttons)
+    }
+
+    fn running(&self) -> bool {
+        true
+    }
+}
+
+impl Core {
+    // Runs f with panics caught rather than unwinding into the frontend,
+    // returning failed if it panics or the core has already crashed
+    fn guard<T, F: FnOnce(&mut Core) -> T>(&mut self, failed: T, f: F) -> T {
+        if self.crashed {
+            return failed;
+        }
+
+        match panic::catch_unwind(panic::AssertUnwindSafe(|| f(self))) {
+            Ok(result) => result,
+            Err(payload) => {
+                self.crashed = true;
+                log(RETRO_LOG_ERROR, &format!("Emulation stopped: {}", panic_message(&*payload)));
+                failed
+            }
+        }
+    }
+
+    // Sends the samples generated during the frame, which the frontend may
+    // take in several batches
+    fn output_audio(&self, audio_sample_batch: AudioSampleBatchFn) {
+        let samples = self.vm.audio_samples();
+        let frames = samples.len() / 2;
+        let mut sent = 0;
+        while sent < frames {
+            let written = audio_sample_batch(samples[sent * 2..].as_ptr(), frames - sent);
+            if written == 0 {
+                break;
+            }
+            sent += written;
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut SystemInfo) {
+    *info = SystemInfo {
+        library_name: b"Scimitar\0".as_ptr() as *const c_char,
+        library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+        valid_extensions: b"gb|dmg\0".as_ptr() as *const c_char,
+        need_fullpath: false,
+        block_extract: false,
+    };
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut SystemAvInfo) {
+    *info = SystemAvInfo {
+        geometry: GameGeometry {
+            base_width: WIDTH,
+            base_height: HEIGHT,
+            max_width: WIDTH,
+            max_height: HEIGHT,
+            aspect_ratio: WIDTH as f32 / HEIGHT as f32,
+        },
+        timing: SystemTiming {
+            fps: CLOCK_RATE as f64 / CYCLES_PER_FRAME as f64,
+            sample_rate: SAMPLE_RATE as f64,
+        },
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(cb: EnvironmentFn) {
+    let mut log = LogCallback { log: None };
+    if !cb(RETRO_ENVIRONMENT_GET_LOG_INTERFACE, &mut log as *mut LogCallback as *mut c_void) {
+        log.log = None;
+    }
+
+    let mut callbacks = CALLBACKS.lock().unwrap();
+    callbacks.environment = Some(cb);
+    callbacks.log = log.log;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(cb: VideoRefreshFn) {
+    CALLBACKS.lock().unwrap().video_refresh = Some(cb);
+}
+
+// Samples are only ever sent in batches
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_: AudioSampleFn) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(cb: AudioSampleBatchFn) {
+    CALLBACKS.lock().unwrap().audio_sample_batch = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(cb: InputPollFn) {
+    CALLBACKS.lock().unwrap().input_poll = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(cb: InputStateFn) {
+    CALLBACKS.lock().unwrap().input_state = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    *CORE.lock().unwrap() = None;
+}
+
+// There's only the one joypad, so whatever the frontend plugs in is treated
+// as one
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_: c_uint, _: c_uint) {}
+
+// Goes back to power-on, keeping the cartridge RAM as a real reset would.
+// This also gets the core going again after a crash.
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    let mut core = CORE.lock().unwrap();
+    if let Some(ref mut core) = *core {
+        let crashed = mem::replace(&mut core.crashed, false);
+        let reset = core.guard(false, |core| {
+            let cart_ram = core.vm.cart_ram_mut().to_vec();
+            let result = core.vm.load_state(&core.power_on);
+            core.vm.cart_ram_mut().copy_from_slice(&cart_ram);
+            match result {
+                Ok(()) => true,
+                Err(e) => {
+                    log(RETRO_LOG_ERROR, &format!("Failed to reset: {}", e));
+                    false
+                }
+            }
+        });
+        if !reset {
+            core.crashed |= crashed;
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    let callbacks = callbacks();
+    if let Some(input_poll) = callbacks.input_poll {
+        input_poll();
+    }
+
+    let mut core = CORE.lock().unwrap();
+    let core = match *core {
+        Some(ref mut core) => core,
+        None => return,
+    };
+
+    // A crashed core keeps showing its last frame
+    core.guard((), |core| {
+        let mut device = RetroDevice { input_state: callbacks.input_state };
+        core.vm.run_frame(&mut device);
+    });
+
+    if let Some(video_refresh) = callbacks.video_refresh {
+        let frame = core.vm.frame_buffer();
+        video_refresh(frame.as_ptr() as *const c_void, WIDTH, HEIGHT, WIDTH as usize * 4);
+    }
+    if let Some(audio_sample_batch) = callbacks.audio_sample_batch {
+        core.output_audio(audio_sample_batch);
+    }
+    core.vm.clear_audio_samples();
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    match *CORE.lock().unwrap() {
+        Some(ref core) => core.vm.save_state().len(),
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    let state = match *CORE.lock().unwrap() {
+        Some(ref core) => core.vm.save_state(),
+        None => return false,
+    };
+    if data.is_null() || size < state.len() {
+        return false;
+    }
+
+    ptr::copy_nonoverlapping(state.as_ptr(), data as *mut u8, state.len());
+    true
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    if data.is_null() {
+        return false;
+    }
+
+    match *CORE.lock().unwrap() {
+        Some(ref mut core) => {
+            // Loading a state gets a crashed core going again
+            let crashed = mem::replace(&mut core.crashed, false);
+            let data = slice::from_raw_parts(data as *const u8, size);
+            let loaded = core.guard(false, |core| {
+                match core.vm.load_state(data) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        log(RETRO_LOG_ERROR, &format!("Failed to load state: {}", e));
+                        false
+                    }
+                }
+            });
+            if !loaded {
+                core.crashed |= crashed;
+            }
+            loaded
+        }
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {
+    if let Some(ref mut core) = *CORE.lock().unwrap() {
+        *core.vm.cheats_mut() = Cheats::default();
+    }
+}
+
+// Frontends join several codes for one cheat with +
+#[no_mangle]
+pub unsafe extern "C" fn retro_cheat_set(_: c_uint, enabled: bool, code: *const c_char) {
+    if code.is_null() {
+        return;
+    }
+
+    if let Some(ref mut core) = *CORE.lock().unwrap() {
+        let code = CStr::from_ptr(code).to_string_lossy();
+        let cheats = core.vm.cheats_mut();
+        for part in code.split('+').map(str::trim).filter(|c| !c.is_empty()) {
+            match cheats.add(part, "") {
+                Ok(index) => {
+                    cheats.set_enabled(index, enabled);
+                }
+                Err(e) => log(RETRO_LOG_WARN, &e),
+            }
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const GameInfo) -> bool {
+    if game.is_null() {
+        return false;
+    }
+    let game = &*game;
+
+    let rom = if !game.data.is_null() {
+        slice::from_raw_parts(game.data as *const u8, game.size).to_vec()
+    } else if !game.path.is_null() {
+        let path = CStr::from_ptr(game.path).to_string_lossy().into_owned();
+        let mut rom = Vec::new();
+        if let Err(e) = File::open(&path).and_then(|mut f| f.read_to_end(&mut rom)) {
+            log(RETRO_LOG_ERROR, &format!("Failed to read {}: {}", path, e));
+            return false;
+        }
+        rom
+    } else {
+        return false;
+    };
+    if rom.len() < MIN_ROM_SIZE {
+        log(RETRO_LOG_ERROR, "ROM is too short to be a Game Boy cartridge");
+        return false;
+    }
+
+    let environment = match callbacks().environment {
+        Some(environment) => environment,
+        None => return false,
+    };
+
+    let mut format = RETRO_PIXEL_FORMAT_XRGB8888;
+    if !environment(RETRO_ENVIRONMENT_SET_PIXEL_FORMAT, &mut format as *mut c_uint as *mut c_void) {
+        log(RETRO_LOG_ERROR, "The frontend doesn't support XRGB8888 video");
+        return false;
+    }
+
+    let boot_rom = match find_boot_rom(environment) {
+        Some(boot_rom) => boot_rom,
+        None => {
+            log(RETRO_LOG_ERROR, "No boot ROM found in the system directory");
+            return false;
+        }
+    };
+
+    // Cartridge panics on headers it doesn't understand, which mustn't unwind
+    // into the frontend
+    let cartridge = match panic::catch_unwind(|| Cartridge::from_bytes(&rom)) {
+        Ok(cartridge) => cartridge,
+        Err(payload) => {
+            log(RETRO_LOG_ERROR, &format!("Unsupported cartridge: {}", panic_message(&*payload)));
+            return false;
+        }
+    };
+
+    let mut vm = VM::without_console(Interconnect::new(boot_rom, cartridge), Symbols::default());
+    vm.enable_audio(SAMPLE_RATE);
+    set_memory_maps(environment, &mut vm);
+
+    *CORE.lock().unwrap() = Some(Core {
+        power_on: vm.save_state(),
+        vm: vm,
+        crashed: false,
+    });
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(_: c_uint, _: *const GameInfo, _: usize) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    *CORE.lock().unwrap() = None;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    RETRO_REGION_NTSC
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    match *CORE.lock().unwrap() {
+        Some(ref mut core) => {
+            match memory(&mut core.vm, id) {
+                Some(mem) if !mem.is_empty() => mem.as_mut_ptr() as *mut c_void,
+                _ => ptr::null_mut(),
+            }
+        }
+        None => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    match *CORE.lock().unwrap() {
+        Some(ref mut core) => memory(&mut core.vm, id).map_or(0, |mem| mem.len()),
+        None => 0,
+    }
+}
+
+fn memory(vm: &mut VM, id: c_uint) -> Option<&mut [u8]> {
+    match id {
+        RETRO_MEMORY_SAVE_RAM => Some(vm.cart_ram_mut()),
+        RETRO_MEMORY_SYSTEM_RAM => Some(vm.work_ram_mut()),
+        _ => None,
+    }
+}
+
+// Tells the frontend where the RAM sits in the address space, for things like
+// achievements. Only the first bank of cartridge RAM is mapped.
+fn set_memory_maps(environment: EnvironmentFn, vm: &mut VM) {
+    let mut descriptors = Vec::new();
+
+    let work_ram = vm.work_ram_mut();
+    descriptors.push(MemoryDescriptor {
+        flags: RETRO_MEMDESC_SYSTEM_RAM,
+        ptr: work_ram.as_mut_ptr() as *mut c_void,
+        offset: 0,
+        start: WORK_RAM_START,
+        select: 0,
+        disconnect: 0,
+        len: work_ram.len(),
+        addrspace: ptr::null(),
+    });
+
+    let cart_ram = vm.cart_ram_mut();
+    if !cart_ram.is_empty() {
+        descriptors.push(MemoryDescriptor {
+            flags: RETRO_MEMDESC_SAVE_RAM,
+            ptr: cart_ram.as_mut_ptr() as *mut c_void,
+            offset: 0,
+            start: CART_RAM_START,
+            select: 0,
+            disconnect: 0,
+            len: cmp::min(cart_ram.len(), CART_RAM_WINDOW),
+            addrspace: ptr::null(),
+        });
+    }
+
+    let mut map = MemoryMap {
+        descriptors: descriptors.as_ptr(),
+        num_descriptors: descriptors.len() as c_uint,
+    };
+    environment(RETRO_ENVIRONMENT_SET_MEMORY_MAPS, &mut map as *mut MemoryMap as *mut c_void);
+}
+
+// Looks for a boot ROM in the frontend's system directory, trying the models
+// in the usual priority order
+unsafe fn find_boot_rom(environment: EnvironmentFn) -> Option<Bootrom> {
+    let mut dir: *const c_char = ptr::null();
+    if !environment(RETRO_ENVIRONMENT_GET_SYSTEM_DIRECTORY, &mut dir as *mut *const c_char as *mut c_void) ||
+       dir.is_null() {
+        return None;
+    }
+    let dir = CStr::from_ptr(dir).to_string_lossy().into_owned();
+
+    for model in &DEFAULT_MODEL_PRIORITY {
+        let path = Path::new(&dir).join(model.bootrom_name());
+        if let Ok(boot_rom) = Bootrom::load(&path) {
+            log(RETRO_LOG_INFO, &format!("Using {} boot ROM from {}", model, path.to_string_lossy()));
+            return Some(boot_rom);
+        }
+    }
+    None
+}
@@ -1,5 +1,8 @@
 use apu::unit::*;
 use apu::frame_sequencer::FrameSequencer;
+use ioregs::on_off;
+use std::io;
+use state::{SaveState, StateReader, StateWriter};
 
 pub struct Channel1 {
     pub sweep: Sweep,
@@ -69,6 +72,11 @@ impl Channel1 {
         if trigger {
             self.length.clocked = true;
             self.active = self.dac_enabled;
+            self.timer.reload(4);
+            self.volume.trigger();
+            if !self.sweep.trigger(self.timer.frequency()) {
+                self.active = false;
+            }
         }
     }
 
@@ -104,11 +112,40 @@ impl Channel1 {
                 self.active = false;
             }
         }
+        if frame_sequencer.sweep_step() && !self.sweep.step(&mut self.timer) {
+            self.active = false;
+        }
+        if frame_sequencer.envelope_step() {
+            self.volume.step();
+        }
+    }
+
+    // Square wave steps take 4 cycles per unit of the timer
+    pub fn run(&mut self, cycles: u32) {
+        for _ in 0..self.timer.step(cycles, 4) {
+            self.wave.step();
+        }
+    }
+
+    pub fn output(&self) -> i16 {
+        dac(self.active && self.dac_enabled, self.wave.output() * self.volume.current())
     }
 
     pub fn active(&self) -> bool {
         self.active
     }
+
+    pub fn print_state(&self) {
+        println!("Channel 1: {}, DAC {}, Length: {:02x} {}",
+                 on_off(self.active),
+                 on_off(self.dac_enabled),
+                 self.length.length,
+                 if self.length_enabled { "(counting)" } else { "(stopped)" });
+        println!("  {}", self.sweep);
+        println!("  {}", self.wave);
+        println!("  {}", self.volume);
+        println!("  {}", self.timer);
+    }
 }
 
 impl Default for Channel2 {
@@ -133,6 +170,8 @@ impl Channel2 {
         if trigger {
             self.length.clocked = true;
             self.active = self.dac_enabled;
+            self.timer.reload(4);
+            self.volume.trigger();
         }
     }
 
@@ -167,11 +206,35 @@ impl Channel2 {
                 self.active = false;
             }
         }
+        if frame_sequencer.envelope_step() {
+            self.volume.step();
+        }
+    }
+
+    pub fn run(&mut self, cycles: u32) {
+        for _ in 0..self.timer.step(cycles, 4) {
+            self.wave.step();
+        }
+    }
+
+    pub fn output(&self) -> i16 {
+        dac(self.active && self.dac_enabled, self.wave.output() * self.volume.current())
     }
 
     pub fn active(&self) -> bool {
         self.active
     }
+
+    pub fn print_state(&self) {
+        println!("Channel 2: {}, DAC {}, Length: {:02x} {}",
+                 on_off(self.active),
+                 on_off(self.dac_enabled),
+                 self.length.length,
+                 if self.length_enabled { "(counting)" } else { "(stopped)" });
+        println!("  {}", self.wave);
+        println!("  {}", self.volume);
+        println!("  {}", self.timer);
+    }
 }
 
 impl Default for Channel3 {
@@ -203,6 +266,8 @@ impl Channel3 {
         if trigger {
             self.length.clocked = true;
             self.active = self.dac_enabled;
+            self.timer.reload(2);
+            self.wave.trigger();
         }
     }
 
@@ -230,6 +295,17 @@ impl Channel3 {
         }
     }
 
+    // Wave samples take 2 cycles per unit of the timer
+    pub fn run(&mut self, cycles: u32) {
+        for _ in 0..self.timer.step(cycles, 2) {
+            self.wave.step();
+        }
+    }
+
+    pub fn output(&self) -> i16 {
+        dac(self.active && self.dac_enabled, self.volume.apply(self.wave.sample()))
+    }
+
     pub fn active(&self) -> bool {
         self.active
     }
@@ -237,6 +313,16 @@ impl Channel3 {
     pub fn dac_enabled(&self) -> bool {
         self.dac_enabled
     }
+
+    pub fn print_state(&self) {
+        println!("Channel 3: {}, DAC {}, Length: {:02x} {}",
+                 on_off(self.active),
+                 on_off(self.dac_enabled),
+                 self.length.length,
+                 if self.length_enabled { "(counting)" } else { "(stopped)" });
+        println!("  {}", self.volume);
+        println!("  {}", self.timer);
+    }
 }
 
 impl Default for Channel4 {
@@ -261,6 +347,8 @@ impl Channel4 {
         if trigger {
             self.length.clocked = true;
             self.active = self.dac_enabled;
+            self.lsfr.trigger();
+            self.volume.trigger();
         }
     }
 
@@ -295,9 +383,134 @@ impl Channel4 {
                 self.active = false;
             }
         }
+        if frame_sequencer.envelope_step() {
+            self.volume.step();
+        }
+    }
+
+    pub fn run(&mut self, cycles: u32) {
+        self.lsfr.step(cycles);
+    }
+
+    pub fn output(&self) -> i16 {
+        dac(self.active && self.dac_enabled, self.lsfr.output() * self.volume.current())
     }
 
     pub fn active(&self) -> bool {
         self.active
     }
+
+    pub fn print_state(&self) {
+        println!("Channel 4: {}, DAC {}, Length: {:02x} {}",
+                 on_off(self.active),
+                 on_off(self.dac_enabled),
+                 self.length.length,
+                 if self.length_enabled { "(counting)" } else { "(stopped)" });
+        println!("  {}", self.lsfr);
+        println!("  {}", self.volume);
+    }
+}
+
+// The DAC turns a channel's 0-15 level into a signed one centred on 0, and
+// is silent while it or the channel is off
+fn dac(on: bool, level: u8) -> i16 {
+    if on {
+        level as i16 * 2 - 15
+    } else {
+        0
+    }
+}
+
+impl SaveState for Channel1 {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.sweep.save_state(state);
+        self.wave.save_state(state);
+        self.length.save_state(state);
+        self.volume.save_state(state);
+        self.timer.save_state(state);
+        state.bool(self.active);
+        state.bool(self.dac_enabled);
+        state.bool(self.length_enabled);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.sweep.load_state(state)?;
+        self.wave.load_state(state)?;
+        self.length.load_state(state)?;
+        self.volume.load_state(state)?;
+        self.timer.load_state(state)?;
+        self.active = state.bool()?;
+        self.dac_enabled = state.bool()?;
+        self.length_enabled = state.bool()?;
+        Ok(())
+    }
+}
+
+impl SaveState for Channel2 {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.wave.save_state(state);
+        self.length.save_state(state);
+        self.volume.save_state(state);
+        self.timer.save_state(state);
+        state.bool(self.active);
+        state.bool(self.dac_enabled);
+        state.bool(self.length_enabled);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.wave.load_state(state)?;
+        self.length.load_state(state)?;
+        self.volume.load_state(state)?;
+        self.timer.load_state(state)?;
+        self.active = state.bool()?;
+        self.dac_enabled = state.bool()?;
+        self.length_enabled = state.bool()?;
+        Ok(())
+    }
+}
+
+impl SaveState for Channel3 {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.timer.save_state(state);
+        self.wave.save_state(state);
+        self.length.save_state(state);
+        self.volume.save_state(state);
+        state.bool(self.active);
+        state.bool(self.dac_enabled);
+        state.bool(self.length_enabled);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.timer.load_state(state)?;
+        self.wave.load_state(state)?;
+        self.length.load_state(state)?;
+        self.volume.load_state(state)?;
+        self.active = state.bool()?;
+        self.dac_enabled = state.bool()?;
+        self.length_enabled = state.bool()?;
+        Ok(())
+    }
+}
+
+impl SaveState for Channel4 {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.timer.save_state(state);
+        self.lsfr.save_state(state);
+        self.length.save_state(state);
+        self.volume.save_state(state);
+        state.bool(self.active);
+        state.bool(self.dac_enabled);
+        state.bool(self.length_enabled);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.timer.load_state(state)?;
+        self.lsfr.load_state(state)?;
+        self.length.load_state(state)?;
+        self.volume.load_state(state)?;
+        self.active = state.bool()?;
+        self.dac_enabled = state.bool()?;
+        self.length_enabled = state.bool()?;
+        Ok(())
+    }
 }
@@ -1,3 +1,6 @@
+use std::io;
+use state::{SaveState, StateReader, StateWriter};
+
 #[derive(Default)]
 pub struct FrameSequencer {
     cycles: u8,
@@ -11,4 +14,23 @@ impl FrameSequencer {
     pub fn length_step(&self) -> bool {
         self.cycles % 2 == 0
     }
+
+    pub fn sweep_step(&self) -> bool {
+        self.cycles == 2 || self.cycles == 6
+    }
+
+    pub fn envelope_step(&self) -> bool {
+        self.cycles == 7
+    }
+}
+
+impl SaveState for FrameSequencer {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.cycles);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.cycles = state.u8()?;
+        Ok(())
+    }
 }
@@ -1,5 +1,8 @@
 use super::interrupt::Irq;
 use super::device::Device;
+use ioregs::{on_off, print_field};
+use std::io;
+use state::{SaveState, StateReader, StateWriter};
 
 mod channel;
 mod unit;
@@ -7,6 +10,12 @@ mod frame_sequencer;
 
 const AUDIO_STEP_CYCLE_COUNT: u16 = 8 * 1024; // Divides the main 4MHz to get 512Hz
 
+// How much of the high-pass filter's charge is kept each cycle
+const HIGH_PASS_CHARGE: f32 = 0.999958;
+// Takes the loudest mix, 4 channels at level 15 at NR50 volume 7, to about
+// half of the sample range, leaving room for the filter's overshoot
+const SAMPLE_SCALE: f32 = 32.0;
+
 pub struct Apu {
     chan1: channel::Channel1,
     chan2: channel::Channel2,
@@ -19,6 +28,11 @@ pub struct Apu {
 
     cycles: u16,
     frame_sequencer: frame_sequencer::FrameSequencer,
+
+    // The filtered left and right levels, and the filter's state. Neither is
+    // in save states, the filter settles within a few milliseconds.
+    output: (i16, i16),
+    capacitor: (f32, f32),
 }
 
 impl Apu {
@@ -35,6 +49,9 @@ impl Apu {
 
             cycles: 0,
             frame_sequencer: frame_sequencer::FrameSequencer::default(),
+
+            output: (0, 0),
+            capacitor: (0.0, 0.0),
         }
     }
 
@@ -150,12 +167,67 @@ impl Apu {
     }
 
     pub fn step(&mut self, cycles: u16, device: &mut Device, _: &mut Irq) {
+        self.chan1.run(cycles as u32);
+        self.chan2.run(cycles as u32);
+        self.chan3.run(cycles as u32);
+        self.chan4.run(cycles as u32);
+
         self.cycles += cycles;
 
         while self.cycles > AUDIO_STEP_CYCLE_COUNT {
             self.inner_step(device);
             self.cycles -= AUDIO_STEP_CYCLE_COUNT;
         }
+
+        self.mix(cycles);
+    }
+
+    // Prints the registers as the CPU reads them, then the state of each
+    // channel, which includes the write-only parts of the registers
+    pub fn print_registers(&self) {
+        let rows: [&[(&str, u16)]; 4] = [&[("NR10", 0xff10), ("NR11", 0xff11), ("NR12", 0xff12), ("NR13", 0xff13), ("NR14", 0xff14)],
+                                         &[("NR21", 0xff16), ("NR22", 0xff17), ("NR23", 0xff18), ("NR24", 0xff19)],
+                                         &[("NR30", 0xff1a), ("NR31", 0xff1b), ("NR32", 0xff1c), ("NR33", 0xff1d), ("NR34", 0xff1e)],
+                                         &[("NR41", 0xff20), ("NR42", 0xff21), ("NR43", 0xff22), ("NR44", 0xff23)]];
+        for row in &rows {
+            let regs: Vec<String> = row.iter()
+                .map(|&(name, addr)| format!("{}: {:02x}", name, self.read_reg(addr)))
+                .collect();
+            println!("{}", regs.join(", "));
+        }
+
+        println!("NR50: {:02x}", self.out_chan_control);
+        print_field("7", "VIN left", on_off(self.out_chan_control & 0x80 != 0));
+        print_field("6-4", "Left volume", &((self.out_chan_control >> 4) & 0x07).to_string());
+        print_field("3", "VIN right", on_off(self.out_chan_control & 0x08 != 0));
+        print_field("2-0", "Right volume", &(self.out_chan_control & 0x07).to_string());
+
+        // Which channels each side plays, given as the channel numbers
+        let channels = |bits: u8| {
+            let on: Vec<String> = (0..4).filter(|i| bits & (1 << i) != 0).map(|i| (i + 1).to_string()).collect();
+            if on.is_empty() { "none".to_owned() } else { on.join(" ") }
+        };
+        println!("NR51: {:02x}", self.output_terminal);
+        print_field("7-4", "Left channels", &channels(self.output_terminal >> 4));
+        print_field("3-0", "Right channels", &channels(self.output_terminal & 0x0f));
+
+        let nr52 = self.read_reg(0xff26);
+        println!("NR52: {:02x}", nr52);
+        print_field("7", "Sound", on_off(self.sound_active));
+        print_field("3-0", "Channels active", &channels(nr52 & 0x0f));
+
+        self.chan1.print_state();
+        self.chan2.print_state();
+        self.chan3.print_state();
+        self.chan4.print_state();
+
+        let wave: Vec<String> = self.chan3.wave.data.iter().map(|b| format!("{:02x}", b)).collect();
+        println!("Wave RAM: {}", wave.join(" "));
+    }
+
+    // The left and right output levels as of the last step
+    pub fn output(&self) -> (i16, i16) {
+        self.output
     }
 
     fn inner_step(&mut self, _: &mut Device) {
@@ -166,4 +238,60 @@ impl Apu {
         self.chan3.step(&self.frame_sequencer);
         self.chan4.step(&self.frame_sequencer);
     }
+
+    // Pans the channels with NR51 and scales each side by its NR50 volume,
+    // then takes out the DC offset as the high-pass filter on the real
+    // hardware's output does
+    fn mix(&mut self, cycles: u16) {
+        let outputs = [self.chan1.output(), self.chan2.output(), self.chan3.output(), self.chan4.output()];
+        let (mut left, mut right) = (0, 0);
+        for (i, output) in outputs.iter().enumerate() {
+            if self.output_terminal & (0x10 << i) != 0 {
+                left += *output as i32;
+            }
+            if self.output_terminal & (0x01 << i) != 0 {
+                right += *output as i32;
+            }
+        }
+        left *= ((self.out_chan_control >> 4) & 0x07) as i32 + 1;
+        right *= (self.out_chan_control & 0x07) as i32 + 1;
+
+        let charge = HIGH_PASS_CHARGE.powi(cycles as i32);
+        self.output = (high_pass(&mut self.capacitor.0, left, charge),
+                       high_pass(&mut self.capacitor.1, right, charge));
+    }
+}
+
+fn high_pass(capacitor: &mut f32, input: i32, charge: f32) -> i16 {
+    let input = input as f32;
+    let output = input - *capacitor;
+    *capacitor = input - output * charge;
+
+    (output * SAMPLE_SCALE).max(i16::MIN as f32).min(i16::MAX as f32) as i16
+}
+
+impl SaveState for Apu {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.chan1.save_state(state);
+        self.chan2.save_state(state);
+        self.chan3.save_state(state);
+        self.chan4.save_state(state);
+        state.u8(self.out_chan_control);
+        state.u8(self.output_terminal);
+        state.bool(self.sound_active);
+        state.u16(self.cycles);
+        self.frame_sequencer.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.chan1.load_state(state)?;
+        self.chan2.load_state(state)?;
+        self.chan3.load_state(state)?;
+        self.chan4.load_state(state)?;
+        self.out_chan_control = state.u8()?;
+        self.output_terminal = state.u8()?;
+        self.sound_active = state.bool()?;
+        self.cycles = state.u16()?;
+        self.frame_sequencer.load_state(state)
+    }
 }
@@ -1,8 +1,22 @@
+use std::fmt;
+use std::io;
+use state::{SaveState, StateReader, StateWriter};
+
+// Which of the 8 steps of a square wave are high, for each duty cycle
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+
+// The noise channel's base periods in cycles, picked by NR43's divider
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
 #[derive(Default)]
 pub struct Sweep {
     sweep_time: u8,
     sweep_increase: bool,
     sweep_shift: u8,
+
+    enabled: bool,
+    timer: u8,
+    shadow_frequency: u16,
 }
 
 impl Sweep {
@@ -15,11 +29,65 @@ impl Sweep {
         self.sweep_increase = ((val >> 3) & 0x01) != 0;
         self.sweep_shift = val & 0x7;
     }
+
+    // Returns false if the first frequency calculation overflows, which stops
+    // the channel straight away
+    pub fn trigger(&mut self, frequency: u16) -> bool {
+        self.shadow_frequency = frequency;
+        self.reload();
+        self.enabled = self.sweep_time != 0 || self.sweep_shift != 0;
+
+        self.sweep_shift == 0 || self.next_frequency() <= 2047
+    }
+
+    // Clocked at 128Hz, moves the channel's frequency on. Returns false if it
+    // overflowed and the channel should stop.
+    pub fn step(&mut self, timer: &mut Timer) -> bool {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer != 0 {
+            return true;
+        }
+
+        self.reload();
+        if !self.enabled || self.sweep_time == 0 {
+            return true;
+        }
+
+        let frequency = self.next_frequency();
+        if frequency > 2047 {
+            return false;
+        }
+        if self.sweep_shift != 0 {
+            self.shadow_frequency = frequency;
+            timer.set_frequency(frequency);
+        }
+
+        // The new frequency is checked again, without being used
+        self.next_frequency() <= 2047
+    }
+
+    // A period of 0 counts as 8
+    fn reload(&mut self) {
+        self.timer = if self.sweep_time == 0 { 8 } else { self.sweep_time };
+    }
+
+    // Bit 3 of NR10 being set makes the sweep lower the frequency
+    fn next_frequency(&self) -> u16 {
+        let delta = self.shadow_frequency >> self.sweep_shift;
+        if self.sweep_increase {
+            self.shadow_frequency - delta
+        } else {
+            self.shadow_frequency + delta
+        }
+    }
 }
 
 #[derive(Default)]
 pub struct SquareWave {
     duty_pattern: u8,
+    position: u8,
 }
 
 impl SquareWave {
@@ -30,6 +98,15 @@ impl SquareWave {
     pub fn write(&mut self, val: u8) {
         self.duty_pattern = (val >> 6) & 0x03;
     }
+
+    pub fn step(&mut self) {
+        self.position = (self.position + 1) % 8;
+    }
+
+    // 1 while the wave is high, 0 while it's low
+    pub fn output(&self) -> u8 {
+        (DUTY_PATTERNS[self.duty_pattern as usize] >> (7 - self.position)) & 0x01
+    }
 }
 
 pub struct LengthCounter {
@@ -78,6 +155,11 @@ pub struct VolumeEnvelope {
     volume: u8,
     volume_increase: bool,
     volume_delta: u8,
+
+    // The volume the channel is playing at, which starts from volume when
+    // the channel is triggered
+    current: u8,
+    timer: u8,
 }
 
 impl VolumeEnvelope {
@@ -90,27 +172,109 @@ impl VolumeEnvelope {
         self.volume_increase = ((val >> 3) & 0x01) != 0;
         self.volume_delta = val & 0x07;
     }
+
+    pub fn trigger(&mut self) {
+        self.current = self.volume;
+        self.timer = self.volume_delta;
+    }
+
+    // Clocked at 64Hz, a period of 0 leaves the volume where it is
+    pub fn step(&mut self) {
+        if self.volume_delta == 0 {
+            return;
+        }
+
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.volume_delta;
+            if self.volume_increase && self.current < 15 {
+                self.current += 1;
+            } else if !self.volume_increase && self.current > 0 {
+                self.current -= 1;
+            }
+        }
+    }
+
+    pub fn current(&self) -> u8 {
+        self.current
+    }
 }
 
+// Counts down (2048 - frequency) units of a channel's cycles per step of its
+// waveform
 #[derive(Default)]
 pub struct Timer {
     frequency: u16,
+    countdown: u32,
 }
 
 impl Timer {
     pub fn write_lo(&mut self, val: u8) {
-        self.frequency = (self.frequency & 0xf0) | val as u16;
+        self.frequency = (self.frequency & 0x700) | val as u16;
     }
 
     pub fn write_hi(&mut self, val: u8) {
-        let val = (val & 0x05) as u16;
-        self.frequency = (self.frequency & 0x0f) | (val << 8)
+        let val = (val & 0x07) as u16;
+        self.frequency = (self.frequency & 0xff) | (val << 8)
+    }
+
+    pub fn frequency(&self) -> u16 {
+        self.frequency
+    }
+
+    pub fn set_frequency(&mut self, frequency: u16) {
+        self.frequency = frequency;
+    }
+
+    pub fn reload(&mut self, unit: u32) {
+        self.countdown = self.period(unit);
+    }
+
+    // Runs the timer for the given number of cycles, returning how many times
+    // it ran out
+    pub fn step(&mut self, cycles: u32, unit: u32) -> u32 {
+        let mut cycles = cycles;
+        let mut steps = 0;
+        while cycles >= self.countdown {
+            cycles -= self.countdown;
+            self.countdown = self.period(unit);
+            steps += 1;
+        }
+        self.countdown -= cycles;
+        steps
+    }
+
+    fn period(&self, unit: u32) -> u32 {
+        (2048 - self.frequency as u32) * unit
     }
 }
 
+// The 32 4-bit samples the wave channel plays, high nibble first
 #[derive(Default)]
 pub struct Wave {
     pub data: [u8; 16],
+    position: u8,
+}
+
+impl Wave {
+    pub fn trigger(&mut self) {
+        self.position = 0;
+    }
+
+    pub fn step(&mut self) {
+        self.position = (self.position + 1) % 32;
+    }
+
+    pub fn sample(&self) -> u8 {
+        let byte = self.data[self.position as usize / 2];
+        if self.position % 2 == 0 {
+            byte >> 4
+        } else {
+            byte & 0x0f
+        }
+    }
 }
 
 #[derive(Default)]
@@ -126,6 +290,15 @@ impl WaveVolume {
     pub fn read(&self) -> u8 {
         0x80 | (self.volume << 5) | 0x1f
     }
+
+    pub fn apply(&self, sample: u8) -> u8 {
+        match self.volume {
+            0 => 0,
+            1 => sample,
+            2 => sample >> 1,
+            _ => sample >> 2,
+        }
+    }
 }
 
 #[derive(Default)]
@@ -133,6 +306,9 @@ pub struct LSFR {
     shift_clock: u8,
     width: u8,
     divider: u8,
+
+    register: u16,
+    countdown: u32,
 }
 
 impl LSFR {
@@ -145,4 +321,212 @@ impl LSFR {
     pub fn read(&self) -> u8 {
         (self.shift_clock << 4) | (self.width << 3) | self.divider
     }
+
+    pub fn trigger(&mut self) {
+        self.register = 0x7fff;
+        self.countdown = self.period();
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles >= self.countdown {
+            cycles -= self.countdown;
+            self.countdown = self.period();
+            self.shift();
+        }
+        self.countdown -= cycles;
+    }
+
+    // 1 while the low bit of the register is clear
+    pub fn output(&self) -> u8 {
+        (!self.register & 0x01) as u8
+    }
+
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[self.divider as usize] << self.shift_clock
+    }
+
+    // The XOR of the low two bits goes in at the top, and also into bit 6 in
+    // 7-bit mode for a shorter, more tonal sequence
+    fn shift(&mut self) {
+        let bit = (self.register ^ (self.register >> 1)) & 0x01;
+        self.register = (self.register >> 1) | (bit << 14);
+        if self.width != 0 {
+            self.register = (self.register & !0x40) | (bit << 6);
+        }
+    }
+}
+
+impl fmt::Display for Sweep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "sweep period {}, {}, shift {}",
+               self.sweep_time,
+               if self.sweep_increase { "down" } else { "up" },
+               self.sweep_shift)
+    }
+}
+
+impl fmt::Display for SquareWave {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "duty {}",
+               match self.duty_pattern {
+                   0 => "12.5%",
+                   1 => "25%",
+                   2 => "50%",
+                   _ => "75%",
+               })
+    }
+}
+
+impl fmt::Display for VolumeEnvelope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "envelope volume {}, {}, period {}",
+               self.volume,
+               if self.volume_increase { "up" } else { "down" },
+               self.volume_delta)
+    }
+}
+
+impl fmt::Display for Timer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "frequency {:03x}", self.frequency)
+    }
+}
+
+impl fmt::Display for WaveVolume {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "volume {}",
+               match self.volume {
+                   0 => "mute",
+                   1 => "100%",
+                   2 => "50%",
+                   _ => "25%",
+               })
+    }
+}
+
+impl fmt::Display for LSFR {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "shift {}, {}, divider {}",
+               self.shift_clock,
+               if self.width != 0 { "7-bit" } else { "15-bit" },
+               self.divider)
+    }
+}
+
+impl SaveState for Sweep {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.read());
+        state.bool(self.enabled);
+        state.u8(self.timer);
+        state.u16(self.shadow_frequency);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.write(state.u8()?);
+        self.enabled = state.bool()?;
+        self.timer = state.u8()?;
+        self.shadow_frequency = state.u16()?;
+        Ok(())
+    }
+}
+
+impl SaveState for SquareWave {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.read());
+        state.u8(self.position);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.write(state.u8()?);
+        self.position = state.u8()? % 8;
+        Ok(())
+    }
+}
+
+impl SaveState for LengthCounter {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.clocked);
+        state.u8(self.length);
+        state.u8(self.last);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.clocked = state.bool()?;
+        self.length = state.u8()?;
+        self.last = state.u8()?;
+        Ok(())
+    }
+}
+
+impl SaveState for VolumeEnvelope {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.read());
+        state.u8(self.current);
+        state.u8(self.timer);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.write(state.u8()?);
+        self.current = state.u8()? & 0x0f;
+        self.timer = state.u8()?;
+        Ok(())
+    }
+}
+
+impl SaveState for Timer {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.frequency);
+        state.u32(self.countdown);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.frequency = state.u16()? & 0x7ff;
+        self.countdown = state.u32()?;
+        Ok(())
+    }
+}
+
+impl SaveState for Wave {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.data);
+        state.u8(self.position);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        state.bytes(&mut self.data)?;
+        self.position = state.u8()? % 32;
+        Ok(())
+    }
+}
+
+impl SaveState for WaveVolume {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.read());
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.write(state.u8()?);
+        Ok(())
+    }
+}
+
+impl SaveState for LSFR {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.read());
+        state.u16(self.register);
+        state.u32(self.countdown);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.write(state.u8()?);
+        self.register = state.u16()?;
+        self.countdown = state.u32()?;
+        Ok(())
+    }
 }
@@ -0,0 +1,80 @@
+extern crate flate2;
+extern crate zip;
+
+use std::fs::File;
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+use self::flate2::read::GzDecoder;
+use self::zip::ZipArchive;
+use self::zip::result::ZipError;
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+const ROM_EXTENSIONS: [&str; 2] = [".gb", ".gbc"];
+
+// Reads a ROM file, unpacking it first if it's a zip or gzip archive. Zip
+// archives use the named entry, or the first Game Boy ROM in them.
+pub fn read_rom(file_name: &Path, entry: Option<&str>) -> io::Result<Vec<u8>> {
+    let mut file = File::open(file_name)?;
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer)?;
+
+    if buffer.starts_with(ZIP_MAGIC) {
+        read_zip_entry(buffer, entry).map_err(|e| {
+            io::Error::new(e.kind(), format!("{}: {}", file_name.to_string_lossy(), e))
+        })
+    } else if buffer.starts_with(GZIP_MAGIC) {
+        let mut rom = Vec::new();
+        GzDecoder::new(&buffer[..]).read_to_end(&mut rom)?;
+        Ok(rom)
+    } else {
+        Ok(buffer)
+    }
+}
+
+fn read_zip_entry(buffer: Vec<u8>, entry: Option<&str>) -> io::Result<Vec<u8>> {
+    let mut archive = ZipArchive::new(Cursor::new(buffer)).map_err(zip_error)?;
+
+    let name = match entry {
+        Some(name) => name.to_owned(),
+        None => {
+            let mut names: Vec<_> = (0..archive.len())
+                .filter_map(|i| archive.by_index(i).ok().map(|f| f.name().to_owned()))
+                .collect();
+            names.retain(|n| is_rom_name(n));
+            match names.into_iter().next() {
+                Some(name) => name,
+                None => {
+                    return Err(io::Error::new(io::ErrorKind::NotFound,
+                                              "archive has no .gb or .gbc file in it"))
+                }
+            }
+        }
+    };
+
+    let mut file = match archive.by_name(&name) {
+        Ok(f) => f,
+        Err(ZipError::FileNotFound) => {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                                      format!("archive has no file named {}", name)))
+        }
+        Err(e) => return Err(zip_error(e)),
+    };
+
+    println!("Loading {} from archive", name);
+    let mut rom = Vec::new();
+    file.read_to_end(&mut rom)?;
+    Ok(rom)
+}
+
+fn is_rom_name(name: &str) -> bool {
+    let name = name.to_lowercase();
+    ROM_EXTENSIONS.iter().any(|ext| name.ends_with(ext))
+}
+
+fn zip_error(e: ZipError) -> io::Error {
+    match e {
+        ZipError::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+    }
+}
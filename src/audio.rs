@@ -0,0 +1,57 @@
+const CLOCK_RATE: u64 = 4194304;
+
+// Turns the APU's output, which can change every cycle, into stereo samples
+// at a fixed rate. Each sample is the average level over the cycles it covers.
+pub struct Resampler {
+    sample_rate: u64,
+    // Cycles not yet covered by samples, in units of 1 / sample_rate cycles
+    remainder: u64,
+    // The level summed over each cycle since the last sample
+    sum: (i64, i64),
+    cycles: u64,
+    // Left and right interleaved
+    samples: Vec<i16>,
+}
+
+impl Resampler {
+    pub fn new(sample_rate: u32) -> Resampler {
+        Resampler {
+            sample_rate: sample_rate as u64,
+            remainder: 0,
+            sum: (0, 0),
+            cycles: 0,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn step(&mut self, cycles: u16, level: (i16, i16)) {
+        self.sum.0 += level.0 as i64 * cycles as i64;
+        self.sum.1 += level.1 as i64 * cycles as i64;
+        self.cycles += cycles as u64;
+
+        self.remainder += cycles as u64 * self.sample_rate;
+        while self.remainder >= CLOCK_RATE {
+            self.remainder -= CLOCK_RATE;
+            // Steps are far shorter than a sample, so any sample after the
+            // first in one step just repeats the level
+            if self.cycles == 0 {
+                self.samples.push(level.0);
+                self.samples.push(level.1);
+            } else {
+                let cycles = self.cycles as i64;
+                self.samples.push((self.sum.0 / cycles) as i16);
+                self.samples.push((self.sum.1 / cycles) as i16);
+            }
+            self.sum = (0, 0);
+            self.cycles = 0;
+        }
+    }
+
+    pub fn samples(&self) -> &[i16] {
+        &self.samples
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+}
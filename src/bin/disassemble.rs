@@ -0,0 +1,52 @@
+#[macro_use]
+extern crate clap;
+extern crate gameboy;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use clap::{Arg, App};
+use gameboy::cartridge::Cartridge;
+use gameboy::disassembler::Disassembly;
+use gameboy::symbols::Symbols;
+
+fn main() {
+    let matches = App::new("Game Boy Disassembler")
+        .version(crate_version!())
+        .author("tompko  <tompko@gmail.com>")
+        .about("Disassembles a Game Boy ROM into RGBDS assembly")
+        .arg(Arg::with_name("INPUT")
+                 .help("Sets the cartridge file to disassemble")
+                 .required(true)
+                 .index(1))
+        .arg(Arg::with_name("sym-file")
+                 .help("Sets the symbol file to use, defaults to one alongside the cartridge")
+                 .short("s")
+                 .long("sym")
+                 .takes_value(true))
+        .arg(Arg::with_name("output")
+                 .help("Sets the file to write the assembly to, defaults to stdout")
+                 .short("o")
+                 .long("output")
+                 .takes_value(true))
+        .get_matches();
+
+    let input_file = matches.value_of("INPUT").unwrap();
+
+    let mut rom = Vec::new();
+    File::open(input_file).and_then(|mut f| f.read_to_end(&mut rom)).unwrap();
+    let cartridge = Cartridge::from_bytes(&rom);
+
+    let symbols = match matches.value_of("sym-file") {
+        Some(sym_file) => Symbols::load(sym_file).unwrap(),
+        None => Symbols::discover(input_file).unwrap(),
+    };
+
+    let disassembly = Disassembly::new(&cartridge, &symbols);
+
+    let mut out: Box<dyn Write> = match matches.value_of("output") {
+        Some(path) => Box::new(BufWriter::new(File::create(Path::new(path)).unwrap())),
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+    disassembly.write_rgbds(&mut out).unwrap();
+}
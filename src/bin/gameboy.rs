@@ -3,62 +3,196 @@ extern crate clap;
 extern crate gameboy;
 extern crate minifb;
 
+use std::path::Path;
+use std::time::{Duration, Instant};
 use clap::{Arg, App};
-use minifb::{Key, Scale, WindowOptions, Window};
+use minifb::{Key, Scale, ScaleMode, WindowOptions, Window};
 use gameboy::Gameboy;
 use gameboy::device::{self, Device};
+use gameboy::filter::{Filter, Ghosting};
+use gameboy::input::Buttons;
+use gameboy::viewer::{VideoState, View};
+use gameboy::vm::Speed;
+
+// The window is drawn at this many times the screen size by default
+const DEFAULT_SCALE: usize = 2;
 
 struct ConsoleDevice {
-    buffer: Box<[u32]>,
+    // The last frame, after ghosting, at the Game Boy's resolution
+    frame: Box<[u32]>,
+    // The frame scaled up to fit the window
+    buffer: Vec<u32>,
     window: Window,
 
     width: usize,
     height: usize,
+    scale: usize,
+    filter: Filter,
+    ghosting: Option<Ghosting>,
+    // Debug viewers, each in its own window
+    viewers: Vec<(View, Window)>,
 
     buffer_set: bool,
+    last_update: Instant,
 }
 
 impl ConsoleDevice {
-    fn new(window: Window, width: usize, height: usize) -> Self {
+    fn new(window: Window, width: usize, height: usize, scale: usize, filter: Filter) -> Self {
         ConsoleDevice {
-            buffer: vec![0; width * height].into_boxed_slice(),
+            frame: vec![0; width * height].into_boxed_slice(),
+            buffer: vec![0; width * height * scale * scale],
             window: window,
             width: width,
             height: height,
+            scale: scale,
+            filter: filter,
+            ghosting: None,
+            viewers: Vec::new(),
             buffer_set: false,
+            last_update: Instant::now(),
         }
+    }
 
+    fn enable_ghosting(&mut self) {
+        self.ghosting = Some(Ghosting::new(self.width * self.height));
+    }
+
+    fn open_viewer(&mut self, view: View) {
+        let (width, height) = view.dimensions();
+        let window_options = WindowOptions {
+            resize: true,
+            scale: Scale::X2,
+            scale_mode: ScaleMode::AspectRatioStretch,
+            ..WindowOptions::default()
+        };
+        let title = format!("Scimitar - {}", view.name());
+        let window = Window::new(&title, width, height, window_options).unwrap();
+        self.viewers.push((view, window));
     }
 }
 
 impl Device for ConsoleDevice {
     fn update(&mut self) {
         if self.buffer_set {
-            self.window.update_with_buffer(&*self.buffer);
+            let (width, height) = (self.width * self.scale, self.height * self.scale);
+            self.window.update_with_buffer(&self.buffer, width, height).unwrap();
             self.buffer_set = false;
+            self.last_update = Instant::now();
+        } else if self.last_update.elapsed() > Duration::from_millis(16) {
+            // No frames come while paused or debugging, keep the window
+            // responding to keys anyway
+            self.window.update();
+            self.last_update = Instant::now();
         }
     }
 
     fn set_frame_buffer(&mut self, buffer: &[u32]) {
-        for y in 0..self.height {
-            for x in 0..self.width {
-                let index = (y * self.width) + x;
-                self.buffer[index as usize] = buffer[index as usize];
-            }
+        match self.ghosting {
+            Some(ref mut ghosting) => ghosting.blend(buffer, &mut self.frame),
+            None => self.frame.copy_from_slice(&buffer[..self.width * self.height]),
+        }
+
+        // Filter at the largest whole scale that fits the window, leaving
+        // minifb to stretch the rest of the way
+        let (window_width, window_height) = self.window.get_size();
+        let scale = (window_width / self.width).min(window_height / self.height).max(self.filter.min_scale());
+        if scale != self.scale {
+            self.scale = scale;
+            self.buffer = vec![0; self.width * self.height * scale * scale];
         }
+        self.filter.apply(&self.frame, self.width, self.height, self.scale, &mut self.buffer);
         self.buffer_set = true;
     }
 
+    fn set_video_state(&mut self, state: &VideoState) {
+        // Closing a viewer doesn't stop the emulator, it just goes away
+        self.viewers.retain(|viewer| viewer.1.is_open());
+        for viewer in &mut self.viewers {
+            let image = viewer.0.render(state);
+            viewer.1.update_with_buffer(&image.pixels, image.width, image.height).unwrap();
+        }
+    }
+
     fn key_down(&self, key: device::Key) -> bool {
         let key = match key {
+            device::Key::Key0 => Key::Key0,
+            device::Key::Key1 => Key::Key1,
+            device::Key::Key2 => Key::Key2,
+            device::Key::Key3 => Key::Key3,
+            device::Key::Key4 => Key::Key4,
+            device::Key::Key5 => Key::Key5,
+            device::Key::Key6 => Key::Key6,
+            device::Key::Key7 => Key::Key7,
+            device::Key::Key8 => Key::Key8,
+            device::Key::Key9 => Key::Key9,
+            device::Key::A => Key::A,
+            device::Key::B => Key::B,
+            device::Key::C => Key::C,
+            device::Key::D => Key::D,
+            device::Key::E => Key::E,
+            device::Key::F => Key::F,
+            device::Key::G => Key::G,
+            device::Key::H => Key::H,
+            device::Key::I => Key::I,
+            device::Key::J => Key::J,
+            device::Key::K => Key::K,
+            device::Key::L => Key::L,
+            device::Key::M => Key::M,
+            device::Key::N => Key::N,
+            device::Key::O => Key::O,
+            device::Key::P => Key::P,
+            device::Key::Q => Key::Q,
+            device::Key::R => Key::R,
+            device::Key::S => Key::S,
+            device::Key::T => Key::T,
+            device::Key::U => Key::U,
+            device::Key::V => Key::V,
+            device::Key::W => Key::W,
+            device::Key::X => Key::X,
+            device::Key::Y => Key::Y,
+            device::Key::Z => Key::Z,
+            device::Key::F1 => Key::F1,
+            device::Key::F2 => Key::F2,
+            device::Key::F3 => Key::F3,
+            device::Key::F4 => Key::F4,
+            device::Key::F5 => Key::F5,
+            device::Key::F6 => Key::F6,
+            device::Key::F7 => Key::F7,
+            device::Key::F8 => Key::F8,
+            device::Key::F9 => Key::F9,
+            device::Key::F10 => Key::F10,
+            device::Key::F11 => Key::F11,
+            device::Key::F12 => Key::F12,
             device::Key::Up => Key::Up,
             device::Key::Down => Key::Down,
             device::Key::Left => Key::Left,
             device::Key::Right => Key::Right,
             device::Key::Backspace => Key::Backspace,
             device::Key::Enter => Key::Enter,
-            device::Key::Z => Key::Z,
-            device::Key::X => Key::X,
+            device::Key::Space => Key::Space,
+            device::Key::Tab => Key::Tab,
+            device::Key::Escape => Key::Escape,
+            device::Key::Insert => Key::Insert,
+            device::Key::Delete => Key::Delete,
+            device::Key::Home => Key::Home,
+            device::Key::End => Key::End,
+            device::Key::PageUp => Key::PageUp,
+            device::Key::PageDown => Key::PageDown,
+            device::Key::LeftShift => Key::LeftShift,
+            device::Key::RightShift => Key::RightShift,
+            device::Key::LeftCtrl => Key::LeftCtrl,
+            device::Key::RightCtrl => Key::RightCtrl,
+            device::Key::LeftAlt => Key::LeftAlt,
+            device::Key::RightAlt => Key::RightAlt,
+            device::Key::Minus => Key::Minus,
+            device::Key::Equal => Key::Equal,
+            device::Key::Comma => Key::Comma,
+            device::Key::Period => Key::Period,
+            device::Key::Slash => Key::Slash,
+            device::Key::Semicolon => Key::Semicolon,
+            device::Key::Apostrophe => Key::Apostrophe,
+            // minifb has no game controller support
+            device::Key::Pad(_) => return false,
         };
 
         self.window.is_key_down(key)
@@ -69,15 +203,54 @@ impl Device for ConsoleDevice {
     }
 }
 
+// Runs with no window or keyboard, for scripted runs with --headless. The
+// buttons come from a movie if one is playing.
+struct HeadlessDevice;
+
+impl Device for HeadlessDevice {
+    fn update(&mut self) {}
+
+    fn set_frame_buffer(&mut self, _: &[u32]) {}
+
+    fn key_down(&self, _: device::Key) -> bool {
+        false
+    }
+
+    fn buttons(&mut self) -> Option<Buttons> {
+        Some(Buttons::default())
+    }
+
+    fn running(&self) -> bool {
+        true
+    }
+}
+
+fn validate_scale(scale: String) -> Result<(), String> {
+    match scale.parse::<usize>() {
+        Ok(scale) if scale >= 1 => Ok(()),
+        _ if scale == "fit" => Ok(()),
+        _ => Err(format!("Invalid scale {}, expected a whole number of at least 1 or fit", scale)),
+    }
+}
+
 fn main() {
     let matches = App::new("Gameboy Emulator")
         .version(crate_version!())
         .author("tompko  <tompko@gmail.com>")
         .about("Emulates the Game Boy language")
         .arg(Arg::with_name("INPUT")
-                 .help("Sets the cartridge file to use")
+                 .help("Sets the cartridge file to use, which may be in a zip or gzip archive")
                  .required(true)
                  .index(1))
+        .arg(Arg::with_name("rom-name")
+                 .help("Sets the file to load when the cartridge is a zip archive of several ROMs")
+                 .long("rom-name")
+                 .takes_value(true))
+        .arg(Arg::with_name("patch")
+                 .help("Applies an IPS, BPS or UPS patch to the cartridge, defaults to one alongside it")
+                 .short("p")
+                 .long("patch")
+                 .takes_value(true))
         .arg(Arg::with_name("boot-rom")
                  .help("Sets the boot rom to use")
                  .short("b")
@@ -89,6 +262,96 @@ fn main() {
                  .short("s")
                  .long("sym")
                  .takes_value(true))
+        .arg(Arg::with_name("profile")
+                 .help("Profiles the run, writing a report of the hottest code to the given file")
+                 .long("profile")
+                 .takes_value(true))
+        .arg(Arg::with_name("profile-stacks")
+                 .help("Profiles the run, writing collapsed call stacks for flamegraphs to the given file")
+                 .long("profile-stacks")
+                 .takes_value(true))
+        .arg(Arg::with_name("cheat")
+                 .help("Enables a Game Genie or GameShark code, saved to the ROM's cheat file")
+                 .short("c")
+                 .long("cheat")
+                 .takes_value(true)
+                 .multiple(true)
+                 .number_of_values(1))
+        .arg(Arg::with_name("input-config")
+                 .help("Sets the key bindings file to use, defaults to input.ini in the config directory")
+                 .long("input-config")
+                 .takes_value(true))
+        .arg(Arg::with_name("speed")
+                 .help("Sets the emulation speed as a multiplier, e.g. 2 or 0.5, or max to run unthrottled")
+                 .long("speed")
+                 .takes_value(true)
+                 .validator(|s| s.parse::<Speed>().map(|_| ()).map_err(|e| e.into_owned())))
+        .arg(Arg::with_name("record")
+                 .help("Records the joypad input of the run to the given movie file")
+                 .long("record")
+                 .takes_value(true)
+                 .conflicts_with("play"))
+        .arg(Arg::with_name("play")
+                 .help("Plays back the joypad input from the given movie file")
+                 .long("play")
+                 .takes_value(true))
+        .arg(Arg::with_name("screenshot-scale")
+                 .help("Sets how many times larger than the screen screenshots are saved")
+                 .long("screenshot-scale")
+                 .takes_value(true))
+        .arg(Arg::with_name("scale")
+                 .help("Sets how many times larger than the screen the window is, or fit to fill the display")
+                 .long("scale")
+                 .takes_value(true)
+                 .validator(validate_scale))
+        .arg(Arg::with_name("filter")
+                 .help("Sets how the screen is scaled up: nearest, scale2x, smooth2x, lcd or scanlines")
+                 .long("filter")
+                 .takes_value(true)
+                 .possible_values(&["nearest", "scale2x", "smooth2x", "lcd", "scanlines"])
+                 .case_insensitive(true))
+        .arg(Arg::with_name("ghosting")
+                 .help("Blends each frame with the last, like the DMG's slow LCD")
+                 .long("ghosting")
+                 .takes_value(false))
+        .arg(Arg::with_name("viewer")
+                 .help("Opens debug viewers for VRAM: tiles, maps or oam")
+                 .long("viewer")
+                 .takes_value(true)
+                 .multiple(true)
+                 .use_delimiter(true)
+                 .possible_values(&["tiles", "maps", "oam"])
+                 .case_insensitive(true)
+                 .conflicts_with("headless"))
+        .arg(Arg::with_name("dump-viewers")
+                 .help("Saves the debug viewers as tiles.png, maps.png and oam.png in the given directory after a headless run")
+                 .long("dump-viewers")
+                 .takes_value(true)
+                 .requires("headless"))
+        .arg(Arg::with_name("palette")
+                 .help("Sets the colours to green, grayscale, pocket, auto, a palette.ini name or RRGGBB colours")
+                 .long("palette")
+                 .takes_value(true))
+        .arg(Arg::with_name("record-video")
+                 .help("Records the screen to the given .y4m file and the sound to a .wav file alongside it")
+                 .long("record-video")
+                 .takes_value(true))
+        .arg(Arg::with_name("headless")
+                 .help("Runs without a window for the number of frames given by --frames")
+                 .long("headless")
+                 .takes_value(false)
+                 .requires("frames")
+                 .conflicts_with("debug"))
+        .arg(Arg::with_name("frames")
+                 .help("Sets how many frames a headless run lasts")
+                 .long("frames")
+                 .takes_value(true)
+                 .requires("headless"))
+        .arg(Arg::with_name("screenshot")
+                 .help("Saves the last frame of a headless run to the given PNG file")
+                 .long("screenshot")
+                 .takes_value(true)
+                 .requires("headless"))
         .arg(Arg::with_name("debug")
                  .help("If present, starts in debugging mode")
                  .short("d")
@@ -98,23 +361,81 @@ fn main() {
 
     let mut gameboy = Gameboy::default()
         .with_cartridge(matches.value_of("INPUT"))
+        .with_archive_entry(matches.value_of("rom-name"))
+        .with_patch(matches.value_of("patch"))
         .with_boot_rom(matches.value_of("boot-rom"))
         .with_symbols(matches.value_of("sym-file"))
+        .with_profile_report(matches.value_of("profile"))
+        .with_profile_stacks(matches.value_of("profile-stacks"))
+        .with_cheats(matches.values_of("cheat").map(|v| v.collect()))
+        .with_input_config(matches.value_of("input-config"))
+        .with_speed(matches.value_of("speed"))
+        .with_record_movie(matches.value_of("record"))
+        .with_play_movie(matches.value_of("play"))
+        .with_screenshot_scale(matches.value_of("screenshot-scale"))
+        .with_palette(matches.value_of("palette"))
+        .with_record_video(matches.value_of("record-video"))
         .start_in_debug(matches.is_present("debug"))
+        .without_console(matches.is_present("headless"))
         .build();
 
+    if matches.is_present("headless") {
+        let frames = value_t_or_exit!(matches, "frames", u32);
+        let mut device = HeadlessDevice;
+        for _ in 0..frames {
+            gameboy.run_frame(&mut device);
+        }
+
+        gameboy.save_profile().unwrap();
+        gameboy.save_movie().unwrap();
+        gameboy.stop_recording().unwrap();
+        if let Some(file_name) = matches.value_of("screenshot") {
+            gameboy.save_screenshot(file_name).unwrap();
+        }
+        if let Some(dir) = matches.value_of("dump-viewers") {
+            let state = gameboy.video_state();
+            for view in &[View::Tiles, View::TileMaps, View::Oam] {
+                let path = Path::new(dir).join(format!("{}.png", view.name()));
+                view.render(&state).save_png(&path).unwrap();
+            }
+        }
+        return;
+    }
+
     let (width, height) = gameboy.get_dimensions();
 
+    let filter = match matches.value_of("filter") {
+        Some(filter) => filter.parse().unwrap(),
+        None => Filter::Nearest,
+    };
+
+    // The filters scale the frame up themselves. Fitting the display leaves
+    // minifb to scale the filter's output up by as much as fits, after which
+    // the frames are filtered at the window's size.
+    let (scale, window_scale) = match matches.value_of("scale") {
+        Some("fit") => (filter.min_scale(), Scale::FitScreen),
+        Some(scale) => (scale.parse().unwrap(), Scale::X1),
+        None => (DEFAULT_SCALE, Scale::X1),
+    };
+
     let window_options = WindowOptions {
-        borderless: false,
-        title: true,
-        resize: false,
-        scale: Scale::X2,
+        resize: true,
+        scale: window_scale,
+        scale_mode: ScaleMode::AspectRatioStretch,
+        ..WindowOptions::default()
     };
 
-    let window = Window::new("Scimitar", width, height, window_options).unwrap();
+    let window = Window::new("Scimitar", width * scale, height * scale, window_options).unwrap();
 
-    let mut device = ConsoleDevice::new(window, width, height);
+    let mut device = ConsoleDevice::new(window, width, height, scale, filter);
+    if matches.is_present("ghosting") {
+        device.enable_ghosting();
+    }
+    if let Some(views) = matches.values_of("viewer") {
+        for view in views {
+            device.open_viewer(view.parse().unwrap());
+        }
+    }
 
     gameboy.run(&mut device);
 }
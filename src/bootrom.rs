@@ -58,6 +58,10 @@ impl Bootrom {
         panic!("No valid bootroms found");
     }
 
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
     pub fn read_byte(&self, addr: u16) -> u8 {
         self.data[addr as usize]
     }
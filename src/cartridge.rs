@@ -1,7 +1,16 @@
+extern crate crc;
+
+use std::cmp;
 use std::io::{self, Read};
 use std::fmt;
 use std::fs::File;
 use std::path::Path;
+use archive;
+use patch;
+use state::{SaveState, StateReader, StateWriter, invalid_state};
+use self::crc::crc32;
+
+const PATCH_EXTENSIONS: [&str; 3] = ["ips", "bps", "ups"];
 
 const ROM_TYPE_OFFSET: usize = 0x0147;
 const RAM_SIZE_OFFSET: usize = 0x0149;
@@ -34,6 +43,7 @@ impl fmt::Display for Mbc {
 
 pub struct Cartridge {
     rom: Box<[u8]>,
+    rom_crc: u32,
     ram: Box<[u8]>,
 
     mbc: Mbc,
@@ -48,9 +58,28 @@ pub struct Cartridge {
 
 impl Cartridge {
     pub fn load(file_name: &Path) -> io::Result<Cartridge> {
-        let mut file = File::open(file_name)?;
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
+        Cartridge::load_with(file_name, None, None)
+    }
+
+    // Loads a ROM, unpacking it from a zip or gzip archive if needed, and
+    // applies an IPS, BPS or UPS patch to it. archive_entry picks the ROM out
+    // of a zip holding several. Without an explicit patch file a game.ips,
+    // game.bps or game.ups alongside the ROM is used if there is one.
+    pub fn load_with(file_name: &Path, archive_entry: Option<&str>, patch_file: Option<&Path>) -> io::Result<Cartridge> {
+        let mut buffer = archive::read_rom(file_name, archive_entry)?;
+
+        let patch_file = match patch_file {
+            Some(p) => Some(p.to_path_buf()),
+            None => {
+                PATCH_EXTENSIONS.iter()
+                    .map(|ext| file_name.with_extension(ext))
+                    .find(|p| p.is_file())
+            }
+        };
+        if let Some(patch_file) = patch_file {
+            println!("Applying patch {}", patch_file.to_string_lossy());
+            buffer = patch::apply(&buffer, &read_file(&patch_file)?)?;
+        }
 
         let cart = Cartridge::from_bytes(&buffer);
 
@@ -80,6 +109,7 @@ impl Cartridge {
         };
 
         Cartridge {
+            rom_crc: crc32::checksum_ieee(&bytes_copy),
             rom: bytes_copy.into_boxed_slice(),
             ram: vec![0; ram_size].into_boxed_slice(),
 
@@ -145,6 +175,60 @@ impl Cartridge {
         }
     }
 
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    // All of the cartridge's RAM banks, which battery backed games save to
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    pub fn rom_bank_count(&self) -> usize {
+        (self.rom.len() + 0x3fff) / 0x4000
+    }
+
+    // Returns how much of the cartridge RAM area is readable, carts with less
+    // than a full 8KB bank only answer at the start of it
+    pub fn mapped_ram_size(&self) -> u16 {
+        if self.ram_enabled {
+            cmp::min(self.ram.len(), 0x2000) as u16
+        } else {
+            0
+        }
+    }
+
+    // Returns the ROM or RAM bank currently mapped at the given address
+    pub fn bank_at(&self, addr: u16) -> u16 {
+        let (lower, upper) = self.rom_offsets;
+
+        let bank = match addr {
+            0...0x3fff => lower / 0x4000,
+            0x4000...0x7fff => upper / 0x4000,
+            0xa000...0xbfff => self.ram_bank_offset / 0x2000,
+            _ => 0,
+        };
+
+        bank as u16
+    }
+
+    pub fn print_registers(&self) {
+        let (lower, upper) = self.rom_offsets;
+        println!("MBC: {}", self.mbc);
+        println!("ROM banks: {:02x} at 0000-3fff, {:02x} at 4000-7fff, of {}",
+                 lower / 0x4000, upper / 0x4000, self.rom_bank_count());
+        println!("RAM: {}, bank {:02x} at a000-bfff, of {}",
+                 if self.ram_enabled { "enabled" } else { "disabled" },
+                 self.ram_bank_offset / 0x2000,
+                 (self.ram.len() + 0x1fff) / 0x2000);
+        if let Mbc::MBC1 = self.mbc {
+            println!("Bank registers: lower {:02x}, upper {:x}, mode {}",
+                     self.rom_bank_lower,
+                     self.bank_upper,
+                     if self.ram_banking { "1 (RAM banking)" } else { "0 (ROM banking)" });
+        }
+    }
+
     fn update_rom_offset(&mut self) {
         let bank_upper = self.bank_upper << 5;
         let lower = if self.ram_banking { bank_upper } else { 0x00 };
@@ -238,3 +322,117 @@ impl Cartridge {
         }
     }
 }
+
+fn read_file(file_name: &Path) -> io::Result<Vec<u8>> {
+    let mut file = File::open(file_name)?;
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer)?;
+    Ok(buffer)
+}
+
+// The ROM isn't part of the state, only a checksum of it so states can't be
+// loaded into a different game
+impl SaveState for Cartridge {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u32(self.rom_crc);
+        state.bytes(&self.ram);
+
+        state.u32(self.rom_bank_lower as u32);
+        state.u32(self.bank_upper as u32);
+        state.bool(self.ram_banking);
+        state.u32(self.rom_offsets.0 as u32);
+        state.u32(self.rom_offsets.1 as u32);
+        state.u32(self.ram_bank_offset as u32);
+        state.bool(self.ram_enabled);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        if state.u32()? != self.rom_crc {
+            return Err(invalid_state("state is for a different ROM"));
+        }
+        state.bytes(&mut self.ram)?;
+
+        let rom_bank_lower = state.u32()? as usize;
+        let bank_upper = state.u32()? as usize;
+        let ram_banking = state.bool()?;
+        let rom_offsets = (state.u32()? as usize, state.u32()? as usize);
+        let ram_bank_offset = state.u32()? as usize;
+        let ram_enabled = state.bool()?;
+
+        // The offsets are used to index the ROM and RAM directly, so a
+        // corrupt state would otherwise panic on the next read
+        let bank_fits = |offset: usize, bank_size: usize, len: usize| {
+            offset % bank_size == 0 && (offset == 0 || offset + bank_size <= len)
+        };
+        if rom_bank_lower > 0x1f || bank_upper > 0x03 ||
+           !bank_fits(rom_offsets.0, 0x4000, self.rom.len()) ||
+           !bank_fits(rom_offsets.1, 0x4000, self.rom.len()) ||
+           !bank_fits(ram_bank_offset, 0x2000, self.ram.len()) {
+            return Err(invalid_state("state has banks the cartridge doesn't have"));
+        }
+
+        self.rom_bank_lower = rom_bank_lower;
+        self.bank_upper = bank_upper;
+        self.ram_banking = ram_banking;
+        self.rom_offsets = rom_offsets;
+        self.ram_bank_offset = ram_bank_offset;
+        self.ram_enabled = ram_enabled;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use state::StateWriter;
+
+    // A 64KB MBC1 cartridge with 32KB of RAM
+    fn test_rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x10000];
+        rom[ROM_TYPE_OFFSET] = 0x03;
+        rom[RAM_SIZE_OFFSET] = 0x03;
+        rom
+    }
+
+    // The test cartridge switched to ROM bank 3 and RAM bank 2
+    fn test_cartridge() -> Cartridge {
+        let mut cartridge = Cartridge::from_bytes(&test_rom());
+        cartridge.write(0x2000, 0x03);
+        cartridge.write(0x4000, 0x02);
+        cartridge.write(0x6000, 0x01);
+        cartridge
+    }
+
+    fn state_of(cartridge: &Cartridge) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        cartridge.save_state(&mut state);
+        state.into_bytes()
+    }
+
+    #[test]
+    fn round_trip() {
+        let state = state_of(&test_cartridge());
+        let mut cartridge = Cartridge::from_bytes(&test_rom());
+        cartridge.load_state(&mut StateReader::new(&state)).unwrap();
+        assert_eq!(cartridge.bank_at(0x4000), 3);
+        assert_eq!(cartridge.bank_at(0xa000), 2);
+    }
+
+    #[test]
+    fn rejects_bad_offsets() {
+        let mut cartridge = test_cartridge();
+        let state = state_of(&cartridge);
+        // The banking registers and offsets follow the ROM checksum and the
+        // RAM with its length
+        let registers = 4 + 4 + 0x8000;
+
+        // ROM offsets past the end of the ROM or not on a bank boundary, then
+        // a RAM offset past the end of the RAM
+        for &(field, offset) in &[(9, 0x10000u32), (13, 0x4001), (17, 0x8000)] {
+            let mut corrupt = state.clone();
+            corrupt[registers + field..registers + field + 4].copy_from_slice(&offset.to_le_bytes());
+            assert!(cartridge.load_state(&mut StateReader::new(&corrupt)).is_err());
+        }
+        assert_eq!(cartridge.bank_at(0x4000), 3);
+    }
+}
@@ -0,0 +1,336 @@
+extern crate app_dirs;
+
+use std::borrow::Cow;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use config::app_info::APP_INFO;
+use self::app_dirs::{AppDataType, app_dir, get_app_dir};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheatCode {
+    // Replaces ROM reads at addr, only while the original byte matches
+    // compare if one was given so that the right bank is patched
+    GameGenie { addr: u16, value: u8, compare: Option<u8> },
+    // Writes value to addr once a frame. Bank 0x80-0x8f selects a cartridge
+    // RAM bank, other banks write to whatever is mapped.
+    GameShark { bank: u8, addr: u16, value: u8 },
+}
+
+impl FromStr for CheatCode {
+    type Err = Cow<'static, str>;
+
+    // Game Genie codes are written ABC-DEF or ABC-DEF-GHI and GameShark codes
+    // as eight hex digits, BBVVLLHH
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits: String = s.chars().filter(|c| *c != '-').collect();
+        let hex = |range: ::std::ops::Range<usize>| u16::from_str_radix(&digits[range], 16);
+
+        if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("Invalid cheat code {}", s).into());
+        }
+
+        match (digits.len(), s.contains('-')) {
+            (6, true) | (9, true) => {
+                let value = hex(0..2).unwrap() as u8;
+                let addr = (hex(5..6).unwrap() << 12 | hex(2..5).unwrap()) ^ 0xf000;
+                let compare = if digits.len() == 9 {
+                    let gi = (hex(6..7).unwrap() << 4 | hex(8..9).unwrap()) as u8;
+                    Some(gi.rotate_right(2) ^ 0xba)
+                } else {
+                    None
+                };
+
+                if addr >= 0x8000 {
+                    return Err(format!("Game Genie code {} doesn't patch ROM", s).into());
+                }
+
+                Ok(CheatCode::GameGenie {
+                    addr: addr,
+                    value: value,
+                    compare: compare,
+                })
+            }
+            (8, false) => {
+                Ok(CheatCode::GameShark {
+                    bank: hex(0..2).unwrap() as u8,
+                    value: hex(2..4).unwrap() as u8,
+                    addr: hex(6..8).unwrap() << 8 | hex(4..6).unwrap(),
+                })
+            }
+            _ => Err(format!("Unrecognised cheat code {}", s).into()),
+        }
+    }
+}
+
+impl fmt::Display for CheatCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CheatCode::GameGenie { addr, value, compare } => {
+                write!(f, "Game Genie {:04x} = {:02x}", addr, value)?;
+                if let Some(compare) = compare {
+                    write!(f, " if {:02x}", compare)?;
+                }
+                Ok(())
+            }
+            CheatCode::GameShark { bank, addr, value } => {
+                write!(f, "GameShark {:02x}:{:04x} = {:02x}", bank, addr, value)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Cheat {
+    pub code: String,
+    pub name: String,
+    pub cheat: CheatCode,
+    pub enabled: bool,
+}
+
+// The cheats for a single ROM, saved as one "on|off CODE name" line each
+pub struct Cheats {
+    cheats: Vec<Cheat>,
+    file_path: PathBuf,
+    modified: bool,
+}
+
+impl Default for Cheats {
+    fn default() -> Self {
+        Cheats {
+            cheats: Vec::new(),
+            file_path: PathBuf::new(),
+            modified: false,
+        }
+    }
+}
+
+impl Cheats {
+    pub fn load<P: AsRef<Path>>(file_name: P) -> io::Result<Cheats> {
+        let mut cheats = Cheats {
+            file_path: file_name.as_ref().to_path_buf(),
+            ..Cheats::default()
+        };
+
+        let file = match File::open(file_name.as_ref()) {
+            Ok(f) => f,
+            Err(_) => return Ok(cheats),
+        };
+
+        for line in BufReader::new(&file).lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut parts = line.splitn(3, char::is_whitespace);
+            let enabled = match parts.next() {
+                Some("on") => true,
+                Some("off") => false,
+                _ => return Err(invalid_line(line)),
+            };
+            let code = parts.next().unwrap_or("").to_uppercase();
+            let name = parts.next().unwrap_or("").trim();
+
+            match code.parse() {
+                Ok(cheat) => {
+                    cheats.cheats.push(Cheat {
+                        code: code,
+                        name: name.to_owned(),
+                        cheat: cheat,
+                        enabled: enabled,
+                    })
+                }
+                Err(_) => return Err(invalid_line(line)),
+            }
+        }
+
+        Ok(cheats)
+    }
+
+    // Loads the cheats kept for the ROM in the user data directory, e.g.
+    // cheats/game.cht for game.gb
+    pub fn for_rom<P: AsRef<Path>>(rom_file: P) -> io::Result<Cheats> {
+        let file_name = match rom_file.as_ref().file_stem() {
+            Some(stem) => Path::new(stem).with_extension("cht"),
+            None => return Ok(Self::default()),
+        };
+
+        match get_app_dir(AppDataType::UserData, &APP_INFO, "cheats") {
+            Ok(dir) => Self::load(dir.join(file_name)),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        if !self.modified || self.file_path.as_os_str().is_empty() {
+            return Ok(());
+        }
+
+        // Make sure the cheats directory exists before writing into it
+        if let Err(e) = app_dir(AppDataType::UserData, &APP_INFO, "cheats") {
+            return Err(io::Error::new(io::ErrorKind::Other, e.to_string()));
+        }
+
+        let mut file = File::create(&self.file_path)?;
+        for cheat in &self.cheats {
+            let line = format!("{} {} {}", if cheat.enabled { "on" } else { "off" }, cheat.code, cheat.name);
+            writeln!(file, "{}", line.trim_end())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn all(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    // Adds a new enabled cheat, returning its index. Adding a code that is
+    // already present just enables it.
+    pub fn add(&mut self, code: &str, name: &str) -> Result<usize, Cow<'static, str>> {
+        let cheat = code.parse()?;
+        let code = code.to_uppercase();
+
+        self.modified = true;
+        if let Some(index) = self.cheats.iter().position(|c| c.code == code) {
+            self.cheats[index].enabled = true;
+            return Ok(index);
+        }
+
+        self.cheats.push(Cheat {
+            code: code,
+            name: name.to_owned(),
+            cheat: cheat,
+            enabled: true,
+        });
+        Ok(self.cheats.len() - 1)
+    }
+
+    pub fn remove(&mut self, index: usize) -> bool {
+        if index < self.cheats.len() {
+            self.cheats.remove(index);
+            self.modified = true;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) -> bool {
+        match self.cheats.get_mut(index) {
+            Some(cheat) => {
+                cheat.enabled = enabled;
+                self.modified = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Applies any Game Genie codes to a byte read from ROM
+    pub fn patch_rom_read(&self, addr: u16, val: u8) -> u8 {
+        for cheat in self.cheats.iter().filter(|c| c.enabled) {
+            if let CheatCode::GameGenie { addr: a, value, compare } = cheat.cheat {
+                if a == addr && compare.map_or(true, |c| c == val) {
+                    return value;
+                }
+            }
+        }
+
+        val
+    }
+
+    // Returns the (bank, addr, value) writes to make for this frame
+    pub fn ram_pokes(&self) -> Vec<(u8, u16, u8)> {
+        self.cheats
+            .iter()
+            .filter(|c| c.enabled)
+            .filter_map(|c| match c.cheat {
+                CheatCode::GameShark { bank, addr, value } => Some((bank, addr, value)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+fn invalid_line(line: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   format!("Unrecognised cheat line: {}", line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn game_genie() {
+        assert_eq!("3E2-A8F".parse(),
+                   Ok(CheatCode::GameGenie { addr: 0x02a8, value: 0x3e, compare: None }));
+        assert_eq!("00A-17B-C49".parse(),
+                   Ok(CheatCode::GameGenie { addr: 0x4a17, value: 0x00, compare: Some(0xc8) }));
+        assert_eq!("3e2-a8f-e6a".parse(),
+                   Ok(CheatCode::GameGenie { addr: 0x02a8, value: 0x3e, compare: Some(0x00) }));
+    }
+
+    #[test]
+    fn game_genie_outside_rom() {
+        assert!("3E2-A80".parse::<CheatCode>().is_err());
+    }
+
+    #[test]
+    fn gameshark() {
+        assert_eq!("010138CD".parse(),
+                   Ok(CheatCode::GameShark { bank: 0x01, addr: 0xcd38, value: 0x01 }));
+        assert_eq!("91FF47d3".parse(),
+                   Ok(CheatCode::GameShark { bank: 0x91, addr: 0xd347, value: 0xff }));
+    }
+
+    #[test]
+    fn unrecognised() {
+        assert!("".parse::<CheatCode>().is_err());
+        assert!("3E2A8F".parse::<CheatCode>().is_err());
+        assert!("010-138-CD".parse::<CheatCode>().is_err());
+        assert!("0101-38CD".parse::<CheatCode>().is_err());
+        assert!("0G0138CD".parse::<CheatCode>().is_err());
+    }
+
+    #[test]
+    fn add_normalises_case() {
+        let mut cheats = Cheats::default();
+        assert_eq!(cheats.add("3e2-a8f", "lives"), Ok(0));
+        assert_eq!(cheats.add("3E2-A8F", ""), Ok(0));
+        assert_eq!(cheats.all()[0].code, "3E2-A8F");
+    }
+
+    #[test]
+    fn load() {
+        let path = ::std::env::temp_dir().join("scimitar_cheats_test.cht");
+        ::std::fs::write(&path, "on 3e2-a8f lives\noff 91ff47d3\n").unwrap();
+        let cheats = Cheats::load(&path).unwrap();
+        assert_eq!(cheats.all()[0].code, "3E2-A8F");
+        assert_eq!(cheats.all()[0].name, "lives");
+        assert!(!cheats.all()[1].enabled);
+
+        ::std::fs::write(&path, "on 3e2-a8f lives\nmaybe 91ff47d3\n").unwrap();
+        assert!(Cheats::load(&path).is_err());
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn apply() {
+        let mut cheats = Cheats::default();
+        cheats.add("00A-17B-C49", "").unwrap();
+        cheats.add("91FF47D3", "").unwrap();
+
+        assert_eq!(cheats.patch_rom_read(0x4a17, 0xc8), 0x00);
+        // Another bank is mapped in
+        assert_eq!(cheats.patch_rom_read(0x4a17, 0xc9), 0xc9);
+        assert_eq!(cheats.ram_pokes(), [(0x91, 0xd347, 0xff)]);
+
+        cheats.set_enabled(0, false);
+        assert_eq!(cheats.patch_rom_read(0x4a17, 0xc8), 0xc8);
+    }
+}
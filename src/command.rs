@@ -1,34 +1,60 @@
-use combine::{choice, eof, many, many1, optional, Parser, parser, try, value};
+use combine::{any, choice, eof, many, many1, optional, Parser, parser, try, value};
 use combine::char::{alpha_num, digit, hex_digit, letter, space, spaces, string};
 use combine::primitives::{ParseResult, Stream};
 use combine;
 
 use std::str::{self, FromStr};
 use std::borrow::Cow;
+use ioregs::{GROUP_NAMES, RegisterGroup};
+use search::{SearchFilter, SearchWidth};
+use vm::Speed;
 
 #[derive(Debug, Clone)]
 pub enum Command {
     ShowRegs,
-    ShowIORegs,
+    ShowIORegs(Option<RegisterGroup>),
     Step(usize),
+    Next(usize),
+    Finish,
+    Backtrace,
+    Profile,
     Continue,
+    FrameAdvance(usize),
+    Speed(Option<Speed>),
     Goto(u16),
     ShowMem(Option<u16>),
     Disassemble(usize),
 
     Breakpoint,
-    AddBreakpoint(u16),
+    AddBreakpoint(Option<u16>, u16),
     AddSymBreakpoint(String),
-    AddTempBreakpoint(u16),
+    AddTempBreakpoint(Option<u16>, u16),
     AddTempSymBreakpoint(String),
-    RemoveBreakpoint(u16),
-    RemoveTempBreakpoint(u16),
+    RemoveBreakpoint(Option<u16>, u16),
+    RemoveTempBreakpoint(Option<u16>, u16),
 
     Watchpoint,
     AddWatchpoint(u16),
     RemoveWatchpoint(u16),
-    AddSymbol(u16, String),
-    RemoveSymbol(u16),
+    AddSymbol(Option<u16>, u16, String),
+    RemoveSymbol(Option<u16>, u16),
+
+    SearchStart(SearchWidth),
+    SearchFilter(SearchFilter),
+    SearchResults,
+    SearchWatch(usize),
+    SearchSymbol(usize, String),
+    SearchFreeze(usize),
+    Frozen,
+    Thaw(Option<u16>, u16),
+
+    Cheats,
+    AddCheat(String, String),
+    EnableCheat(usize),
+    DisableCheat(usize),
+    RemoveCheat(usize),
+    Screenshot(Option<String>),
+    RecordMovie(String),
     Exit,
     Repeat,
 }
@@ -49,17 +75,47 @@ fn command<I: Stream<Item = char>>(input: I) -> ParseResult<Command, I> {
         choice([try(string("showregs")), try(string("r"))]).map(|_| Command::ShowRegs).boxed();
 
     let show_io_regs =
-        choice([try(string("showioregs")), try(string("ior"))]).map(|_| Command::ShowIORegs).boxed();
+        (choice([try(string("showioregs")), try(string("ior"))]),
+         optional((spaces(), register_group()).map(|x| x.1)))
+                .map(|(_, group)| Command::ShowIORegs(group))
+                .boxed();
 
     let step =
-        (choice([try(string("step")), try(string("s")), try(string("next")), try(string("n"))]),
+        (choice([try(string("step")), try(string("s"))]),
          optional((spaces(), usize_()).map(|x| x.1)))
                 .map(|(_, count)| Command::Step(count.unwrap_or(1)))
                 .boxed();
 
+    let next =
+        (choice([try(string("next")), try(string("n"))]),
+         optional((spaces(), usize_()).map(|x| x.1)))
+                .map(|(_, count)| Command::Next(count.unwrap_or(1)))
+                .boxed();
+
+    let finish =
+        choice([try(string("finish")), try(string("f"))]).map(|_| Command::Finish).boxed();
+
+    let backtrace =
+        choice([try(string("backtrace")), try(string("bt"))]).map(|_| Command::Backtrace).boxed();
+
+    let profile =
+        choice([try(string("profile")), try(string("p"))]).map(|_| Command::Profile).boxed();
+
     let continue_ =
         choice([try(string("continue")), try(string("c"))]).map(|_| Command::Continue).boxed();
 
+    let frame_advance =
+        (choice([try(string("frameadvance")), try(string("fa"))]),
+         optional((spaces(), usize_()).map(|x| x.1)))
+                .map(|(_, count)| Command::FrameAdvance(count.unwrap_or(1)))
+                .boxed();
+
+    let speed =
+        (choice([try(string("speed")), try(string("sp"))]),
+         optional((spaces(), speed()).map(|x| x.1)))
+                .map(|(_, speed)| Command::Speed(speed))
+                .boxed();
+
     let goto = (choice([try(string("goto")), try(string("g"))]), spaces(), u16_hex())
         .map(|(_, _, addr)| Command::Goto(addr))
         .boxed();
@@ -78,8 +134,8 @@ fn command<I: Stream<Item = char>>(input: I) -> ParseResult<Command, I> {
         choice([try(string("breakpoint")), try(string("b"))]).map(|_| Command::Breakpoint).boxed();
 
     let add_breakpoint =
-        (choice([try(string("addbreakpoint")), try(string("ab"))]), space(), u16_hex())
-            .map(|(_, _, addr)| Command::AddBreakpoint(addr))
+        (choice([try(string("addbreakpoint")), try(string("ab"))]), space(), banked_addr())
+            .map(|(_, _, (bank, addr))| Command::AddBreakpoint(bank, addr))
             .boxed();
 
     let add_sym_breakpoint =
@@ -88,8 +144,8 @@ fn command<I: Stream<Item = char>>(input: I) -> ParseResult<Command, I> {
             .boxed();
 
     let add_temp_breakpoint =
-        (choice([try(string("addtempbreakpoint")), try(string("at"))]), space(), u16_hex())
-            .map(|(_, _, addr)| Command::AddTempBreakpoint(addr))
+        (choice([try(string("addtempbreakpoint")), try(string("at"))]), space(), banked_addr())
+            .map(|(_, _, (bank, addr))| Command::AddTempBreakpoint(bank, addr))
             .boxed();
 
     let add_temp_sym_breakpoint =
@@ -98,13 +154,13 @@ fn command<I: Stream<Item = char>>(input: I) -> ParseResult<Command, I> {
             .boxed();
 
     let remove_breakpoint =
-        (choice([try(string("removebreakpoint")), try(string("rb"))]), space(), u16_hex())
-            .map(|(_, _, addr)| Command::RemoveBreakpoint(addr))
+        (choice([try(string("removebreakpoint")), try(string("rb"))]), space(), banked_addr())
+            .map(|(_, _, (bank, addr))| Command::RemoveBreakpoint(bank, addr))
             .boxed();
 
     let remove_temp_breakpoint =
-        (choice([try(string("removetempbreakpoint")), try(string("rt"))]), space(), u16_hex())
-            .map(|(_, _, addr)| Command::RemoveTempBreakpoint(addr))
+        (choice([try(string("removetempbreakpoint")), try(string("rt"))]), space(), banked_addr())
+            .map(|(_, _, (bank, addr))| Command::RemoveTempBreakpoint(bank, addr))
             .boxed();
 
     let watchpoint =
@@ -121,15 +177,89 @@ fn command<I: Stream<Item = char>>(input: I) -> ParseResult<Command, I> {
             .boxed();
 
     let add_symbol =
-        (choice([try(string("addsymbol")), try(string("as"))]), space(), u16_hex(), space(), symbol_id())
-        .map(|(_, _, addr, _, sym)| Command::AddSymbol(addr, sym))
+        (choice([try(string("addsymbol")), try(string("as"))]), space(), banked_addr(), space(), symbol_id())
+        .map(|(_, _, (bank, addr), _, sym)| Command::AddSymbol(bank, addr, sym))
         .boxed();
 
     let remove_symbol =
-        (choice([try(string("removesymbol")), try(string("rs"))]), space(), u16_hex())
-        .map(|(_, _, addr)| Command::RemoveSymbol(addr))
+        (choice([try(string("removesymbol")), try(string("rs"))]), space(), banked_addr())
+        .map(|(_, _, (bank, addr))| Command::RemoveSymbol(bank, addr))
         .boxed();
 
+    let search_start =
+        (choice([try(string("searchstart")), try(string("ss"))]),
+         optional((spaces(), search_width()).map(|x| x.1)))
+            .map(|(_, width)| Command::SearchStart(width.unwrap_or(SearchWidth::Byte)))
+            .boxed();
+
+    let search_filter =
+        (choice([try(string("searchfilter")), try(string("sf"))]), space(), search_filter())
+            .map(|(_, _, filter)| Command::SearchFilter(filter))
+            .boxed();
+
+    let search_results =
+        choice([try(string("searchresults")), try(string("sr"))]).map(|_| Command::SearchResults).boxed();
+
+    let search_watch =
+        (choice([try(string("searchwatch")), try(string("sw"))]), space(), usize_())
+            .map(|(_, _, index)| Command::SearchWatch(index))
+            .boxed();
+
+    let search_symbol =
+        (choice([try(string("searchsymbol")), try(string("sy"))]), space(), usize_(), space(), symbol_id())
+            .map(|(_, _, index, _, sym)| Command::SearchSymbol(index, sym))
+            .boxed();
+
+    let search_freeze =
+        (choice([try(string("searchfreeze")), try(string("sz"))]), space(), usize_())
+            .map(|(_, _, index)| Command::SearchFreeze(index))
+            .boxed();
+
+    let frozen =
+        choice([try(string("frozen")), try(string("fz"))]).map(|_| Command::Frozen).boxed();
+
+    let thaw =
+        (choice([try(string("thaw")), try(string("th"))]), space(), banked_addr())
+            .map(|(_, _, (bank, addr))| Command::Thaw(bank, addr))
+            .boxed();
+
+    let cheats =
+        choice([try(string("cheats")), try(string("ch"))]).map(|_| Command::Cheats).boxed();
+
+    let add_cheat =
+        (choice([try(string("addcheat")), try(string("ac"))]),
+         space(),
+         cheat_code(),
+         optional((space(), many1(any())).map(|x| x.1)))
+            .map(|(_, _, code, name)| Command::AddCheat(code, name.unwrap_or_default()))
+            .boxed();
+
+    let enable_cheat =
+        (choice([try(string("enablecheat")), try(string("ec"))]), space(), usize_())
+            .map(|(_, _, index)| Command::EnableCheat(index))
+            .boxed();
+
+    let disable_cheat =
+        (choice([try(string("disablecheat")), try(string("dc"))]), space(), usize_())
+            .map(|(_, _, index)| Command::DisableCheat(index))
+            .boxed();
+
+    let remove_cheat =
+        (choice([try(string("removecheat")), try(string("rc"))]), space(), usize_())
+            .map(|(_, _, index)| Command::RemoveCheat(index))
+            .boxed();
+
+    let screenshot =
+        (choice([try(string("screenshot")), try(string("sc"))]),
+         optional((space(), many1(any())).map(|x| x.1)))
+            .map(|(_, file_name)| Command::Screenshot(file_name))
+            .boxed();
+
+    let record_movie =
+        (choice([try(string("recordmovie")), try(string("rec"))]), space(), many1(any()))
+            .map(|(_, _, file_name)| Command::RecordMovie(file_name))
+            .boxed();
+
     let exit = choice([try(string("exit")),
                        try(string("quit")),
                        try(string("e")),
@@ -143,7 +273,13 @@ fn command<I: Stream<Item = char>>(input: I) -> ParseResult<Command, I> {
     choice(vec![show_regs,
                 show_io_regs,
                 step,
+                next,
+                finish,
+                backtrace,
+                profile,
                 continue_,
+                frame_advance,
+                speed,
                 goto,
                 show_mem,
                 disassemble,
@@ -159,6 +295,21 @@ fn command<I: Stream<Item = char>>(input: I) -> ParseResult<Command, I> {
                 remove_watchpoint,
                 add_symbol,
                 remove_symbol,
+                search_start,
+                search_filter,
+                search_results,
+                search_watch,
+                search_symbol,
+                search_freeze,
+                frozen,
+                thaw,
+                cheats,
+                add_cheat,
+                enable_cheat,
+                disable_cheat,
+                remove_cheat,
+                screenshot,
+                record_movie,
                 exit,
                 repeat]
                    .into_iter()
@@ -180,6 +331,61 @@ fn u16_hex<'a, I: Stream<Item = char> + 'a>() -> Box<Parser<Input = I, Output =
         .boxed()
 }
 
+// An address with an optional bank prefix, e.g. 01:4000
+fn banked_addr<'a, I: Stream<Item = char> + 'a>() -> Box<Parser<Input = I, Output = (Option<u16>, u16)> + 'a> {
+    let bank = (many1(hex_digit()), combine::char::char(':'))
+        .map(|x| x.0)
+        .and_then(|s: String| u16::from_str_radix(&s, 16));
+    (optional(try(bank)), u16_hex()).boxed()
+}
+
+// A multiplier such as 2 or 0.5, or max for unthrottled
+fn speed<'a, I: Stream<Item = char> + 'a>() -> Box<Parser<Input = I, Output = Speed> + 'a> {
+    let unthrottled = try(string("max")).map(|_| Speed::Unthrottled);
+    let multiplier = many1(digit().or(combine::char::char('.')))
+        .and_then(|s: String| s.parse::<f64>())
+        .map(Speed::from_multiplier);
+
+    choice([unthrottled.boxed(), try(multiplier).boxed()]).boxed()
+}
+
+fn search_width<'a, I: Stream<Item = char> + 'a>() -> Box<Parser<Input = I, Output = SearchWidth> + 'a> {
+    choice([try(string("16")), try(string("8"))])
+        .map(|w| if w == "16" { SearchWidth::Word } else { SearchWidth::Byte })
+        .boxed()
+}
+
+fn register_group<'a, I: Stream<Item = char> + 'a>() -> Box<Parser<Input = I, Output = RegisterGroup> + 'a> {
+    choice(GROUP_NAMES.iter()
+               .map(|&(name, group)| try(string(name)).map(move |_| group))
+               .collect::<Vec<_>>())
+        .boxed()
+}
+
+// A comparison against the previous snapshot, or a hex value to match
+fn search_filter<'a, I: Stream<Item = char> + 'a>() -> Box<Parser<Input = I, Output = SearchFilter> + 'a> {
+    let equal = choice([try(string("equal")), try(string("eq")), try(string("="))])
+        .map(|_| SearchFilter::Equal);
+    let changed = choice([try(string("changed")), try(string("ne")), try(string("!="))])
+        .map(|_| SearchFilter::Changed);
+    let increased = choice([try(string("increased")), try(string("gt")), try(string(">"))])
+        .map(|_| SearchFilter::Increased);
+    let decreased = choice([try(string("decreased")), try(string("lt")), try(string("<"))])
+        .map(|_| SearchFilter::Decreased);
+
+    choice([try(equal.boxed()),
+            try(changed.boxed()),
+            try(increased.boxed()),
+            try(decreased.boxed()),
+            try(u16_hex().map(SearchFilter::Value).boxed())])
+        .boxed()
+}
+
+// Game Genie (ABC-DEF-GHI) or GameShark (01FF16C0) code, checked when added
+fn cheat_code<'a, I: Stream<Item = char> + 'a>() -> Box<Parser<Input = I, Output = String> + 'a> {
+    many1(hex_digit().or(combine::char::char('-'))).boxed()
+}
+
 fn symbol_id<'a, I: Stream<Item = char> + 'a>() -> Box<Parser<Input = I, Output = String> + 'a> {
     (letter().and(many(combine::char::char('_').or(alpha_num()))))
         .map(|(a, mut b): (char, String)| {b.insert(0, a); b})
@@ -46,4 +46,10 @@ impl Model {
             Model::Sgb2 => "sgb2_boot.bin",
         }
     }
+
+    // Guesses the model from a boot ROM's file name, for boot ROMs named the
+    // way lookup expects
+    pub fn from_bootrom_name(name: &str) -> Option<Model> {
+        DEFAULT_MODEL_PRIORITY.iter().find(|m| m.bootrom_name() == name).cloned()
+    }
 }
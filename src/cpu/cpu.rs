@@ -1,6 +1,8 @@
 use interconnect::Interconnect;
 use events::Event;
 use device::Device;
+use std::io;
+use state::{SaveState, StateReader, StateWriter, invalid_state};
 
 const CYCLES_PER_STEP: u16  = 4;
 
@@ -2466,6 +2468,7 @@ impl Cpu {
         // figure out what the other three are
         bus.step(CYCLES_PER_STEP * 2); // Internal delay
         call!(self, bus, addr);
+        bus.events.push(Event::Interrupt(addr));
         self.halted = 0;
     }
 
@@ -2703,3 +2706,41 @@ impl Default for Cpu {
         Self::new()
     }
 }
+
+impl SaveState for Cpu {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.af());
+        state.u16(self.bc());
+        state.u16(self.de());
+        state.u16(self.hl());
+        state.u16(self.sp);
+        state.u16(self.pc);
+        state.u8(match self.interrupt_state {
+            InterruptState::PendingDI => 0,
+            InterruptState::PendingEI => 1,
+            InterruptState::Enabled => 2,
+            InterruptState::Disabled => 3,
+        });
+        state.u8(self.halted as u8);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        let af = state.u16()?;
+        self.a = (af >> 8) as u8;
+        self.f = Flags::from(af as u8);
+        self.set_bc(state.u16()?);
+        self.set_de(state.u16()?);
+        self.set_hl(state.u16()?);
+        self.sp = state.u16()?;
+        self.pc = state.u16()?;
+        self.interrupt_state = match state.u8()? {
+            0 => InterruptState::PendingDI,
+            1 => InterruptState::PendingEI,
+            2 => InterruptState::Enabled,
+            3 => InterruptState::Disabled,
+            _ => return Err(invalid_state("invalid CPU interrupt state")),
+        };
+        self.halted = state.u8()? as i8;
+        Ok(())
+    }
+}
@@ -112,8 +112,10 @@ macro_rules! call {
     ($c: expr, $b: expr, $a: expr) => {
         {
             let pc = $c.pc;
+            let addr = $a;
             push_u16!($c, $b, pc);
-            $c.pc = $a;
+            $c.pc = addr;
+            $b.events.push(Event::Call { target: addr, return_addr: pc });
         }
     }
 }
@@ -125,6 +127,7 @@ macro_rules! ret {
             let addr = pop_u16!($c, $b);
             $b.step(CYCLES_PER_STEP); // Internal delay
             $c.pc = addr;
+            $b.events.push(Event::Return(addr));
         }
     }
 }
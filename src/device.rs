@@ -1,5 +1,63 @@
-#[derive(Clone, Copy)]
+use std::borrow::Cow;
+use std::str::FromStr;
+use input::Buttons;
+use viewer::VideoState;
+
+// Host inputs that can be bound to Game Boy buttons and hotkeys. Pad(n) is
+// button n on a game controller, for frontends that support them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Key {
+    Key0,
+    Key1,
+    Key2,
+    Key3,
+    Key4,
+    Key5,
+    Key6,
+    Key7,
+    Key8,
+    Key9,
+
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
+    G,
+    H,
+    I,
+    J,
+    K,
+    L,
+    M,
+    N,
+    O,
+    P,
+    Q,
+    R,
+    S,
+    T,
+    U,
+    V,
+    W,
+    X,
+    Y,
+    Z,
+
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+
     Up,
     Down,
     Left,
@@ -7,16 +65,90 @@ pub enum Key {
 
     Backspace,
     Enter,
+    Space,
+    Tab,
+    Escape,
+    Insert,
+    Delete,
+    Home,
+    End,
+    PageUp,
+    PageDown,
 
-    Z,
-    X,
+    LeftShift,
+    RightShift,
+    LeftCtrl,
+    RightCtrl,
+    LeftAlt,
+    RightAlt,
+
+    Minus,
+    Equal,
+    Comma,
+    Period,
+    Slash,
+    Semicolon,
+    Apostrophe,
+
+    Pad(u8),
+}
+
+static KEY_NAMES: [(Key, &str); 76] = [
+    (Key::Key0, "Key0"), (Key::Key1, "Key1"), (Key::Key2, "Key2"), (Key::Key3, "Key3"),
+    (Key::Key4, "Key4"), (Key::Key5, "Key5"), (Key::Key6, "Key6"), (Key::Key7, "Key7"),
+    (Key::Key8, "Key8"), (Key::Key9, "Key9"),
+    (Key::A, "A"), (Key::B, "B"), (Key::C, "C"), (Key::D, "D"), (Key::E, "E"), (Key::F, "F"),
+    (Key::G, "G"), (Key::H, "H"), (Key::I, "I"), (Key::J, "J"), (Key::K, "K"), (Key::L, "L"),
+    (Key::M, "M"), (Key::N, "N"), (Key::O, "O"), (Key::P, "P"), (Key::Q, "Q"), (Key::R, "R"),
+    (Key::S, "S"), (Key::T, "T"), (Key::U, "U"), (Key::V, "V"), (Key::W, "W"), (Key::X, "X"),
+    (Key::Y, "Y"), (Key::Z, "Z"),
+    (Key::F1, "F1"), (Key::F2, "F2"), (Key::F3, "F3"), (Key::F4, "F4"), (Key::F5, "F5"),
+    (Key::F6, "F6"), (Key::F7, "F7"), (Key::F8, "F8"), (Key::F9, "F9"), (Key::F10, "F10"),
+    (Key::F11, "F11"), (Key::F12, "F12"),
+    (Key::Up, "Up"), (Key::Down, "Down"), (Key::Left, "Left"), (Key::Right, "Right"),
+    (Key::Backspace, "Backspace"), (Key::Enter, "Enter"), (Key::Space, "Space"), (Key::Tab, "Tab"),
+    (Key::Escape, "Escape"), (Key::Insert, "Insert"), (Key::Delete, "Delete"), (Key::Home, "Home"),
+    (Key::End, "End"), (Key::PageUp, "PageUp"), (Key::PageDown, "PageDown"),
+    (Key::LeftShift, "LeftShift"), (Key::RightShift, "RightShift"), (Key::LeftCtrl, "LeftCtrl"),
+    (Key::RightCtrl, "RightCtrl"), (Key::LeftAlt, "LeftAlt"), (Key::RightAlt, "RightAlt"),
+    (Key::Minus, "Minus"), (Key::Equal, "Equal"), (Key::Comma, "Comma"), (Key::Period, "Period"),
+    (Key::Slash, "Slash"), (Key::Semicolon, "Semicolon"), (Key::Apostrophe, "Apostrophe"),
+];
+
+impl FromStr for Key {
+    type Err = Cow<'static, str>;
+
+    // Key names match the variants, e.g. Enter, F5 or Pad3
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() > 3 && s.is_char_boundary(3) && s[..3].eq_ignore_ascii_case("pad") {
+            return match s[3..].parse() {
+                Ok(n) => Ok(Key::Pad(n)),
+                Err(_) => Err(format!("Unrecognised key {}", s).into()),
+            };
+        }
+
+        match KEY_NAMES.iter().find(|&&(_, name)| name.eq_ignore_ascii_case(s)) {
+            Some(&(key, _)) => Ok(key),
+            None => Err(format!("Unrecognised key {}", s).into()),
+        }
+    }
 }
 
 pub trait Device {
     fn update(&mut self);
     fn set_frame_buffer(&mut self, buffer: &[u32]);
 
+    // Called with VRAM, OAM and the PPU registers at the start of each
+    // VBlank, for devices that show debug viewers
+    fn set_video_state(&mut self, _: &VideoState) {}
+
     fn key_down(&self, key: Key) -> bool;
 
+    // The Game Boy buttons held down, polled once a frame. Devices that
+    // return None have their keys mapped to buttons with the key bindings.
+    fn buttons(&mut self) -> Option<Buttons> {
+        None
+    }
+
     fn running(&self) -> bool;
 }
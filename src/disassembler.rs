@@ -0,0 +1,621 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, Write};
+use std::slice;
+use cartridge::Cartridge;
+use symbols::{BankedAddr, Symbols};
+
+const BANK_SIZE: usize = 0x4000;
+const BYTES_PER_DATA_LINE: usize = 8;
+// Runs of a single byte at least this long are written as a ds directive
+const MIN_FILL_RUN: usize = 16;
+
+const ENTRY_POINT: u16 = 0x0100;
+const INTERRUPT_VECTORS: [u16; 5] = [0x0040, 0x0048, 0x0050, 0x0058, 0x0060];
+
+const R8: [&str; 8] = ["b", "c", "d", "e", "h", "l", "[hl]", "a"];
+const R16: [&str; 4] = ["bc", "de", "hl", "sp"];
+const R16_STACK: [&str; 4] = ["bc", "de", "hl", "af"];
+const CONDITIONS: [&str; 4] = ["nz", "z", "nc", "c"];
+const ALU: [&str; 8] = ["add a,", "adc a,", "sub", "sbc a,", "and", "xor", "or", "cp"];
+const ROTATES: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+
+// Placeholder in an instruction's text for its jump/call target, replaced with
+// a label when the target is known
+const TARGET: &str = "{target}";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Flow {
+    Continue,
+    // Unconditional jump, execution never reaches the next instruction
+    Jump,
+    // Conditional jump, either the target or the next instruction runs
+    Branch,
+    Call,
+    // RET, RETI, JP HL and friends, where the next instruction isn't known
+    Stop,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ByteKind {
+    Data,
+    CodeStart,
+    CodeBody,
+}
+
+struct Instruction {
+    length: u16,
+    text: String,
+    target: Option<u16>,
+    flow: Flow,
+}
+
+// A static disassembly of a whole cartridge. Code is found by following
+// control flow from the entry point and interrupt vectors, anything not
+// reached that way is treated as data.
+pub struct Disassembly<'a> {
+    cartridge: &'a Cartridge,
+    symbols: &'a Symbols,
+
+    kinds: Vec<ByteKind>,
+    labels: BTreeMap<BankedAddr, String>,
+    // The resolved jump/call target of the instruction at each ROM offset
+    targets: HashMap<usize, BankedAddr>,
+}
+
+impl<'a> Disassembly<'a> {
+    pub fn new(cartridge: &'a Cartridge, symbols: &'a Symbols) -> Self {
+        let mut disassembly = Disassembly {
+            cartridge: cartridge,
+            symbols: symbols,
+
+            kinds: vec![ByteKind::Data; cartridge.rom().len()],
+            labels: BTreeMap::new(),
+            targets: HashMap::new(),
+        };
+
+        disassembly.trace();
+        disassembly
+    }
+
+    // Writes the disassembly as RGBDS assembly, one section per ROM bank
+    pub fn write_rgbds<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "; Disassembled by scimitar")?;
+
+        for bank in 0..self.cartridge.rom_bank_count() {
+            writeln!(out)?;
+            if bank == 0 {
+                writeln!(out, "SECTION \"ROM Bank $000\", ROM0[$0000]")?;
+            } else {
+                writeln!(out, "SECTION \"ROM Bank ${:03x}\", ROMX[$4000], BANK[${:x}]", bank, bank)?;
+            }
+            writeln!(out)?;
+
+            self.write_bank(out, bank)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_bank<W: Write>(&self, out: &mut W, bank: usize) -> io::Result<()> {
+        let start = bank * BANK_SIZE;
+        let end = ((bank + 1) * BANK_SIZE).min(self.kinds.len());
+        let mut offset = start;
+
+        while offset < end {
+            let addr = self.cpu_addr(bank, offset);
+            self.write_labels(out, BankedAddr::new(bank as u16, addr))?;
+
+            if self.kinds[offset] == ByteKind::CodeStart {
+                let instr = self.decode_at(bank, addr).unwrap();
+                writeln!(out, "    {}", self.format_instruction(&instr, offset))?;
+
+                // Labels inside the instruction, e.g. jumps that skip its
+                // opcode, have no line of their own so are defined relative
+                // to the end of it
+                let end = offset + instr.length as usize;
+                for inner in offset + 1..end {
+                    let addr = BankedAddr::new(bank as u16, self.cpu_addr(bank, inner));
+                    for label in self.labels_at(addr) {
+                        writeln!(out, "{} EQU @-{}", label, end - inner)?;
+                    }
+                }
+                offset = end;
+            } else {
+                offset = self.write_data(out, bank, offset, end)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_labels<W: Write>(&self, out: &mut W, addr: BankedAddr) -> io::Result<()> {
+        for label in self.labels_at(addr) {
+            writeln!(out, "{}:", label)?;
+        }
+        Ok(())
+    }
+
+    // The symbols at addr, or the generated label if it has none
+    fn labels_at(&self, addr: BankedAddr) -> &[String] {
+        let symbols = self.symbols.get_all(addr);
+        if !symbols.is_empty() {
+            symbols
+        } else {
+            match self.labels.get(&addr) {
+                Some(label) => slice::from_ref(label),
+                None => &[],
+            }
+        }
+    }
+
+    // Writes a run of data bytes, stopping at the next code or label so those
+    // get their own lines. Returns the offset of the first byte not written.
+    fn write_data<W: Write>(&self, out: &mut W, bank: usize, offset: usize, end: usize) -> io::Result<usize> {
+        let rom = self.cartridge.rom();
+
+        let mut run_end = offset + 1;
+        while run_end < end && self.is_plain_data(bank, run_end) {
+            run_end += 1;
+        }
+
+        let fill = rom[offset];
+        let fill_len = rom[offset..run_end].iter().take_while(|&&b| b == fill).count();
+        if fill_len >= MIN_FILL_RUN {
+            writeln!(out, "    ds {}, ${:02x}", fill_len, fill)?;
+            return Ok(offset + fill_len);
+        }
+
+        // Don't swallow the start of a long fill run into this line
+        let mut line_end = offset;
+        while line_end < run_end && line_end - offset < BYTES_PER_DATA_LINE {
+            let b = rom[line_end];
+            let repeats = rom[line_end..run_end].iter().take_while(|&&x| x == b).count();
+            if line_end != offset && repeats >= MIN_FILL_RUN {
+                break;
+            }
+            line_end += 1;
+        }
+
+        let bytes: Vec<_> = rom[offset..line_end].iter().map(|b| format!("${:02x}", b)).collect();
+        writeln!(out, "    db {}", bytes.join(", "))?;
+
+        Ok(line_end)
+    }
+
+    fn is_plain_data(&self, bank: usize, offset: usize) -> bool {
+        let addr = BankedAddr::new(bank as u16, self.cpu_addr(bank, offset));
+        self.kinds[offset] == ByteKind::Data && !self.labels.contains_key(&addr) &&
+            self.symbols.get_all(addr).is_empty()
+    }
+
+    fn format_instruction(&self, instr: &Instruction, offset: usize) -> String {
+        match instr.target {
+            Some(target) if instr.text.contains(TARGET) => {
+                let label = self.targets.get(&offset).and_then(|t| self.label_for(*t));
+                let operand = label.unwrap_or_else(|| format!("${:04x}", target));
+                instr.text.replace(TARGET, &operand)
+            }
+            _ => instr.text.clone(),
+        }
+    }
+
+    fn label_for(&self, addr: BankedAddr) -> Option<String> {
+        match self.symbols.get(addr) {
+            Some(sym) => Some(sym.to_owned()),
+            None => self.labels.get(&addr).cloned(),
+        }
+    }
+
+    // Follows code flow from every entry point, marking the bytes of each
+    // instruction reached and labelling jump and call targets
+    fn trace(&mut self) {
+        let mut queue = VecDeque::new();
+
+        queue.push_back((0, ENTRY_POINT, None));
+        for vector in &INTERRUPT_VECTORS {
+            queue.push_back((0, *vector, None));
+            self.labels.insert(BankedAddr::new(0, *vector), format!("Interrupt_{:04x}", vector));
+        }
+
+        while let Some((bank, addr, mapped_bank)) = queue.pop_front() {
+            self.trace_from(bank, addr, mapped_bank, &mut queue);
+        }
+    }
+
+    fn trace_from(&mut self, bank: u16, start: u16, mut mapped_bank: Option<u16>,
+                  queue: &mut VecDeque<(u16, u16, Option<u16>)>) {
+        let mut addr = start;
+        // Tracks the value of A loaded by "ld a, n8" so that a following write
+        // to the MBC bank register tells us which bank is mapped
+        let mut known_a = None;
+
+        loop {
+            let offset = match self.rom_offset(bank, addr) {
+                Some(offset) => offset,
+                None => return,
+            };
+            if self.kinds[offset] != ByteKind::Data {
+                return;
+            }
+
+            let instr = match self.decode_at(bank as usize, addr) {
+                Some(instr) => instr,
+                None => return,
+            };
+            let length = instr.length as usize;
+            if self.rom_offset(bank, addr + instr.length - 1) != Some(offset + length - 1) {
+                // Runs off the end of the bank
+                return;
+            }
+
+            self.kinds[offset] = ByteKind::CodeStart;
+            for kind in &mut self.kinds[offset + 1..offset + length] {
+                *kind = ByteKind::CodeBody;
+            }
+
+            let opcode = self.cartridge.rom()[offset];
+            known_a = match opcode {
+                0x3e => Some(self.cartridge.rom()[offset + 1]),
+                0xea => {
+                    let rom = self.cartridge.rom();
+                    let dest = ((rom[offset + 2] as u16) << 8) | rom[offset + 1] as u16;
+                    if (0x2000..0x4000).contains(&dest) {
+                        mapped_bank = known_a.map(|a| if a == 0 { 1 } else { a as u16 });
+                    }
+                    known_a
+                }
+                _ => None,
+            };
+
+            if let Some(target) = instr.target {
+                if let Some(target_bank) = self.resolve_bank(bank, target, mapped_bank) {
+                    let name = if instr.flow == Flow::Call { "Call" } else { "Jump" };
+                    let label = BankedAddr::new(target_bank, target);
+                    self.targets.insert(offset, label);
+                    if self.symbols.get(label).is_none() && !INTERRUPT_VECTORS.contains(&target) {
+                        self.labels.entry(label).or_insert_with(|| {
+                            format!("{}_{:03x}_{:04x}", name, target_bank, target)
+                        });
+                    }
+                    queue.push_back((target_bank, target, mapped_bank));
+                }
+            }
+
+            match instr.flow {
+                Flow::Continue | Flow::Branch | Flow::Call => addr += instr.length,
+                Flow::Jump | Flow::Stop => return,
+            }
+        }
+    }
+
+    // Works out which bank a jump from code in bank lands in. Jumps into the
+    // switchable area from bank 0 use the bank the code last mapped, or the
+    // only bank with a symbol there.
+    fn resolve_bank(&self, from_bank: u16, target: u16, mapped_bank: Option<u16>) -> Option<u16> {
+        match target {
+            0x0000...0x3fff => Some(0),
+            0x4000...0x7fff => {
+                let bank_count = self.cartridge.rom_bank_count() as u16;
+                if from_bank != 0 {
+                    Some(from_bank)
+                } else if let Some(bank) = mapped_bank {
+                    if bank < bank_count { Some(bank) } else { None }
+                } else if bank_count <= 2 {
+                    Some(1)
+                } else {
+                    let mut banks = (1..bank_count)
+                        .filter(|b| self.symbols.get(BankedAddr::new(*b, target)).is_some());
+                    match (banks.next(), banks.next()) {
+                        (Some(bank), None) => Some(bank),
+                        _ => None,
+                    }
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn rom_offset(&self, bank: u16, addr: u16) -> Option<usize> {
+        let offset = match addr {
+            0x0000...0x3fff if bank == 0 => addr as usize,
+            0x4000...0x7fff if bank != 0 => bank as usize * BANK_SIZE + (addr as usize - BANK_SIZE),
+            _ => return None,
+        };
+
+        if offset < self.kinds.len() { Some(offset) } else { None }
+    }
+
+    fn cpu_addr(&self, bank: usize, offset: usize) -> u16 {
+        if bank == 0 {
+            offset as u16
+        } else {
+            (BANK_SIZE + offset - bank * BANK_SIZE) as u16
+        }
+    }
+
+    fn decode_at(&self, bank: usize, addr: u16) -> Option<Instruction> {
+        let rom = self.cartridge.rom();
+        let byte = |i: u16| {
+            self.rom_offset(bank as u16, addr.wrapping_add(i)).map_or(0, |o| rom[o])
+        };
+
+        decode([byte(0), byte(1), byte(2)], addr)
+    }
+}
+
+// Decodes the instruction starting with bytes[0] into RGBDS syntax, returning
+// None for the unused opcodes. The opcode is split into the usual x/y/z fields,
+// xxyyyzzz, with y further split into p and q, ppq.
+fn decode(bytes: [u8; 3], addr: u16) -> Option<Instruction> {
+    let opcode = bytes[0];
+    let (x, y, z) = (opcode >> 6, ((opcode >> 3) & 7) as usize, (opcode & 7) as usize);
+    let (p, q) = (y >> 1, y & 1);
+
+    let n8 = bytes[1];
+    let n16 = ((bytes[2] as u16) << 8) | bytes[1] as u16;
+    let e8 = bytes[1] as i8;
+    let jr_target = addr.wrapping_add(2).wrapping_add(e8 as u16);
+
+    let instr = |length: u16, text: String| {
+        Some(Instruction {
+            length: length,
+            text: text,
+            target: None,
+            flow: Flow::Continue,
+        })
+    };
+    let branch = |length: u16, text: String, target: u16, flow: Flow| {
+        Some(Instruction {
+            length: length,
+            text: text,
+            target: Some(target),
+            flow: flow,
+        })
+    };
+    let stop = |text: &str| {
+        Some(Instruction {
+            length: 1,
+            text: text.to_owned(),
+            target: None,
+            flow: Flow::Stop,
+        })
+    };
+    // rgbasm may turn "ld [$ffxx], a" into ldh, so spell out the bytes to
+    // keep the output byte identical to the ROM
+    let raw = |text: String| {
+        format!("db ${:02x}, ${:02x}, ${:02x} ; {}", bytes[0], bytes[1], bytes[2], text)
+    };
+
+    match (x, z) {
+        (0, 0) => {
+            match y {
+                0 => instr(1, "nop".to_owned()),
+                1 => instr(3, format!("ld [${:04x}], sp", n16)),
+                2 if n8 == 0 => instr(2, "stop".to_owned()),
+                2 => instr(2, format!("db $10, ${:02x} ; stop", n8)),
+                3 => branch(2, format!("jr {}", TARGET), jr_target, Flow::Jump),
+                _ => branch(2, format!("jr {}, {}", CONDITIONS[y - 4], TARGET), jr_target, Flow::Branch),
+            }
+        }
+        (0, 1) if q == 0 => instr(3, format!("ld {}, ${:04x}", R16[p], n16)),
+        (0, 1) => instr(1, format!("add hl, {}", R16[p])),
+        (0, 2) => {
+            let mem = ["[bc]", "[de]", "[hl+]", "[hl-]"][p];
+            if q == 0 {
+                instr(1, format!("ld {}, a", mem))
+            } else {
+                instr(1, format!("ld a, {}", mem))
+            }
+        }
+        (0, 3) if q == 0 => instr(1, format!("inc {}", R16[p])),
+        (0, 3) => instr(1, format!("dec {}", R16[p])),
+        (0, 4) => instr(1, format!("inc {}", R8[y])),
+        (0, 5) => instr(1, format!("dec {}", R8[y])),
+        (0, 6) => instr(2, format!("ld {}, ${:02x}", R8[y], n8)),
+        (0, 7) => instr(1, ["rlca", "rrca", "rla", "rra", "daa", "cpl", "scf", "ccf"][y].to_owned()),
+        (1, 6) if y == 6 => instr(1, "halt".to_owned()),
+        (1, _) => instr(1, format!("ld {}, {}", R8[y], R8[z])),
+        (2, _) => instr(1, format!("{} {}", ALU[y], R8[z])),
+        (3, 0) => {
+            match y {
+                0...3 => instr(1, format!("ret {}", CONDITIONS[y])),
+                4 => instr(2, format!("ldh [$ff{:02x}], a", n8)),
+                5 => instr(2, format!("add sp, {}", e8)),
+                6 => instr(2, format!("ldh a, [$ff{:02x}]", n8)),
+                _ if e8 < 0 => instr(2, format!("db $f8, ${:02x} ; ld hl, sp{}", n8, e8)),
+                _ => instr(2, format!("ld hl, sp+{}", e8)),
+            }
+        }
+        (3, 1) if q == 0 => instr(1, format!("pop {}", R16_STACK[p])),
+        (3, 1) => {
+            match p {
+                0 => stop("ret"),
+                1 => stop("reti"),
+                2 => stop("jp hl"),
+                _ => instr(1, "ld sp, hl".to_owned()),
+            }
+        }
+        (3, 2) => {
+            match y {
+                0...3 => branch(3, format!("jp {}, {}", CONDITIONS[y], TARGET), n16, Flow::Branch),
+                4 => instr(1, "ldh [c], a".to_owned()),
+                5 if n16 >= 0xff00 => instr(3, raw(format!("ld [${:04x}], a", n16))),
+                5 => instr(3, format!("ld [${:04x}], a", n16)),
+                6 => instr(1, "ldh a, [c]".to_owned()),
+                _ if n16 >= 0xff00 => instr(3, raw(format!("ld a, [${:04x}]", n16))),
+                _ => instr(3, format!("ld a, [${:04x}]", n16)),
+            }
+        }
+        (3, 3) => {
+            match y {
+                0 => branch(3, format!("jp {}", TARGET), n16, Flow::Jump),
+                1 => decode_cb(n8),
+                6 => instr(1, "di".to_owned()),
+                7 => instr(1, "ei".to_owned()),
+                _ => None,
+            }
+        }
+        (3, 4) if y < 4 => branch(3, format!("call {}, {}", CONDITIONS[y], TARGET), n16, Flow::Call),
+        (3, 5) if q == 0 => instr(1, format!("push {}", R16_STACK[p])),
+        (3, 5) if p == 0 => branch(3, format!("call {}", TARGET), n16, Flow::Call),
+        (3, 6) => instr(2, format!("{} ${:02x}", ALU[y], n8)),
+        (3, 7) => branch(1, format!("rst ${:02x}", y * 8), (y * 8) as u16, Flow::Call),
+        _ => None,
+    }
+}
+
+fn decode_cb(opcode: u8) -> Option<Instruction> {
+    let (x, y, z) = (opcode >> 6, (opcode >> 3) & 7, (opcode & 7) as usize);
+
+    let text = match x {
+        0 => format!("{} {}", ROTATES[y as usize], R8[z]),
+        1 => format!("bit {}, {}", y, R8[z]),
+        2 => format!("res {}, {}", y, R8[z]),
+        _ => format!("set {}, {}", y, R8[z]),
+    };
+
+    Some(Instruction {
+        length: 2,
+        text: text,
+        target: None,
+        flow: Flow::Continue,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An MBC1 ROM of the given number of banks, filled with an unused opcode
+    // so tracing stops wherever the test hasn't put code, with a jump at the
+    // entry point over the header to 0x0150
+    fn test_rom(banks: usize) -> Vec<u8> {
+        let mut rom = vec![0xd3; banks * BANK_SIZE];
+        rom[0x147] = 0x01;
+        rom[0x149] = 0x00;
+        rom[0x100..0x103].copy_from_slice(&[0xc3, 0x50, 0x01]);
+        rom
+    }
+
+    fn disassemble(rom: &[u8], symbols: &Symbols) -> Vec<String> {
+        let cartridge = Cartridge::from_bytes(rom);
+        let mut out = Vec::new();
+        Disassembly::new(&cartridge, symbols).write_rgbds(&mut out).unwrap();
+        String::from_utf8(out).unwrap().lines().map(|l| l.to_owned()).collect()
+    }
+
+    fn contains_lines(lines: &[String], expected: &[&str]) -> bool {
+        lines.windows(expected.len()).any(|w| w.iter().zip(expected).all(|(a, b)| a == b))
+    }
+
+    fn decoded(bytes: [u8; 3]) -> (u16, String) {
+        let instr = decode(bytes, 0x0150).unwrap();
+        (instr.length, instr.text)
+    }
+
+    #[test]
+    fn cb_ops() {
+        assert_eq!(decoded([0xcb, 0x00, 0x00]), (2, "rlc b".to_owned()));
+        assert_eq!(decoded([0xcb, 0x37, 0x00]), (2, "swap a".to_owned()));
+        assert_eq!(decoded([0xcb, 0x7e, 0x00]), (2, "bit 7, [hl]".to_owned()));
+        assert_eq!(decoded([0xcb, 0x91, 0x00]), (2, "res 2, c".to_owned()));
+        assert_eq!(decoded([0xcb, 0xff, 0x00]), (2, "set 7, a".to_owned()));
+    }
+
+    #[test]
+    fn invalid_opcodes() {
+        for opcode in &[0xd3, 0xdb, 0xdd, 0xe3, 0xe4, 0xeb, 0xec, 0xed, 0xf4, 0xfc, 0xfd] {
+            assert!(decode([*opcode, 0x00, 0x00], 0x0150).is_none(), "opcode {:02x}", opcode);
+        }
+    }
+
+    #[test]
+    fn invalid_opcodes_are_data() {
+        let lines = disassemble(&test_rom(2), &Symbols::default());
+
+        assert!(contains_lines(&lines, &["    jp Jump_000_0150", "    ds 68, $d3"]));
+        assert!(contains_lines(&lines, &["Jump_000_0150:", "    ds 16048, $d3"]));
+    }
+
+    #[test]
+    fn jump_and_call_labels() {
+        let mut rom = test_rom(2);
+        // call $0200, jr back to 0x0150, and a ret at 0x0200
+        rom[0x150..0x155].copy_from_slice(&[0xcd, 0x00, 0x02, 0x18, 0xfb]);
+        rom[0x200] = 0xc9;
+
+        let lines = disassemble(&rom, &Symbols::default());
+
+        assert!(lines.contains(&"    jp Jump_000_0150".to_owned()));
+        assert!(contains_lines(&lines, &["Jump_000_0150:", "    call Call_000_0200", "    jr Jump_000_0150"]));
+        assert!(contains_lines(&lines, &["Call_000_0200:", "    ret"]));
+    }
+
+    #[test]
+    fn symbols_replace_labels() {
+        let mut rom = test_rom(2);
+        rom[0x150..0x153].copy_from_slice(&[0xcd, 0x00, 0x02]);
+        rom[0x200] = 0xc9;
+        let mut symbols = Symbols::default();
+        symbols.insert(BankedAddr::new(0, 0x0200), "Sub");
+
+        let lines = disassemble(&rom, &symbols);
+
+        assert!(lines.contains(&"    call Sub".to_owned()));
+        assert!(contains_lines(&lines, &["Sub:", "    ret"]));
+        assert!(!lines.iter().any(|l| l.contains("Call_000_0200")));
+    }
+
+    #[test]
+    fn labels_inside_instructions() {
+        let mut rom = test_rom(2);
+        // ld a, $c9 followed by a jump to its operand, which is a ret
+        rom[0x150..0x155].copy_from_slice(&[0x3e, 0xc9, 0xc3, 0x51, 0x01]);
+
+        let lines = disassemble(&rom, &Symbols::default());
+
+        assert!(contains_lines(&lines, &["    ld a, $c9", "Jump_000_0151 EQU @-1", "    jp Jump_000_0151"]));
+    }
+
+    #[test]
+    fn bank_switching() {
+        let mut rom = test_rom(4);
+        // ld a, 2; ld [$2000], a; call $4000 with a ret at the start of bank 2
+        rom[0x150..0x158].copy_from_slice(&[0x3e, 0x02, 0xea, 0x00, 0x20, 0xcd, 0x00, 0x40]);
+        rom[2 * BANK_SIZE] = 0xc9;
+
+        let lines = disassemble(&rom, &Symbols::default());
+
+        assert!(lines.contains(&"    call Call_002_4000".to_owned()));
+        assert!(contains_lines(&lines, &["SECTION \"ROM Bank $002\", ROMX[$4000], BANK[$2]",
+                                         "",
+                                         "Call_002_4000:",
+                                         "    ret"]));
+    }
+
+    #[test]
+    fn unknown_bank() {
+        let mut rom = test_rom(4);
+        rom[0x150..0x153].copy_from_slice(&[0xcd, 0x00, 0x40]);
+        rom[2 * BANK_SIZE] = 0xc9;
+
+        let lines = disassemble(&rom, &Symbols::default());
+
+        assert!(lines.contains(&"    call $4000".to_owned()));
+        assert!(!lines.iter().any(|l| l.starts_with("Call_")));
+    }
+
+    #[test]
+    fn bank_from_symbols() {
+        let mut rom = test_rom(4);
+        rom[0x150..0x153].copy_from_slice(&[0xcd, 0x00, 0x40]);
+        rom[3 * BANK_SIZE] = 0xc9;
+        let mut symbols = Symbols::default();
+        symbols.insert(BankedAddr::new(3, 0x4000), "Bank3Sub");
+
+        let lines = disassemble(&rom, &symbols);
+
+        assert!(lines.contains(&"    call Bank3Sub".to_owned()));
+        assert!(contains_lines(&lines, &["Bank3Sub:", "    ret"]));
+    }
+}
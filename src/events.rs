@@ -1,5 +1,19 @@
+use input::{Buttons, Hotkey};
+
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum Event {
     Watchpoint,
     Unrecognized0xed,
+    // A CALL, RST or interrupt dispatch jumped to target, pushing return_addr
+    Call { target: u16, return_addr: u16 },
+    // A RET or RETI popped the given address into PC
+    Return(u16),
+    // The preceding Call was the dispatch of an interrupt
+    Interrupt(u16),
+    // A hotkey was pressed this step
+    Hotkey(Hotkey),
+    // The PPU entered VBlank, finishing a frame
+    VBlank,
+    // The gamepad sampled a frame's input
+    InputPolled(Buttons),
 }
@@ -0,0 +1,222 @@
+use std::borrow::Cow;
+use std::str::FromStr;
+
+// Ways of scaling a frame of 0xAARRGGBB pixels up for display
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    Nearest,
+    // Smooths diagonal edges without blurring, see
+    // https://www.scale2x.it/algorithm
+    Scale2x,
+    // Blends each quarter of a pixel with whichever of the three neighbours
+    // towards it look alike, smoothing edges into gradients. A much simpler
+    // take on hq2x, which matches patterns of all eight neighbours.
+    Smooth2x,
+    // Darkens the edges of each pixel, like the gaps on the DMG's LCD
+    LcdGrid,
+    Scanlines,
+}
+
+impl FromStr for Filter {
+    type Err = Cow<'static, str>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "nearest" => Ok(Filter::Nearest),
+            "scale2x" => Ok(Filter::Scale2x),
+            "smooth2x" => Ok(Filter::Smooth2x),
+            "lcd" => Ok(Filter::LcdGrid),
+            "scanlines" => Ok(Filter::Scanlines),
+            _ => Err(format!("Unknown filter {}, expected nearest, scale2x, smooth2x, lcd or scanlines", s).into()),
+        }
+    }
+}
+
+impl Filter {
+    // The smallest scale the filter makes a difference at
+    pub fn min_scale(&self) -> usize {
+        match *self {
+            Filter::Nearest => 1,
+            Filter::Scale2x | Filter::Smooth2x | Filter::LcdGrid | Filter::Scanlines => 2,
+        }
+    }
+
+    // Draws the width by height frame in src into dest, which is scale times
+    // as wide and as high
+    pub fn apply(&self, src: &[u32], width: usize, height: usize, scale: usize, dest: &mut [u32]) {
+        let (dest_width, dest_height) = (width * scale, height * scale);
+
+        match *self {
+            Filter::Nearest => resize(src, width, height, dest, dest_width, dest_height),
+            Filter::Scale2x => {
+                let doubled = scale2x(src, width, height);
+                resize(&doubled, width * 2, height * 2, dest, dest_width, dest_height);
+            }
+            Filter::Smooth2x => {
+                let doubled = smooth2x(src, width, height);
+                resize(&doubled, width * 2, height * 2, dest, dest_width, dest_height);
+            }
+            Filter::LcdGrid | Filter::Scanlines => {
+                resize(src, width, height, dest, dest_width, dest_height);
+                if scale < 2 {
+                    return;
+                }
+
+                for (i, pixel) in dest.iter_mut().enumerate() {
+                    let last_col = (i % dest_width) % scale == scale - 1;
+                    let last_row = (i / dest_width) % scale == scale - 1;
+                    if *self == Filter::LcdGrid && (last_col || last_row) {
+                        *pixel = darken(*pixel, 3, 4);
+                    } else if *self == Filter::Scanlines && last_row {
+                        *pixel = darken(*pixel, 1, 2);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Blends each frame with the one before, like the DMG's slow LCD. Some games
+// rely on this to make things drawn every other frame look transparent.
+pub struct Ghosting {
+    previous: Vec<u32>,
+}
+
+impl Ghosting {
+    pub fn new(len: usize) -> Ghosting {
+        Ghosting { previous: vec![0xffffffff; len] }
+    }
+
+    pub fn blend(&mut self, frame: &[u32], dest: &mut [u32]) {
+        for ((out, previous), pixel) in dest.iter_mut().zip(self.previous.iter_mut()).zip(frame) {
+            *out = 0xff000000 | (((*previous & 0xfefefe) >> 1) + ((*pixel & 0xfefefe) >> 1));
+            *previous = *pixel;
+        }
+    }
+}
+
+fn resize(src: &[u32], width: usize, height: usize, dest: &mut [u32], dest_width: usize, dest_height: usize) {
+    for y in 0..dest_height {
+        let row = &src[(y * height / dest_height) * width..];
+        for x in 0..dest_width {
+            dest[y * dest_width + x] = row[x * width / dest_width];
+        }
+    }
+}
+
+fn scale2x(src: &[u32], width: usize, height: usize) -> Vec<u32> {
+    let mut dest = vec![0; width * height * 4];
+    let at = |x: usize, y: usize| src[y * width + x];
+
+    for y in 0..height {
+        for x in 0..width {
+            let p = at(x, y);
+            let a = if y > 0 { at(x, y - 1) } else { p };
+            let b = if x + 1 < width { at(x + 1, y) } else { p };
+            let c = if x > 0 { at(x - 1, y) } else { p };
+            let d = if y + 1 < height { at(x, y + 1) } else { p };
+
+            let mut out = [p; 4];
+            if c == a && c != d && a != b {
+                out[0] = a;
+            }
+            if a == b && a != c && b != d {
+                out[1] = b;
+            }
+            if d == c && d != b && c != a {
+                out[2] = c;
+            }
+            if b == d && b != a && d != c {
+                out[3] = d;
+            }
+
+            let top = (y * 2) * width * 2 + x * 2;
+            let bottom = top + width * 2;
+            dest[top] = out[0];
+            dest[top + 1] = out[1];
+            dest[bottom] = out[2];
+            dest[bottom + 1] = out[3];
+        }
+    }
+
+    dest
+}
+
+fn smooth2x(src: &[u32], width: usize, height: usize) -> Vec<u32> {
+    let mut dest = vec![0; width * height * 4];
+    let at = |x: usize, y: usize| src[y * width + x];
+
+    for y in 0..height {
+        for x in 0..width {
+            let p = at(x, y);
+            // Each quarter of the output pixel only looks towards its own
+            // corner, so the same rules work for all four once the
+            // neighbours are picked. Off the edge of the frame counts as p.
+            for (i, &(dx, dy)) in [(-1, -1), (1, -1), (-1, 1), (1, 1)].iter().enumerate() {
+                let nx = x as isize + dx;
+                let ny = y as isize + dy;
+                let in_x = nx >= 0 && (nx as usize) < width;
+                let in_y = ny >= 0 && (ny as usize) < height;
+
+                let vertical = if in_y { at(x, ny as usize) } else { p };
+                let horizontal = if in_x { at(nx as usize, y) } else { p };
+                let corner = if in_x && in_y { at(nx as usize, ny as usize) } else { p };
+
+                let out = (y * 2 + i / 2) * width * 2 + x * 2 + i % 2;
+                dest[out] = smooth2x_corner(p, vertical, horizontal, corner);
+            }
+        }
+    }
+
+    dest
+}
+
+// The quarter of pixel p nearest the given neighbours
+fn smooth2x_corner(p: u32, vertical: u32, horizontal: u32, corner: u32) -> u32 {
+    let v = yuv_differ(p, vertical);
+    let h = yuv_differ(p, horizontal);
+    let c = yuv_differ(p, corner);
+
+    match (v, h) {
+        (false, false) => blend(&[(p, 2), (vertical, 1), (horizontal, 1)]),
+        (true, false) if c => blend(&[(p, 3), (horizontal, 1)]),
+        (true, false) => blend(&[(p, 2), (corner, 1), (horizontal, 1)]),
+        (false, true) if c => blend(&[(p, 3), (vertical, 1)]),
+        (false, true) => blend(&[(p, 2), (corner, 1), (vertical, 1)]),
+        // Both sides differ from p but match each other, so an edge cuts
+        // across the corner
+        (true, true) if !yuv_differ(vertical, horizontal) => {
+            blend(&[(p, 2), (vertical, 1), (horizontal, 1)])
+        }
+        (true, true) if c => p,
+        (true, true) => blend(&[(p, 3), (corner, 1)]),
+    }
+}
+
+// Whether two colours are far enough apart in YUV to count as different,
+// with hq2x's thresholds
+fn yuv_differ(a: u32, b: u32) -> bool {
+    let yuv = |pixel: u32| {
+        let (r, g, b) = (((pixel >> 16) & 0xff) as i32, ((pixel >> 8) & 0xff) as i32, (pixel & 0xff) as i32);
+        ((r + g + b) >> 2, 128 + ((r - b) >> 2), 128 + ((2 * g - r - b) >> 3))
+    };
+    let (ya, ua, va) = yuv(a);
+    let (yb, ub, vb) = yuv(b);
+    (ya - yb).abs() > 0x30 || (ua - ub).abs() > 7 || (va - vb).abs() > 6
+}
+
+// The weighted average of the pixels' colour channels
+fn blend(pixels: &[(u32, u32)]) -> u32 {
+    let total: u32 = pixels.iter().map(|&(_, weight)| weight).sum();
+    let channel = |shift: u32| {
+        let sum: u32 = pixels.iter().map(|&(pixel, weight)| ((pixel >> shift) & 0xff) * weight).sum();
+        (sum / total) << shift
+    };
+    0xff000000 | channel(16) | channel(8) | channel(0)
+}
+
+// Scales each colour channel by num / den
+fn darken(pixel: u32, num: u32, den: u32) -> u32 {
+    let channel = |shift: u32| (((pixel >> shift) & 0xff) * num / den) << shift;
+    (pixel & 0xff000000) | channel(16) | channel(8) | channel(0)
+}
@@ -1,45 +1,23 @@
-use device::{Device, Key};
+use device::Device;
+use events::Event;
+use input::{Button, Buttons, Hotkey, KeyBindings, KeyboardInput};
 use interrupt::{Irq, Interrupt};
+use ioregs::print_field;
+use std::io;
+use state::{SaveState, StateReader, StateWriter};
 
-pub struct KeyPad {
-    key_code: Key,
-    pressed: bool,
-}
-
-impl KeyPad {
-    fn new(key_code: Key) -> Self {
-        KeyPad {
-            key_code: key_code,
-            pressed: false,
-        }
-    }
-
-    fn step(&mut self, device: &mut Device, irq: &mut Irq) {
-        let new_pressed = device.key_down(self.key_code);
-        if !self.pressed && new_pressed {
-            irq.raise_interrupt(Interrupt::Gamepad);
-        }
-
-        self.pressed = new_pressed;
-    }
-
-    fn pressed(&self) -> bool {
-        self.pressed
-    }
-}
+const CYCLES_PER_FRAME: u32 = 70224;
+const CYCLES_PER_LINE: u32 = 456;
 
 pub struct Gamepad {
     p15: bool,
     p14: bool,
 
-    up: KeyPad,
-    down: KeyPad,
-    left: KeyPad,
-    right: KeyPad,
-    a: KeyPad,
-    b: KeyPad,
-    start: KeyPad,
-    select: KeyPad,
+    keyboard: KeyboardInput,
+    pressed: Buttons,
+
+    frame_cycles: u32,
+    interrupt_pending: bool,
 }
 
 impl Gamepad {
@@ -48,66 +26,128 @@ impl Gamepad {
             p15: false,
             p14: false,
 
-            // TODO - allow for configuration
-            up: KeyPad::new(Key::Up),
-            down: KeyPad::new(Key::Down),
-            left: KeyPad::new(Key::Left),
-            right: KeyPad::new(Key::Right),
-            a: KeyPad::new(Key::Z),
-            b: KeyPad::new(Key::X),
-            start: KeyPad::new(Key::Enter),
-            select: KeyPad::new(Key::Backspace),
+            keyboard: KeyboardInput::new(KeyBindings::default()),
+            pressed: Buttons::default(),
+
+            frame_cycles: 0,
+            interrupt_pending: false,
         }
     }
 
-    pub fn step(&mut self, _: u16, device: &mut Device, irq: &mut Irq) {
-        self.up.step(device, irq);
-        self.down.step(device, irq);
-        self.left.step(device, irq);
-        self.right.step(device, irq);
-        self.a.step(device, irq);
-        self.b.step(device, irq);
-        self.start.step(device, irq);
-        self.select.step(device, irq);
+    pub fn set_bindings(&mut self, bindings: KeyBindings) {
+        self.keyboard = KeyboardInput::new(bindings);
     }
 
-    pub fn read_reg(&self) -> u8 {
-        let mut ret = 0xc0;
+    // Input is sampled at the start of every VBlank, or once a frame's worth
+    // of cycles while the LCD is off, so that the same inputs always land at
+    // the same point in emulation and never change mid-frame
+    pub fn step(&mut self, cycles: u16, device: &mut Device, irq: &mut Irq, events: &mut Vec<Event>) {
+        // The fallback waits an extra line so it never beats a VBlank that's due
+        self.frame_cycles += cycles as u32;
+        if irq.is_raised(Interrupt::VBlank) || self.frame_cycles >= CYCLES_PER_FRAME + CYCLES_PER_LINE {
+            self.frame_cycles = 0;
 
-        if !self.p15 {
-            if !self.a.pressed() {
-                ret |= 1;
-            }
-            if !self.b.pressed() {
-                ret |= 1 << 1;
-            }
-            if !self.select.pressed() {
-                ret |= 1 << 2;
-            }
-            if !self.start.pressed() {
-                ret |= 1 << 3;
+            let pressed = match device.buttons() {
+                Some(buttons) => buttons,
+                None => self.keyboard.buttons(device),
+            };
+            for hotkey in self.keyboard.new_hotkeys(device) {
+                events.push(Event::Hotkey(hotkey));
             }
+
+            self.update_lines(|gamepad| gamepad.pressed = pressed);
+            events.push(Event::InputPolled(pressed));
         }
-        if !self.p14 {
-            if !self.right.pressed() {
-                ret |= 1;
-            }
-            if !self.left.pressed() {
-                ret |= 1 << 1;
-            }
-            if !self.up.pressed() {
-                ret |= 1 << 2;
-            }
-            if !self.down.pressed() {
-                ret |= 1 << 3;
-            }
+
+        if self.interrupt_pending {
+            irq.raise_interrupt(Interrupt::Gamepad);
+            self.interrupt_pending = false;
+        }
+    }
+
+    pub fn poll_hotkeys(&mut self, device: &Device) -> Vec<Hotkey> {
+        self.keyboard.new_hotkeys(device)
+    }
+
+    pub fn read_reg(&self) -> u8 {
+        let mut ret = 0xc0 | self.lines();
+
+        if self.p15 {
+            ret |= 1 << 5;
+        }
+        if self.p14 {
+            ret |= 1 << 4;
         }
 
         ret
     }
 
     pub fn write_reg(&mut self, val: u8) {
-        self.p15 = val & (1 << 5) != 0;
-        self.p14 = val & (1 << 4) != 0;
+        self.update_lines(|gamepad| {
+            gamepad.p15 = val & (1 << 5) != 0;
+            gamepad.p14 = val & (1 << 4) != 0;
+        });
+    }
+
+    pub fn print_registers(&self) {
+        let selected = |line: bool| if line { "no" } else { "yes" };
+        println!("P1: {:02x}", self.read_reg());
+        print_field("5", "Buttons selected", selected(self.p15));
+        print_field("4", "D-pad selected", selected(self.p14));
+        print_field("3-0", "Input lines", &format!("{:04b}", self.lines()));
+
+        let buttons = [Button::A, Button::B, Button::Select, Button::Start,
+                       Button::Right, Button::Left, Button::Up, Button::Down];
+        let pressed: Vec<String> = buttons.iter()
+            .filter(|b| self.pressed.contains(**b))
+            .map(|b| format!("{:?}", b))
+            .collect();
+        println!("Pressed: {}", if pressed.is_empty() { "none".to_owned() } else { pressed.join(" ") });
+    }
+
+    // The P10-P13 input lines, low for a pressed button on either selected
+    // line. Both button groups are wired to the same lines so selecting both
+    // combines them.
+    fn lines(&self) -> u8 {
+        let mut pressed = 0;
+
+        if !self.p15 {
+            pressed |= self.pressed.0 >> 4;
+        }
+        if !self.p14 {
+            pressed |= self.pressed.0 & 0x0f;
+        }
+
+        !pressed & 0x0f
+    }
+
+    // The joypad interrupt fires when any input line goes from high to low,
+    // whether from a button press or from selecting a line with a button
+    // already held
+    fn update_lines<F: FnOnce(&mut Self)>(&mut self, update: F) {
+        let before = self.lines();
+        update(self);
+        if before & !self.lines() != 0 {
+            self.interrupt_pending = true;
+        }
+    }
+}
+
+impl SaveState for Gamepad {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.p15);
+        state.bool(self.p14);
+        state.u8(self.pressed.0);
+        state.u32(self.frame_cycles);
+        state.bool(self.interrupt_pending);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.p15 = state.bool()?;
+        self.p14 = state.bool()?;
+        self.pressed = Buttons(state.u8()?);
+        self.frame_cycles = state.u32()?;
+        self.interrupt_pending = state.bool()?;
+        Ok(())
     }
 }
@@ -0,0 +1,313 @@
+extern crate app_dirs;
+
+use std::fs::File;
+use std::io::{self, BufReader, BufRead};
+use std::path::Path;
+use config::app_info::APP_INFO;
+use device::{Device, Key};
+use self::app_dirs::{AppDataType, get_app_root};
+
+const CONFIG_FILE: &str = "input.ini";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Button {
+    A,
+    B,
+    Start,
+    Select,
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl Button {
+    // The bit for the button in a mask laid out like the P1 register, with the
+    // d-pad in the low nibble and the buttons in the high one
+    pub fn mask(&self) -> u8 {
+        match *self {
+            Button::Right => 0x01,
+            Button::Left => 0x02,
+            Button::Up => 0x04,
+            Button::Down => 0x08,
+            Button::A => 0x10,
+            Button::B => 0x20,
+            Button::Select => 0x40,
+            Button::Start => 0x80,
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Button> {
+        match name {
+            "a" => Some(Button::A),
+            "b" => Some(Button::B),
+            "start" => Some(Button::Start),
+            "select" => Some(Button::Select),
+            "up" => Some(Button::Up),
+            "down" => Some(Button::Down),
+            "left" => Some(Button::Left),
+            "right" => Some(Button::Right),
+            _ => None,
+        }
+    }
+}
+
+// A set of pressed buttons, using the bits from Button::mask
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Buttons(pub u8);
+
+impl Buttons {
+    pub fn contains(&self, button: Button) -> bool {
+        self.0 & button.mask() != 0
+    }
+
+    pub fn insert(&mut self, button: Button) {
+        self.0 |= button.mask();
+    }
+
+    pub fn remove(&mut self, button: Button) {
+        self.0 &= !button.mask();
+    }
+}
+
+// Emulator controls that aren't passed on to the game
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Hotkey {
+    SaveState,
+    LoadState,
+    Pause,
+    FastForward,
+    SlowMotion,
+    FrameAdvance,
+    Screenshot,
+    Record,
+}
+
+impl Hotkey {
+    pub fn mask(&self) -> u8 {
+        match *self {
+            Hotkey::SaveState => 0x01,
+            Hotkey::LoadState => 0x02,
+            Hotkey::Pause => 0x04,
+            Hotkey::FastForward => 0x08,
+            Hotkey::SlowMotion => 0x10,
+            Hotkey::FrameAdvance => 0x20,
+            Hotkey::Screenshot => 0x40,
+            Hotkey::Record => 0x80,
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Hotkey> {
+        match name {
+            "save_state" => Some(Hotkey::SaveState),
+            "load_state" => Some(Hotkey::LoadState),
+            "pause" => Some(Hotkey::Pause),
+            "fast_forward" => Some(Hotkey::FastForward),
+            "slow_motion" => Some(Hotkey::SlowMotion),
+            "frame_advance" => Some(Hotkey::FrameAdvance),
+            "screenshot" => Some(Hotkey::Screenshot),
+            "record" => Some(Hotkey::Record),
+            _ => None,
+        }
+    }
+}
+
+// Maps host keys onto Game Boy buttons and hotkeys. Loaded from an INI file:
+//
+//   [buttons]
+//   a = Z, Pad0
+//   start = Enter
+//
+//   [turbo]
+//   a = A
+//   rate = 4
+//
+//   [hotkeys]
+//   fast_forward = Tab
+#[derive(Clone, Debug)]
+pub struct KeyBindings {
+    pub buttons: Vec<(Button, Key)>,
+    pub turbo: Vec<(Button, Key)>,
+    pub hotkeys: Vec<(Hotkey, Key)>,
+    // Turbo buttons alternate between pressed and released every this many
+    // frames
+    pub turbo_rate: u32,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        KeyBindings {
+            buttons: vec![(Button::Up, Key::Up),
+                          (Button::Down, Key::Down),
+                          (Button::Left, Key::Left),
+                          (Button::Right, Key::Right),
+                          (Button::A, Key::Z),
+                          (Button::B, Key::X),
+                          (Button::Start, Key::Enter),
+                          (Button::Select, Key::Backspace)],
+            turbo: vec![(Button::A, Key::A), (Button::B, Key::S)],
+            hotkeys: vec![(Hotkey::SaveState, Key::F5),
+                          (Hotkey::LoadState, Key::F7),
+                          (Hotkey::Pause, Key::P),
+                          (Hotkey::FastForward, Key::Tab),
+                          (Hotkey::SlowMotion, Key::Minus),
+                          (Hotkey::FrameAdvance, Key::N),
+                          (Hotkey::Screenshot, Key::F12),
+                          (Hotkey::Record, Key::F9)],
+            turbo_rate: 2,
+        }
+    }
+}
+
+impl KeyBindings {
+    // Sections left out of the file keep their default bindings
+    pub fn load<P: AsRef<Path>>(file_name: P) -> io::Result<KeyBindings> {
+        let file = File::open(file_name)?;
+        let defaults = KeyBindings::default();
+        let mut bindings = KeyBindings {
+            buttons: Vec::new(),
+            turbo: Vec::new(),
+            hotkeys: Vec::new(),
+            turbo_rate: defaults.turbo_rate,
+        };
+        let mut seen_sections = Vec::new();
+        let mut section = String::new();
+
+        for line in BufReader::new(&file).lines() {
+            let line = line?;
+            let line = line.split(&[';', '#'][..]).next().unwrap().trim();
+
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                section = line[1..line.len() - 1].trim().to_lowercase();
+                seen_sections.push(section.clone());
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=');
+            let (name, value) = match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => (name.trim().to_lowercase(), value.trim()),
+                _ => return Err(invalid_line(line)),
+            };
+
+            if section == "turbo" && name == "rate" {
+                bindings.turbo_rate = match value.parse() {
+                    Ok(rate) if rate > 0 => rate,
+                    _ => return Err(invalid_line(line)),
+                };
+                continue;
+            }
+
+            let mut keys = Vec::new();
+            for key in value.split(',').map(|k| k.trim()).filter(|k| !k.is_empty()) {
+                match key.parse::<Key>() {
+                    Ok(key) => keys.push(key),
+                    Err(_) => return Err(invalid_line(line)),
+                }
+            }
+
+            match (section.as_ref(), Button::from_name(&name), Hotkey::from_name(&name)) {
+                ("buttons", Some(button), _) => bindings.buttons.extend(keys.iter().map(|k| (button, *k))),
+                ("turbo", Some(button), _) => bindings.turbo.extend(keys.iter().map(|k| (button, *k))),
+                ("hotkeys", _, Some(hotkey)) => bindings.hotkeys.extend(keys.iter().map(|k| (hotkey, *k))),
+                _ => return Err(invalid_line(line)),
+            }
+        }
+
+        let seen = |s: &str| seen_sections.iter().any(|x| x == s);
+        if !seen("buttons") {
+            bindings.buttons = defaults.buttons;
+        }
+        if !seen("turbo") {
+            bindings.turbo = defaults.turbo;
+        }
+        if !seen("hotkeys") {
+            bindings.hotkeys = defaults.hotkeys;
+        }
+
+        Ok(bindings)
+    }
+
+    // Loads input.ini from the user's config directory, falling back to the
+    // default bindings if there isn't one
+    pub fn discover() -> io::Result<KeyBindings> {
+        if let Ok(dir) = get_app_root(AppDataType::UserConfig, &APP_INFO) {
+            let path = dir.join(CONFIG_FILE);
+            if path.is_file() {
+                println!("Loading key bindings from {}", path.to_string_lossy());
+                return Self::load(path);
+            }
+        }
+
+        Ok(Self::default())
+    }
+}
+
+static HOTKEYS: [Hotkey; 8] = [Hotkey::SaveState,
+                               Hotkey::LoadState,
+                               Hotkey::Pause,
+                               Hotkey::FastForward,
+                               Hotkey::SlowMotion,
+                               Hotkey::FrameAdvance,
+                               Hotkey::Screenshot,
+                               Hotkey::Record];
+
+// Adapts a device that only reports host keys to Game Boy buttons and
+// hotkeys, using the key bindings. Expected to be polled once a frame.
+pub struct KeyboardInput {
+    bindings: KeyBindings,
+    frame: u32,
+    hotkeys: u8,
+}
+
+impl KeyboardInput {
+    pub fn new(bindings: KeyBindings) -> Self {
+        KeyboardInput {
+            bindings: bindings,
+            frame: 0,
+            hotkeys: 0,
+        }
+    }
+
+    pub fn buttons(&mut self, device: &Device) -> Buttons {
+        let turbo_on = (self.frame / self.bindings.turbo_rate) & 1 == 0;
+        self.frame = self.frame.wrapping_add(1);
+
+        let mut buttons = Buttons::default();
+        for &(button, key) in &self.bindings.buttons {
+            if device.key_down(key) {
+                buttons.insert(button);
+            }
+        }
+        if turbo_on {
+            for &(button, key) in &self.bindings.turbo {
+                if device.key_down(key) {
+                    buttons.insert(button);
+                }
+            }
+        }
+
+        buttons
+    }
+
+    // Returns the hotkeys pressed since the last call
+    pub fn new_hotkeys(&mut self, device: &Device) -> Vec<Hotkey> {
+        let mut hotkeys = 0;
+        for &(hotkey, key) in &self.bindings.hotkeys {
+            if device.key_down(key) {
+                hotkeys |= hotkey.mask();
+            }
+        }
+
+        let pressed = HOTKEYS.iter().cloned().filter(|h| hotkeys & !self.hotkeys & h.mask() != 0).collect();
+        self.hotkeys = hotkeys;
+        pressed
+    }
+}
+
+fn invalid_line(line: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   format!("Unrecognised input config line: {}", line))
+}
@@ -1,4 +1,5 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::io;
 
 use mem_map::*;
 use bootrom::Bootrom;
@@ -9,8 +10,16 @@ use device::Device;
 use apu::Apu;
 use timer::Timer;
 use gamepad::Gamepad;
-use interrupt::Irq;
+use interrupt::{Interrupt, Irq};
 use events::Event;
+use symbols::BankedAddr;
+use cheats::Cheats;
+use input::{Hotkey, KeyBindings};
+use ioregs::{REGISTER_GROUPS, RegisterGroup, on_off, print_field};
+use palette::Palette;
+use pixel::Pixel;
+use viewer::VideoState;
+use state::{SaveState, StateReader, StateWriter, invalid_state};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DmaState {
@@ -42,6 +51,8 @@ pub struct Interconnect {
 
     trigger_watchpoint: bool,
     pub watchpoints: HashSet<u16>,
+    frozen: HashMap<BankedAddr, u8>,
+    pub cheats: Cheats,
 
     pub dma_source: u16,
     pub dma_slot: u8,
@@ -71,6 +82,8 @@ impl Interconnect {
 
             watchpoints: HashSet::new(),
             trigger_watchpoint: false,
+            frozen: HashMap::new(),
+            cheats: Cheats::default(),
 
             dma_source: 0,
             dma_slot: 0,
@@ -109,7 +122,15 @@ impl Interconnect {
         if self.watchpoints.contains(&addr) {
             self.trigger_watchpoint = true;
         }
+        if !self.frozen.is_empty() && self.frozen.contains_key(&self.banked_addr(unecho(addr))) {
+            return;
+        }
+
+        self.inner_write_byte(addr, val);
+    }
 
+    #[cfg_attr(feature = "cargo-clippy", allow(match_same_arms, match_overlapping_arm))]
+    fn inner_write_byte(&mut self, addr: u16, val: u8) {
         match addr {
             ROM_START...ROM_END => self.cartridge.write(addr - ROM_START, val),
             VRAM_START...VRAM_END => self.ppu.write_vram(addr - VRAM_START, val),
@@ -179,10 +200,15 @@ impl Interconnect {
         self.apu.step(cycles, device, &mut irq);
         self.ppu.step(cycles, device, &mut irq);
         self.timer.step(cycles, device, &mut irq);
-        self.gamepad.step(cycles, device, &mut irq);
+        self.gamepad.step(cycles, device, &mut irq, events);
 
         self.if_register |= irq.get_if();
 
+        if irq.is_raised(Interrupt::VBlank) {
+            self.apply_ram_cheats();
+            events.push(Event::VBlank);
+        }
+
         if self.trigger_watchpoint {
             events.push(Event::Watchpoint);
             self.trigger_watchpoint = false;
@@ -197,14 +223,161 @@ impl Interconnect {
         self.ppu.get_height()
     }
 
+    pub fn frame_buffer(&self) -> &[u32] {
+        self.ppu.frame_buffer()
+    }
+
+    pub fn frame_pixels(&self) -> &[Pixel] {
+        self.ppu.frame_pixels()
+    }
+
+    pub fn video_state(&self) -> VideoState<'_> {
+        VideoState {
+            vram: self.ppu.vram(),
+            oam: self.ppu.oam(),
+            lcdc: self.ppu.read_reg(0xff40),
+            scy: self.ppu.read_reg(0xff42),
+            scx: self.ppu.read_reg(0xff43),
+            wy: self.ppu.read_reg(0xff4a),
+            wx: self.ppu.read_reg(0xff4b),
+            bgp: self.ppu.read_reg(0xff47),
+            obp0: self.ppu.read_reg(0xff48),
+            obp1: self.ppu.read_reg(0xff49),
+            palette: self.ppu.palette(),
+        }
+    }
+
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.ppu.set_palette(palette);
+    }
+
+    pub fn audio_output(&self) -> (i16, i16) {
+        self.apu.output()
+    }
+
     pub fn get_timer(&self) -> &Timer {
         &self.timer
     }
 
+    // Prints the registers of one part of the hardware, or all of them
+    pub fn print_io_registers(&self, group: Option<RegisterGroup>) {
+        let groups = match group {
+            Some(group) => vec![group],
+            None => REGISTER_GROUPS.to_vec(),
+        };
+
+        for group in groups {
+            println!("{}:", group.title());
+            match group {
+                RegisterGroup::Timer => self.timer.print_registers(),
+                RegisterGroup::Interrupts => {
+                    // From bit 0 up
+                    let names = ["VBlank", "STAT", "Timer", "Serial", "Joypad"];
+                    let pending = |reg: u8| {
+                        let set: Vec<&str> = names.iter().enumerate()
+                            .filter(|&(i, _)| reg & (1 << i) != 0)
+                            .map(|(_, name)| *name)
+                            .collect();
+                        if set.is_empty() { "none".to_owned() } else { set.join(" ") }
+                    };
+                    println!("IE: {:02x} ({})", self.ie_register, pending(self.ie_register));
+                    println!("IF: {:02x} ({})", self.if_register, pending(self.if_register));
+                }
+                RegisterGroup::Dma => {
+                    println!("Source: {:04x}, State: {:?}, Slot: {:02x}", self.dma_source, self.dma_state, self.dma_slot);
+                }
+                RegisterGroup::Ppu => self.ppu.print_registers(),
+                RegisterGroup::Apu => self.apu.print_registers(),
+                RegisterGroup::Joypad => self.gamepad.print_registers(),
+                RegisterGroup::Serial => {
+                    // The link cable isn't emulated, so these are the values
+                    // last written rather than what reads give back
+                    println!("SB: {:02x}", self.serial_transfer_data);
+                    println!("SC: {:02x}", self.serial_control);
+                    print_field("7", "Transfer", on_off(self.serial_control & 0x80 != 0));
+                    print_field("0", "Clock", if self.serial_control & 0x01 != 0 { "internal" } else { "external" });
+                }
+                RegisterGroup::Cartridge => {
+                    println!("Boot ROM: {}", if self.boot_rom_active { "mapped" } else { "unmapped" });
+                    self.cartridge.print_registers();
+                }
+            }
+        }
+    }
+
+    // Returns the bank currently visible at addr, areas without banking are
+    // always bank 0
+    pub fn bank_at(&self, addr: u16) -> u16 {
+        match addr {
+            BOOT_ROM_START...BOOT_ROM_END if self.boot_rom_active => 0,
+            ROM_START...ROM_END | CRAM_START...CRAM_END => self.cartridge.bank_at(addr - ROM_START),
+            _ => 0,
+        }
+    }
+
+    pub fn banked_addr(&self, addr: u16) -> BankedAddr {
+        BankedAddr::new(self.bank_at(addr), addr)
+    }
+
+    pub fn set_key_bindings(&mut self, bindings: KeyBindings) {
+        self.gamepad.set_bindings(bindings);
+    }
+
+    // Returns the hotkeys pressed since they were last checked, for polling
+    // them while the CPU isn't being stepped
+    pub fn poll_hotkeys(&mut self, device: &Device) -> Vec<Hotkey> {
+        self.gamepad.poll_hotkeys(device)
+    }
+
+    pub fn cart_ram_size(&self) -> u16 {
+        self.cartridge.mapped_ram_size()
+    }
+
+    pub fn cart_ram_mut(&mut self) -> &mut [u8] {
+        self.cartridge.ram_mut()
+    }
+
+    pub fn work_ram_mut(&mut self) -> &mut [u8] {
+        self.internal_ram.data_mut()
+    }
+
+    // Writes val to addr and then ignores any further writes there, so that
+    // the game can't change it
+    pub fn freeze(&mut self, addr: BankedAddr, val: u8) {
+        let addr = BankedAddr::new(addr.bank, unecho(addr.addr));
+        self.frozen.remove(&addr);
+        if self.bank_at(addr.addr) == addr.bank {
+            self.write_byte(addr.addr, val);
+        }
+        self.frozen.insert(addr, val);
+    }
+
+    pub fn thaw(&mut self, addr: BankedAddr) -> bool {
+        self.frozen.remove(&BankedAddr::new(addr.bank, unecho(addr.addr))).is_some()
+    }
+
+    pub fn frozen(&self) -> &HashMap<BankedAddr, u8> {
+        &self.frozen
+    }
+
+    // GameShark codes write straight to memory once a frame, skipping any
+    // watchpoints or frozen values the debugger has set up
+    fn apply_ram_cheats(&mut self) {
+        for (bank, addr, val) in self.cheats.ram_pokes() {
+            let cart_ram_bank = bank & 0xf0 == 0x80 && (CRAM_START..=CRAM_END).contains(&addr);
+            if cart_ram_bank && self.bank_at(addr) != (bank & 0x0f) as u16 {
+                continue;
+            }
+            self.inner_write_byte(addr, val);
+        }
+    }
+
     fn inner_read_byte(&self, addr: u16) -> u8 {
         match addr {
             BOOT_ROM_START...BOOT_ROM_END if self.boot_rom_active => self.boot_rom.read_byte(addr - BOOT_ROM_START),
-            ROM_START...ROM_END => self.cartridge.read_byte(addr - ROM_START),
+            ROM_START...ROM_END => {
+                self.cheats.patch_rom_read(addr, self.cartridge.read_byte(addr - ROM_START))
+            }
             VRAM_START...VRAM_END => self.ppu.read_vram(addr - VRAM_START),
             CRAM_START...CRAM_END => self.cartridge.read_byte(addr - ROM_START),
             INTERNAL_RAM_START...INTERNAL_RAM_END => {
@@ -236,3 +409,75 @@ impl Interconnect {
         }
     }
 }
+
+// Maps echo RAM addresses onto the internal RAM they mirror
+fn unecho(addr: u16) -> u16 {
+    match addr {
+        IRAM_ECHO_START...IRAM_ECHO_END => addr - IRAM_ECHO_START + INTERNAL_RAM_START,
+        _ => addr,
+    }
+}
+
+// Watchpoints, frozen addresses and cheats belong to the user rather than the
+// machine so they're left out of save states
+impl SaveState for Interconnect {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.cartridge.save_state(state);
+        self.ppu.save_state(state);
+        self.apu.save_state(state);
+        self.timer.save_state(state);
+        self.gamepad.save_state(state);
+
+        state.bool(self.boot_rom_active);
+        self.internal_ram.save_state(state);
+        self.high_ram.save_state(state);
+        state.u8(self.if_register);
+        state.u8(self.ie_register);
+        state.u8(self.serial_transfer_data);
+        state.u8(self.serial_control);
+
+        let (dma_state, dma_index) = match self.dma_state {
+            DmaState::Inactive => (0, 0),
+            DmaState::Setup1 => (1, 0),
+            DmaState::Setup2 => (2, 0),
+            DmaState::Reset1 => (3, 0),
+            DmaState::Reset2 => (4, 0),
+            DmaState::Active(index) => (5, index),
+        };
+        state.u16(self.dma_source);
+        state.u8(self.dma_slot);
+        state.u8(dma_state);
+        state.u16(dma_index);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.cartridge.load_state(state)?;
+        self.ppu.load_state(state)?;
+        self.apu.load_state(state)?;
+        self.timer.load_state(state)?;
+        self.gamepad.load_state(state)?;
+
+        self.boot_rom_active = state.bool()?;
+        self.internal_ram.load_state(state)?;
+        self.high_ram.load_state(state)?;
+        self.if_register = state.u8()?;
+        self.ie_register = state.u8()?;
+        self.serial_transfer_data = state.u8()?;
+        self.serial_control = state.u8()?;
+
+        self.dma_source = state.u16()?;
+        self.dma_slot = state.u8()?;
+        let dma_state = state.u8()?;
+        let dma_index = state.u16()?;
+        self.dma_state = match dma_state {
+            0 => DmaState::Inactive,
+            1 => DmaState::Setup1,
+            2 => DmaState::Setup2,
+            3 => DmaState::Reset1,
+            4 => DmaState::Reset2,
+            5 => DmaState::Active(dma_index),
+            _ => return Err(invalid_state("invalid OAM DMA state")),
+        };
+        Ok(())
+    }
+}
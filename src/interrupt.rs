@@ -6,6 +6,18 @@ pub enum Interrupt {
     Gamepad
 }
 
+impl Interrupt {
+    fn mask(&self) -> u8 {
+        match *self {
+            Interrupt::VBlank => 0x01,
+            Interrupt::Stat => 0x02,
+            Interrupt::Timer => 0x04,
+            Interrupt::SerialIO => 0x08,
+            Interrupt::Gamepad => 0x10,
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct Irq {
     iflags: u8,
@@ -13,13 +25,11 @@ pub struct Irq {
 
 impl Irq {
     pub fn raise_interrupt(&mut self, int: Interrupt) {
-        self.iflags |= match int {
-            Interrupt::VBlank => 0x01,
-            Interrupt::Stat => 0x02,
-            Interrupt::Timer => 0x04,
-            Interrupt::SerialIO => 0x08,
-            Interrupt::Gamepad => 0x10,
-        }
+        self.iflags |= int.mask();
+    }
+
+    pub fn is_raised(&self, int: Interrupt) -> bool {
+        self.iflags & int.mask() != 0
     }
 
     pub fn get_if(&self) -> u8 {
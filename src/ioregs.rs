@@ -0,0 +1,62 @@
+// The hardware the debugger's showioregs command can print the registers of
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegisterGroup {
+    Timer,
+    Interrupts,
+    Dma,
+    Ppu,
+    Apu,
+    Joypad,
+    Serial,
+    Cartridge,
+}
+
+pub const REGISTER_GROUPS: [RegisterGroup; 8] = [RegisterGroup::Timer,
+                                                  RegisterGroup::Interrupts,
+                                                  RegisterGroup::Dma,
+                                                  RegisterGroup::Ppu,
+                                                  RegisterGroup::Apu,
+                                                  RegisterGroup::Joypad,
+                                                  RegisterGroup::Serial,
+                                                  RegisterGroup::Cartridge];
+
+// Names a group can be given in commands, longer names first so that a
+// prefix doesn't match before them
+pub const GROUP_NAMES: [(&str, RegisterGroup); 14] = [("timer", RegisterGroup::Timer),
+                                                      ("interrupts", RegisterGroup::Interrupts),
+                                                      ("int", RegisterGroup::Interrupts),
+                                                      ("dma", RegisterGroup::Dma),
+                                                      ("ppu", RegisterGroup::Ppu),
+                                                      ("lcd", RegisterGroup::Ppu),
+                                                      ("apu", RegisterGroup::Apu),
+                                                      ("sound", RegisterGroup::Apu),
+                                                      ("joypad", RegisterGroup::Joypad),
+                                                      ("p1", RegisterGroup::Joypad),
+                                                      ("serial", RegisterGroup::Serial),
+                                                      ("cartridge", RegisterGroup::Cartridge),
+                                                      ("cart", RegisterGroup::Cartridge),
+                                                      ("mbc", RegisterGroup::Cartridge)];
+
+impl RegisterGroup {
+    pub fn title(&self) -> &'static str {
+        match *self {
+            RegisterGroup::Timer => "Timer",
+            RegisterGroup::Interrupts => "Interrupts",
+            RegisterGroup::Dma => "OAM DMA",
+            RegisterGroup::Ppu => "PPU",
+            RegisterGroup::Apu => "APU",
+            RegisterGroup::Joypad => "Joypad",
+            RegisterGroup::Serial => "Serial",
+            RegisterGroup::Cartridge => "Cartridge",
+        }
+    }
+}
+
+// Prints one decoded field of a register, bits being e.g. "7" or "1-0"
+pub fn print_field(bits: &str, name: &str, value: &str) {
+    println!("  {:>3} {:<20} {}", bits, name, value);
+}
+
+pub fn on_off(on: bool) -> &'static str {
+    if on { "on" } else { "off" }
+}
@@ -10,6 +10,18 @@ pub mod cpu;
 pub mod interconnect;
 pub mod device;
 pub mod symbols;
+pub mod disassembler;
+pub mod cheats;
+pub mod input;
+pub mod movie;
+pub mod screenshot;
+pub mod rom_files;
+pub mod palette;
+pub mod pixel;
+pub mod filter;
+pub mod viewer;
+pub mod recorder;
+pub mod audio;
 
 mod mem_map;
 mod memory;
@@ -18,9 +30,15 @@ mod apu;
 mod timer;
 mod opcodes;
 mod command;
+mod ioregs;
 mod gamepad;
 mod interrupt;
 mod events;
+mod profiler;
+mod search;
+mod patch;
+mod archive;
+mod state;
 
 use std::path::Path;
 use self::vm::VM;
@@ -28,13 +46,33 @@ use self::bootrom::Bootrom;
 use self::cartridge::Cartridge;
 use self::interconnect::Interconnect;
 use self::symbols::Symbols;
+use self::cheats::Cheats;
+use self::input::KeyBindings;
+use self::movie::{Movie, MovieHeader};
+use self::rom_files::RomFiles;
+use self::screenshot::Screenshots;
+use self::palette::{Palette, PaletteConfig};
+use self::config::model::Model;
 
 #[derive(Default)]
 pub struct Gameboy {
     cartridge: Option<String>,
+    archive_entry: Option<String>,
+    patch: Option<String>,
     boot_rom: Option<String>,
     symbols: Option<String>,
+    profile_report: Option<String>,
+    profile_stacks: Option<String>,
+    cheats: Vec<String>,
+    input_config: Option<String>,
+    speed: Option<String>,
+    record_movie: Option<String>,
+    play_movie: Option<String>,
+    screenshot_scale: Option<String>,
+    palette: Option<String>,
+    record_video: Option<String>,
     start_in_debug: bool,
+    without_console: bool,
 }
 
 impl Gameboy {
@@ -47,6 +85,22 @@ impl Gameboy {
         self
     }
 
+    pub fn with_archive_entry(mut self, entry: Option<&str>) -> Self {
+        self.archive_entry = match entry {
+            Some(s) => Some(s.to_owned()),
+            None => None,
+        };
+        self
+    }
+
+    pub fn with_patch(mut self, patch: Option<&str>) -> Self {
+        self.patch = match patch {
+            Some(s) => Some(s.to_owned()),
+            None => None,
+        };
+        self
+    }
+
     pub fn with_boot_rom(mut self, boot_rom: Option<&str>) -> Self {
         self.boot_rom = match boot_rom {
             Some(s) => Some(s.to_owned()),
@@ -63,27 +117,214 @@ impl Gameboy {
         self
     }
 
+    pub fn with_profile_report(mut self, report: Option<&str>) -> Self {
+        self.profile_report = match report {
+            Some(s) => Some(s.to_owned()),
+            None => None,
+        };
+        self
+    }
+
+    pub fn with_profile_stacks(mut self, stacks: Option<&str>) -> Self {
+        self.profile_stacks = match stacks {
+            Some(s) => Some(s.to_owned()),
+            None => None,
+        };
+        self
+    }
+
+    pub fn with_cheats(mut self, codes: Option<Vec<&str>>) -> Self {
+        self.cheats = match codes {
+            Some(c) => c.iter().map(|s| (*s).to_owned()).collect(),
+            None => Vec::new(),
+        };
+        self
+    }
+
+    pub fn with_input_config(mut self, config: Option<&str>) -> Self {
+        self.input_config = match config {
+            Some(s) => Some(s.to_owned()),
+            None => None,
+        };
+        self
+    }
+
+    pub fn with_speed(mut self, speed: Option<&str>) -> Self {
+        self.speed = match speed {
+            Some(s) => Some(s.to_owned()),
+            None => None,
+        };
+        self
+    }
+
+    pub fn with_record_movie(mut self, movie: Option<&str>) -> Self {
+        self.record_movie = match movie {
+            Some(s) => Some(s.to_owned()),
+            None => None,
+        };
+        self
+    }
+
+    pub fn with_play_movie(mut self, movie: Option<&str>) -> Self {
+        self.play_movie = match movie {
+            Some(s) => Some(s.to_owned()),
+            None => None,
+        };
+        self
+    }
+
+    pub fn with_screenshot_scale(mut self, scale: Option<&str>) -> Self {
+        self.screenshot_scale = match scale {
+            Some(s) => Some(s.to_owned()),
+            None => None,
+        };
+        self
+    }
+
+    pub fn with_palette(mut self, palette: Option<&str>) -> Self {
+        self.palette = match palette {
+            Some(s) => Some(s.to_owned()),
+            None => None,
+        };
+        self
+    }
+
+    pub fn with_record_video(mut self, video_file: Option<&str>) -> Self {
+        self.record_video = match video_file {
+            Some(s) => Some(s.to_owned()),
+            None => None,
+        };
+        self
+    }
+
     pub fn start_in_debug(mut self, sid: bool) -> Self {
         self.start_in_debug = sid;
         self
     }
 
+    // Leaves out the stdin debugger console, for hosting the emulator in
+    // another frontend
+    pub fn without_console(mut self, wc: bool) -> Self {
+        self.without_console = wc;
+        self
+    }
+
     pub fn build(self) -> vm::VM {
         let input_file = self.cartridge.unwrap();
         let boot_rom_file = self.boot_rom.unwrap();
-        let cartridge = Cartridge::load(Path::new(&input_file)).unwrap();
+        let cartridge = Cartridge::load_with(Path::new(&input_file),
+                                            self.archive_entry.as_ref().map(|s| s.as_str()),
+                                            self.patch.as_ref().map(Path::new))
+            .unwrap_or_else(|e| panic!("Couldn't load {}: {}", input_file, e));
         let start_in_debug = self.start_in_debug;
 
-        let boot_rom = Bootrom::load(Path::new(&boot_rom_file)).unwrap();
+        let boot_rom = Bootrom::load(Path::new(&boot_rom_file))
+            .unwrap_or_else(|e| panic!("Couldn't load {}: {}", boot_rom_file, e));
 
         let symbols = if let Some(sym_file) = self.symbols {
-            Symbols::load(sym_file).unwrap()
+            Symbols::load(sym_file)
         } else {
-            Symbols::default()
+            Symbols::discover(&input_file)
+        };
+        let symbols = match symbols {
+            Ok(symbols) => symbols,
+            Err(e) => {
+                // The defaults have no file, so the broken one isn't overwritten
+                println!("Warning: couldn't load symbols, starting without any ({})", e);
+                Symbols::default()
+            }
+        };
+
+        let mut cheats = match Cheats::for_rom(&input_file) {
+            Ok(cheats) => cheats,
+            Err(e) => {
+                // The defaults have no file, so the broken one isn't overwritten
+                println!("Warning: couldn't load cheats, starting without any ({})", e);
+                Cheats::default()
+            }
+        };
+        for code in &self.cheats {
+            if let Err(e) = cheats.add(code, "") {
+                println!("{}", e);
+            }
+        }
+
+        let model = Path::new(&boot_rom_file).file_name()
+            .and_then(|name| Model::from_bootrom_name(&name.to_string_lossy()));
+        let movie_header = MovieHeader::new(model, boot_rom.data(), cartridge.rom());
+
+        let palettes = match PaletteConfig::discover() {
+            Ok(palettes) => palettes,
+            Err(e) => {
+                println!("Warning: couldn't load palettes, using the built in ones ({})", e);
+                PaletteConfig::default()
+            }
+        };
+        let palette = match palettes.palette_for(self.palette.as_ref().map(|s| s.as_str()), cartridge.rom()) {
+            Ok(palette) => palette,
+            Err(e) => {
+                println!("Warning: {}, using the default palette", e);
+                Palette::default()
+            }
         };
 
-        let interconnect = Interconnect::new(boot_rom, cartridge);
+        let mut interconnect = Interconnect::new(boot_rom, cartridge);
+        interconnect.cheats = cheats;
+        interconnect.set_palette(palette);
+
+        let bindings = if let Some(config) = self.input_config {
+            KeyBindings::load(config)
+        } else {
+            KeyBindings::discover()
+        };
+        let bindings = match bindings {
+            Ok(bindings) => bindings,
+            Err(e) => {
+                println!("Warning: couldn't load key bindings, using the defaults ({})", e);
+                KeyBindings::default()
+            }
+        };
+        interconnect.set_key_bindings(bindings);
+
+        let mut vm = if self.without_console {
+            VM::without_console(interconnect, symbols)
+        } else {
+            VM::new(interconnect, start_in_debug, symbols)
+        };
+
+        if self.profile_report.is_some() || self.profile_stacks.is_some() {
+            vm.enable_profiling(self.profile_report.as_ref().map(Path::new),
+                                self.profile_stacks.as_ref().map(Path::new));
+        }
+
+        if let Some(speed) = self.speed {
+            vm.set_speed(speed.parse().unwrap());
+        }
+
+        let mut screenshots = Screenshots::for_rom(&input_file);
+        if let Some(scale) = self.screenshot_scale {
+            screenshots.scale = scale.parse().unwrap();
+        }
+        vm.set_screenshots(screenshots);
+        vm.set_rom_files(RomFiles::for_rom(&input_file));
+
+        if let Some(movie_file) = self.play_movie {
+            let movie = Movie::load(&movie_file).unwrap();
+            if movie.header != movie_header {
+                println!("Warning: movie {} was recorded with a different ROM, boot ROM or model",
+                         movie_file);
+            }
+            println!("Playing {} frame movie {}", movie.len(), movie_file);
+            vm.play_movie(movie).unwrap();
+        } else if let Some(movie_file) = self.record_movie {
+            vm.record_movie(Movie::new(movie_header.clone()), Path::new(&movie_file));
+        }
+        vm.set_movie_header(movie_header);
+
+        if let Some(video_file) = self.record_video {
+            vm.start_recording(&video_file).unwrap();
+        }
 
-        VM::new(interconnect, start_in_debug, symbols)
+        vm
     }
 }
@@ -1,3 +1,6 @@
+use std::io;
+use state::{SaveState, StateReader, StateWriter};
+
 pub struct Memory {
     mem: Box<[u8]>,
 }
@@ -14,4 +17,18 @@ impl Memory {
     pub fn write_byte(&mut self, addr: u16, val: u8) {
         self.mem[addr as usize] = val;
     }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.mem
+    }
+}
+
+impl SaveState for Memory {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.mem);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        state.bytes(&mut self.mem)
+    }
 }
@@ -0,0 +1,257 @@
+extern crate crc;
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use config::model::{Model, DEFAULT_MODEL_PRIORITY};
+use device::{Device, Key};
+use input::Buttons;
+use self::crc::crc32;
+
+const MAGIC: &str = "scimitar-movie 1";
+
+// Recordings store a hash of the emulator state every this many frames, so
+// playback can tell when it's drifted from the original run. Must be a power
+// of two.
+const HASH_INTERVAL: usize = 64;
+
+// The machine the movie was recorded on. Movies recorded from part way
+// through a session also embed a save state to start from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MovieHeader {
+    pub model: Option<Model>,
+    pub boot_rom_crc: u32,
+    pub rom_crc: u32,
+}
+
+impl MovieHeader {
+    pub fn new(model: Option<Model>, boot_rom: &[u8], rom: &[u8]) -> Self {
+        MovieHeader {
+            model: model,
+            boot_rom_crc: crc32::checksum_ieee(boot_rom),
+            rom_crc: crc32::checksum_ieee(rom),
+        }
+    }
+}
+
+// The joypad state for every frame of a run. Movie files are text:
+//
+//   scimitar-movie 1
+//   start power-on
+//   model DMG
+//   boot_rom_crc 59c8598e
+//   rom_crc 7e5f8c2b
+//   frames
+//   00
+//   10 8c1a2f3e
+//
+// with one line per frame holding the pressed buttons as a Buttons mask, and
+// every HASH_INTERVAL frames the state hash from the recording. Movies that
+// don't start at power-on have "start state" followed by the save state in
+// hex instead.
+pub struct Movie {
+    pub header: MovieHeader,
+    // The save state to load before playing, None to start at power-on
+    pub start_state: Option<Vec<u8>>,
+    frames: Vec<(Buttons, Option<u32>)>,
+}
+
+impl Movie {
+    pub fn new(header: MovieHeader) -> Self {
+        Movie {
+            header: header,
+            start_state: None,
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(file_name: P) -> io::Result<Movie> {
+        let file = File::open(file_name)?;
+        let mut lines = BufReader::new(&file).lines();
+
+        match lines.next() {
+            Some(Ok(ref line)) if line.trim() == MAGIC => {}
+            Some(Err(e)) => return Err(e),
+            _ => return Err(invalid_movie("not a movie file")),
+        }
+
+        let mut model = None;
+        let mut start_state = None;
+        let mut boot_rom_crc = None;
+        let mut rom_crc = None;
+
+        for line in &mut lines {
+            let line = line?;
+            let line = line.trim();
+            if line == "frames" {
+                break;
+            }
+
+            let mut parts = line.splitn(2, ' ');
+            match (parts.next(), parts.next()) {
+                (Some("start"), Some("power-on")) => start_state = None,
+                (Some("start"), Some(start)) if start.starts_with("state ") => {
+                    start_state = Some(parse_hex_bytes(start["state ".len()..].trim())?)
+                }
+                (Some("model"), Some("unknown")) => model = None,
+                (Some("model"), Some(name)) => {
+                    model = match DEFAULT_MODEL_PRIORITY.iter().find(|m| m.to_string() == name) {
+                        Some(m) => Some(*m),
+                        None => return Err(invalid_movie(&format!("unrecognised model {}", name))),
+                    }
+                }
+                (Some("boot_rom_crc"), Some(crc)) => boot_rom_crc = Some(parse_hex(crc)?),
+                (Some("rom_crc"), Some(crc)) => rom_crc = Some(parse_hex(crc)?),
+                _ => return Err(invalid_movie(&format!("unrecognised line {}", line))),
+            }
+        }
+
+        let header = match (boot_rom_crc, rom_crc) {
+            (Some(boot_rom_crc), Some(rom_crc)) => {
+                MovieHeader {
+                    model: model,
+                    boot_rom_crc: boot_rom_crc,
+                    rom_crc: rom_crc,
+                }
+            }
+            _ => return Err(invalid_movie("header is missing a ROM CRC")),
+        };
+
+        let mut frames = Vec::new();
+        for line in lines {
+            let line = line?;
+            let mut parts = line.split_whitespace();
+            let buttons = match parts.next() {
+                Some(b) => {
+                    match parse_hex(b)? {
+                        buttons if buttons <= 0xff => Buttons(buttons as u8),
+                        _ => return Err(invalid_movie(&format!("invalid buttons {}", b))),
+                    }
+                }
+                None => continue,
+            };
+            let hash = match parts.next() {
+                Some(h) => Some(parse_hex(h)?),
+                None => None,
+            };
+            frames.push((buttons, hash));
+        }
+
+        Ok(Movie {
+            header: header,
+            start_state: start_state,
+            frames: frames,
+        })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, file_name: P) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(file_name)?);
+
+        writeln!(file, "{}", MAGIC)?;
+        match self.start_state {
+            Some(ref state) => {
+                write!(file, "start state ")?;
+                for byte in state {
+                    write!(file, "{:02x}", byte)?;
+                }
+                writeln!(file)?;
+            }
+            None => writeln!(file, "start power-on")?,
+        }
+        match self.header.model {
+            Some(model) => writeln!(file, "model {}", model)?,
+            None => writeln!(file, "model unknown")?,
+        }
+        writeln!(file, "boot_rom_crc {:08x}", self.header.boot_rom_crc)?;
+        writeln!(file, "rom_crc {:08x}", self.header.rom_crc)?;
+        writeln!(file, "frames")?;
+
+        for &(buttons, hash) in &self.frames {
+            match hash {
+                Some(hash) => writeln!(file, "{:02x} {:08x}", buttons.0, hash)?,
+                None => writeln!(file, "{:02x}", buttons.0)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn frame(&self, index: usize) -> Option<Buttons> {
+        self.frames.get(index).map(|&(buttons, _)| buttons)
+    }
+
+    pub fn hash(&self, index: usize) -> Option<u32> {
+        self.frames.get(index).and_then(|&(_, hash)| hash)
+    }
+
+    // Whether the frame at index stores a state hash
+    pub fn is_hashed_frame(index: usize) -> bool {
+        index & (HASH_INTERVAL - 1) == 0
+    }
+
+    pub fn push(&mut self, buttons: Buttons, hash: Option<u32>) {
+        self.frames.push((buttons, hash));
+    }
+}
+
+// A movie being recorded or played back by the VM
+pub enum MovieState {
+    Recording { movie: Movie, path: PathBuf },
+    Playing { movie: Movie, frame: usize, desynced: bool },
+}
+
+// Wraps the frontend's device during playback, reporting the movie's buttons
+// instead of the player's. Keys still reach the hotkeys.
+pub struct PlaybackDevice<'a, 'b> {
+    pub device: &'a mut Device,
+    pub movie: &'b Movie,
+    pub frame: usize,
+}
+
+impl<'a, 'b> Device for PlaybackDevice<'a, 'b> {
+    fn update(&mut self) {
+        self.device.update();
+    }
+
+    fn set_frame_buffer(&mut self, buffer: &[u32]) {
+        self.device.set_frame_buffer(buffer);
+    }
+
+    fn key_down(&self, key: Key) -> bool {
+        self.device.key_down(key)
+    }
+
+    fn buttons(&mut self) -> Option<Buttons> {
+        self.movie.frame(self.frame)
+    }
+
+    fn running(&self) -> bool {
+        self.device.running()
+    }
+}
+
+fn parse_hex(s: &str) -> io::Result<u32> {
+    u32::from_str_radix(s, 16).map_err(|_| invalid_movie(&format!("invalid number {}", s)))
+}
+
+fn parse_hex_bytes(s: &str) -> io::Result<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        return Err(invalid_movie("invalid start state"));
+    }
+
+    (0..s.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| invalid_movie("invalid start state")))
+        .collect()
+}
+
+fn invalid_movie(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid movie: {}", reason))
+}
@@ -580,7 +580,7 @@ pub fn decode_instr(interconnect: &Interconnect, symbols: &Symbols, addr: u16) -
         .wrapping_add(opcode_spec.opcode_length as u16);
     let opcode_target_addr = ((n1 as u16) << 8) | (n0 as u16);
 
-    let sym = symbols.get(opcode_target_addr).unwrap_or("").to_owned();
+    let sym = symbols.describe(interconnect.banked_addr(opcode_target_addr)).unwrap_or_default();
 
     if opcode_spec.opcode_disasm == "" {
         panic!("No opcode string for op {0}/{0:02x} ({1}/{1:02x})",
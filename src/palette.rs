@@ -0,0 +1,403 @@
+extern crate app_dirs;
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, BufReader, BufRead};
+use std::path::Path;
+use std::str::FromStr;
+use config::app_info::APP_INFO;
+use pixel::{Layer, Pixel};
+use self::app_dirs::{AppDataType, get_app_root};
+
+const CONFIG_FILE: &str = "palette.ini";
+
+const TITLE_OFFSET: usize = 0x0134;
+const TITLE_LENGTH: usize = 16;
+const NEW_LICENSEE_OFFSET: usize = 0x0144;
+const OLD_LICENSEE_OFFSET: usize = 0x014b;
+
+const GREEN: [u32; 4] = [0xff7e8429, 0xff527a4b, 0xff315d4b, 0xff29473e];
+const GRAYSCALE: [u32; 4] = [0xffffffff, 0xffaaaaaa, 0xff555555, 0xff000000];
+const POCKET: [u32; 4] = [0xffc4cfa1, 0xff8b956d, 0xff4d533c, 0xff1f1f1f];
+
+// The tables the CGB boot ROM colourises DMG games with. Games are looked up
+// by the sum of the bytes of their title. The checksums from
+// CGB_UNIQUE_CHECKSUMS on are shared by several games, which are told apart
+// by the fourth letter of the title: CGB_LETTERS holds a row of letters for
+// those checksums, then another row for the next game with each checksum, and
+// so on.
+const CGB_UNIQUE_CHECKSUMS: usize = 65;
+
+static CGB_CHECKSUMS: [u8; 79] = [
+    0x00, 0x88, 0x16, 0x36, 0xd1, 0xdb, 0xf2, 0x3c, 0x8c, 0x92, 0x3d, 0x5c, 0x58, 0xc9, 0x3e, 0x70,
+    0x1d, 0x59, 0x69, 0x19, 0x35, 0xa8, 0x14, 0xaa, 0x75, 0x95, 0x99, 0x34, 0x6f, 0x15, 0xff, 0x97,
+    0x4b, 0x90, 0x17, 0x10, 0x39, 0xf7, 0xf6, 0xa2, 0x49, 0x4e, 0x43, 0x68, 0xe0, 0x8b, 0xf0, 0xce,
+    0x0c, 0x29, 0xe8, 0xb7, 0x86, 0x9a, 0x52, 0x01, 0x9d, 0x71, 0x9c, 0xbd, 0x5d, 0x6d, 0x67, 0x3f,
+    0x6b,
+    // Shared checksums
+    0xb3, 0x46, 0x28, 0xa5, 0xc6, 0xd3, 0x27, 0x61, 0x18, 0x66, 0x6a, 0xbf, 0x0d, 0xf4,
+];
+
+static CGB_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+// The index in CGB_COMBINATIONS for each unique checksum, followed by one for
+// each letter in CGB_LETTERS
+static CGB_GAME_COMBINATIONS: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44,
+    21, 32, 31, 20, 5, 33, 13, 14, 5, 29, 5, 18, 9, 3, 2, 26,
+    25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34,
+    5, 42, 6, 5, 33, 25, 42, 42, 40, 2, 4, 34, 42, 42, 5, 0,
+    39,
+    // By letter
+    36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50,
+    17, 46, 6, 27, 0, 47, 41, 41, 0, 0, 19, 34, 23, 18,
+    29,
+];
+
+// Where the OBJ0, OBJ1 and background colours of each combination start in
+// CGB_COLOURS. Most start at one of its palettes of four colours, a few part
+// way through one.
+static CGB_COMBINATIONS: [(usize, usize, usize); 51] = [
+    (4 * 4, 4 * 4, 29 * 4),
+    (18 * 4, 18 * 4, 18 * 4),
+    (20 * 4, 20 * 4, 20 * 4),
+    (24 * 4, 24 * 4, 24 * 4),
+    (9 * 4, 9 * 4, 9 * 4),
+    (0, 0, 0),
+    (27 * 4, 27 * 4, 27 * 4),
+    (5 * 4, 5 * 4, 5 * 4),
+    (12 * 4, 12 * 4, 12 * 4),
+    (26 * 4, 26 * 4, 26 * 4),
+    (16 * 4, 8 * 4, 8 * 4),
+    (4 * 4, 28 * 4, 28 * 4),
+    (4 * 4, 2 * 4, 2 * 4),
+    (3 * 4, 4 * 4, 4 * 4),
+    (4 * 4, 29 * 4, 29 * 4),
+    (28 * 4, 4 * 4, 28 * 4),
+    (2 * 4, 17 * 4, 2 * 4),
+    (16 * 4, 16 * 4, 8 * 4),
+    (4 * 4, 4 * 4, 7 * 4),
+    (4 * 4, 4 * 4, 18 * 4),
+    (4 * 4, 4 * 4, 20 * 4),
+    (19 * 4, 19 * 4, 9 * 4),
+    (4 * 4 - 1, 4 * 4 - 1, 11 * 4),
+    (17 * 4, 17 * 4, 2 * 4),
+    (4 * 4, 4 * 4, 2 * 4),
+    (4 * 4, 4 * 4, 3 * 4),
+    (28 * 4, 28 * 4, 0),
+    (3 * 4, 3 * 4, 0),
+    (0, 0, 4),
+    (18 * 4, 22 * 4, 18 * 4),
+    (20 * 4, 22 * 4, 20 * 4),
+    (24 * 4, 22 * 4, 24 * 4),
+    (16 * 4, 22 * 4, 8 * 4),
+    (17 * 4, 4 * 4, 13 * 4),
+    (28 * 4 - 1, 0, 14 * 4),
+    (28 * 4 - 1, 4 * 4, 15 * 4),
+    (19 * 4, 22 * 4, 9 * 4),
+    (16 * 4, 28 * 4, 10 * 4),
+    (4 * 4, 23 * 4, 28 * 4),
+    (17 * 4, 22 * 4, 2 * 4),
+    (4 * 4, 0, 2 * 4),
+    (4 * 4, 28 * 4, 3 * 4),
+    (28 * 4, 3 * 4, 0),
+    (3 * 4, 28 * 4, 4 * 4),
+    (21 * 4, 28 * 4, 4 * 4),
+    (3 * 4, 28 * 4, 0),
+    (25 * 4, 3 * 4, 28 * 4),
+    (0, 28 * 4, 8 * 4),
+    (4 * 4, 3 * 4, 28 * 4),
+    (28 * 4, 3 * 4, 6 * 4),
+    (4 * 4, 28 * 4, 29 * 4),
+];
+
+// The CGB's 15 bit BGR colours, four to a palette from lightest to darkest
+static CGB_COLOURS: [u16; 120] = [
+    0x7fff, 0x32bf, 0x00d0, 0x0000,
+    0x639f, 0x4279, 0x15b0, 0x04cb,
+    0x7fff, 0x6e31, 0x454a, 0x0000,
+    0x7fff, 0x1bef, 0x0200, 0x0000,
+    0x7fff, 0x421f, 0x1cf2, 0x0000,
+    0x7fff, 0x5294, 0x294a, 0x0000,
+    0x7fff, 0x03ff, 0x012f, 0x0000,
+    0x7fff, 0x03ef, 0x01d6, 0x0000,
+    0x7fff, 0x42b5, 0x3dc8, 0x0000,
+    0x7e74, 0x03ff, 0x0180, 0x0000,
+    0x67ff, 0x77ac, 0x1a13, 0x2d6b,
+    0x7ed6, 0x4bff, 0x2175, 0x0000,
+    0x53ff, 0x4a5f, 0x7e52, 0x0000,
+    0x4fff, 0x7ed2, 0x3a4c, 0x1ce0,
+    0x03ed, 0x7fff, 0x255f, 0x0000,
+    0x036a, 0x021f, 0x03ff, 0x7fff,
+    0x7fff, 0x01df, 0x0112, 0x0000,
+    0x231f, 0x035f, 0x00f2, 0x0009,
+    0x7fff, 0x03ea, 0x011f, 0x0000,
+    0x299f, 0x001a, 0x000c, 0x0000,
+    0x7fff, 0x027f, 0x001f, 0x0000,
+    0x7fff, 0x03e0, 0x0206, 0x0120,
+    0x7fff, 0x7eeb, 0x001f, 0x7c00,
+    0x7fff, 0x3fff, 0x7e00, 0x001f,
+    0x7fff, 0x03ff, 0x001f, 0x0000,
+    0x03ff, 0x001f, 0x000c, 0x0000,
+    0x7fff, 0x033f, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037f, 0x7fff,
+    0x7fff, 0x7e8c, 0x7c00, 0x0000,
+    0x7fff, 0x1bef, 0x6180, 0x0000,
+];
+
+// The 0xAARRGGBB colours the four shades are drawn in. The background and the
+// two sprite palettes can each have their own colours, which is how the CGB
+// colourises DMG games.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Palette {
+    pub bg: [u32; 4],
+    pub obj0: [u32; 4],
+    pub obj1: [u32; 4],
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::uniform(GREEN)
+    }
+}
+
+impl FromStr for Palette {
+    type Err = Cow<'static, str>;
+
+    // Either green, grayscale or pocket, or a list of RRGGBB colours from
+    // lightest to darkest. Four colours are used for everything, twelve give
+    // the background, OBJ0 and OBJ1 their own.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "green" => return Ok(Palette::uniform(GREEN)),
+            "grayscale" | "greyscale" => return Ok(Palette::uniform(GRAYSCALE)),
+            "pocket" => return Ok(Palette::uniform(POCKET)),
+            _ => {}
+        }
+
+        let mut colours = Vec::new();
+        for colour in s.split(',').map(|c| c.trim().trim_start_matches('#')) {
+            match u32::from_str_radix(colour, 16) {
+                Ok(rgb) if colour.len() == 6 => colours.push(0xff000000 | rgb),
+                _ => return Err(format!("Invalid palette colour {}, expected RRGGBB", colour).into()),
+            }
+        }
+
+        let layer = |n: usize| [colours[n], colours[n + 1], colours[n + 2], colours[n + 3]];
+        match colours.len() {
+            4 => Ok(Palette::uniform(layer(0))),
+            12 => {
+                Ok(Palette {
+                    bg: layer(0),
+                    obj0: layer(4),
+                    obj1: layer(8),
+                })
+            }
+            _ => Err(format!("Invalid palette {}, expected a name, 4 colours or 12", s).into()),
+        }
+    }
+}
+
+impl Palette {
+    pub fn uniform(colours: [u32; 4]) -> Palette {
+        Palette {
+            bg: colours,
+            obj0: colours,
+            obj1: colours,
+        }
+    }
+
+    pub fn colour(&self, pixel: Pixel) -> u32 {
+        let colours = match pixel.layer {
+            Layer::Background | Layer::Window => &self.bg,
+            Layer::Obj0 => &self.obj0,
+            Layer::Obj1 => &self.obj1,
+        };
+        colours[pixel.shade as usize & 0x03]
+    }
+
+    // Converts pixels from the PPU to 0xAARRGGBB colours in dest
+    pub fn to_rgba(&self, pixels: &[Pixel], dest: &mut [u32]) {
+        for (colour, pixel) in dest.iter_mut().zip(pixels) {
+            *colour = self.colour(*pixel);
+        }
+    }
+
+    // Picks the palette a CGB would show a DMG game in. Only games published
+    // by Nintendo are looked up, everything else gets the default.
+    pub fn colourise(rom: &[u8]) -> Palette {
+        if rom.len() <= OLD_LICENSEE_OFFSET {
+            return Palette::cgb_combination(0);
+        }
+
+        let licensee = rom[OLD_LICENSEE_OFFSET];
+        let nintendo = licensee == 0x01 ||
+                       (licensee == 0x33 && &rom[NEW_LICENSEE_OFFSET..NEW_LICENSEE_OFFSET + 2] == b"01");
+        if !nintendo {
+            return Palette::cgb_combination(0);
+        }
+
+        let title = &rom[TITLE_OFFSET..TITLE_OFFSET + TITLE_LENGTH];
+        let checksum = title.iter().fold(0u8, |sum, c| sum.wrapping_add(*c));
+        let game = match CGB_CHECKSUMS.iter().position(|&sum| sum == checksum) {
+            Some(index) if index < CGB_UNIQUE_CHECKSUMS => Some(index),
+            Some(index) => {
+                let shared = CGB_CHECKSUMS.len() - CGB_UNIQUE_CHECKSUMS;
+                (index - CGB_UNIQUE_CHECKSUMS..CGB_LETTERS.len())
+                    .step_by(shared)
+                    .find(|&letter| CGB_LETTERS[letter] == title[3])
+                    .map(|letter| CGB_UNIQUE_CHECKSUMS + letter)
+            }
+            None => None,
+        };
+
+        let combination = game.map_or(0, |game| CGB_GAME_COMBINATIONS[game] as usize);
+        Palette::cgb_combination(combination)
+    }
+
+    fn cgb_combination(index: usize) -> Palette {
+        let (obj0, obj1, bg) = CGB_COMBINATIONS[index];
+        let colours = |start: usize| {
+            let mut colours = [0; 4];
+            for (colour, bgr) in colours.iter_mut().zip(&CGB_COLOURS[start..start + 4]) {
+                *colour = bgr555_to_rgba(*bgr);
+            }
+            colours
+        };
+
+        Palette {
+            bg: colours(bg),
+            obj0: colours(obj0),
+            obj1: colours(obj1),
+        }
+    }
+}
+
+// Scales each 5 bit channel up to 8 bits, repeating the top bits in the
+// bottom so that white stays white
+fn bgr555_to_rgba(bgr: u16) -> u32 {
+    let channel = |shift: u16| {
+        let c = ((bgr >> shift) & 0x1f) as u32;
+        (c << 3) | (c >> 2)
+    };
+    0xff000000 | (channel(0) << 16) | (channel(5) << 8) | channel(10)
+}
+
+// Palettes chosen in palette.ini, which looks like:
+//
+//   [palettes]
+//   default = pocket
+//   gold = ffffff, ffd700, 806000, 000000
+//
+//   [games]
+//   TETRIS = auto
+//   SUPER MARIOLAND = gold
+//
+// Palettes are given by name, as colours, or as auto for the colours a CGB
+// would use. Games are matched on the title in the cartridge header.
+#[derive(Clone, Debug, Default)]
+pub struct PaletteConfig {
+    palettes: Vec<(String, String)>,
+    games: Vec<(String, String)>,
+}
+
+impl PaletteConfig {
+    pub fn load<P: AsRef<Path>>(file_name: P) -> io::Result<PaletteConfig> {
+        let file = File::open(file_name)?;
+        let mut config = PaletteConfig::default();
+        let mut section = String::new();
+
+        for line in BufReader::new(&file).lines() {
+            let line = line?;
+            let line = line.split(&[';', '#'][..]).next().unwrap().trim();
+
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                section = line[1..line.len() - 1].trim().to_lowercase();
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=');
+            let (name, value) = match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => (name.trim().to_owned(), value.trim().to_owned()),
+                _ => return Err(invalid_line(line)),
+            };
+
+            match section.as_ref() {
+                "palettes" => config.palettes.push((name.to_lowercase(), value)),
+                "games" => config.games.push((name, value)),
+                _ => return Err(invalid_line(line)),
+            }
+        }
+
+        Ok(config)
+    }
+
+    // Loads palette.ini from the user's config directory, if there is one
+    pub fn discover() -> io::Result<PaletteConfig> {
+        if let Ok(dir) = get_app_root(AppDataType::UserConfig, &APP_INFO) {
+            let path = dir.join(CONFIG_FILE);
+            if path.is_file() {
+                println!("Loading palettes from {}", path.to_string_lossy());
+                return Self::load(path);
+            }
+        }
+
+        Ok(Self::default())
+    }
+
+    // The palette to draw a ROM in. A palette given by name overrides the
+    // game's entry in the config, which overrides the config's default.
+    pub fn palette_for(&self, name: Option<&str>, rom: &[u8]) -> Result<Palette, Cow<'static, str>> {
+        let title = title(rom);
+        let game = self.games.iter()
+            .find(|game| game.0.eq_ignore_ascii_case(&title))
+            .map(|game| game.1.as_str());
+
+        match name.or(game).or_else(|| self.named("default")) {
+            Some(name) => self.resolve(name, rom),
+            None => Ok(Palette::default()),
+        }
+    }
+
+    fn named(&self, name: &str) -> Option<&str> {
+        self.palettes.iter()
+            .find(|palette| palette.0 == name)
+            .map(|palette| palette.1.as_str())
+    }
+
+    fn resolve(&self, name: &str, rom: &[u8]) -> Result<Palette, Cow<'static, str>> {
+        // Custom palettes can be defined in terms of each other, up to a point
+        let mut name = name;
+        for _ in 0..8 {
+            if name.eq_ignore_ascii_case("auto") {
+                return Ok(Palette::colourise(rom));
+            }
+            match self.named(&name.to_lowercase()) {
+                Some(value) => name = value,
+                None => return name.parse(),
+            }
+        }
+
+        Err(format!("Palette {} refers to itself", name).into())
+    }
+}
+
+// The title from the cartridge header
+fn title(rom: &[u8]) -> String {
+    rom.iter()
+        .skip(TITLE_OFFSET)
+        .take(TITLE_LENGTH)
+        .take_while(|c| **c != 0)
+        .map(|c| *c as char)
+        .collect::<String>()
+        .trim()
+        .to_owned()
+}
+
+fn invalid_line(line: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   format!("Unrecognised palette config line: {}", line))
+}
@@ -0,0 +1,460 @@
+extern crate byteorder;
+extern crate crc;
+
+use std::io;
+use self::byteorder::{BigEndian, ByteOrder, LittleEndian};
+use self::crc::crc32;
+
+// The largest Game Boy cartridges, MBC5 with 512 banks, are 8MB. Patches
+// claiming to make anything bigger are corrupt.
+const MAX_ROM_SIZE: usize = 8 * 1024 * 1024;
+
+// Applies an IPS, BPS or UPS patch to a ROM, picking the format from the
+// patch's magic number
+pub fn apply(rom: &[u8], patch: &[u8]) -> io::Result<Vec<u8>> {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(b"BPS1") {
+        apply_bps(rom, patch)
+    } else if patch.starts_with(b"UPS1") {
+        apply_ups(rom, patch)
+    } else {
+        Err(invalid_patch("unrecognised patch format"))
+    }
+}
+
+// IPS patches are a list of (offset, data) records, or run length encoded
+// records when the size is zero, ending with "EOF" and an optional size to
+// truncate the ROM to
+fn apply_ips(rom: &[u8], patch: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = rom.to_vec();
+    let mut reader = PatchReader::new(&patch[5..]);
+
+    loop {
+        let record = reader.bytes(3)?;
+        if record == b"EOF" {
+            break;
+        }
+        let offset = BigEndian::read_uint(record, 3) as usize;
+        let size = BigEndian::read_u16(reader.bytes(2)?) as usize;
+
+        let data = if size == 0 {
+            let run = BigEndian::read_u16(reader.bytes(2)?) as usize;
+            vec![reader.byte()?; run]
+        } else {
+            reader.bytes(size)?.to_vec()
+        };
+
+        if out.len() < offset + data.len() {
+            out.resize(offset + data.len(), 0);
+        }
+        out[offset..offset + data.len()].copy_from_slice(&data);
+    }
+
+    if let Ok(truncate) = reader.bytes(3) {
+        out.truncate(BigEndian::read_uint(truncate, 3) as usize);
+    }
+
+    Ok(out)
+}
+
+// BPS patches build the target from a list of commands that copy runs from the
+// source, the patch or earlier in the target
+fn apply_bps(rom: &[u8], patch: &[u8]) -> io::Result<Vec<u8>> {
+    let (source_crc, target_crc) = check_footer(patch)?;
+    if crc32::checksum_ieee(rom) != source_crc {
+        return Err(invalid_patch("BPS patch is for a different ROM"));
+    }
+
+    let mut reader = PatchReader::new(&patch[4..patch.len() - 12]);
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    let metadata_size = reader.varint()?;
+    reader.bytes(metadata_size)?;
+
+    if source_size != rom.len() {
+        return Err(invalid_patch("BPS patch is for a different ROM"));
+    }
+    if target_size > MAX_ROM_SIZE {
+        return Err(invalid_patch("BPS patch makes a ROM too big for a Game Boy"));
+    }
+
+    let mut out = Vec::with_capacity(target_size);
+    let mut source_offset: isize = 0;
+    let mut target_offset: isize = 0;
+
+    while !reader.is_empty() {
+        let data = reader.varint()?;
+        let length = (data >> 2) + 1;
+        if length > target_size - out.len() {
+            return Err(invalid_patch("BPS patch writes past the end of the target"));
+        }
+
+        match data & 3 {
+            // SourceRead
+            0 => {
+                let start = out.len();
+                match rom.get(start..start + length) {
+                    Some(bytes) => out.extend_from_slice(bytes),
+                    None => return Err(invalid_patch("BPS read past the end of the source")),
+                }
+            }
+            // TargetRead
+            1 => out.extend_from_slice(reader.bytes(length)?),
+            // SourceCopy
+            2 => {
+                source_offset = checked_offset(source_offset, reader.signed_varint()?)?;
+                if source_offset < 0 {
+                    return Err(invalid_patch("BPS copy before the start of the source"));
+                }
+                let start = source_offset as usize;
+                match rom.get(start..start + length) {
+                    Some(bytes) => out.extend_from_slice(bytes),
+                    None => return Err(invalid_patch("BPS copy past the end of the source")),
+                }
+                source_offset += length as isize;
+            }
+            // TargetCopy, which may overlap the bytes it is writing
+            _ => {
+                target_offset = checked_offset(target_offset, reader.signed_varint()?)?;
+                for _ in 0..length {
+                    match out.get(target_offset as usize).cloned() {
+                        Some(byte) => out.push(byte),
+                        None => return Err(invalid_patch("BPS copy past the end of the target")),
+                    }
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    if out.len() != target_size || crc32::checksum_ieee(&out) != target_crc {
+        return Err(invalid_patch("BPS patch produced a corrupt ROM"));
+    }
+
+    Ok(out)
+}
+
+// UPS patches XOR runs of bytes in the source, each run ends with a zero byte
+fn apply_ups(rom: &[u8], patch: &[u8]) -> io::Result<Vec<u8>> {
+    let (source_crc, target_crc) = check_footer(patch)?;
+    if crc32::checksum_ieee(rom) != source_crc {
+        return Err(invalid_patch("UPS patch is for a different ROM"));
+    }
+
+    let mut reader = PatchReader::new(&patch[4..patch.len() - 12]);
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+
+    if source_size != rom.len() {
+        return Err(invalid_patch("UPS patch is for a different ROM"));
+    }
+    if target_size > MAX_ROM_SIZE {
+        return Err(invalid_patch("UPS patch makes a ROM too big for a Game Boy"));
+    }
+
+    let mut out = rom.to_vec();
+    out.resize(target_size, 0);
+
+    let mut offset = 0usize;
+    while !reader.is_empty() {
+        offset = match offset.checked_add(reader.varint()?) {
+            Some(offset) => offset,
+            None => return Err(invalid_patch("UPS patch is corrupt")),
+        };
+        loop {
+            let byte = reader.byte()?;
+            if offset < out.len() {
+                out[offset] ^= byte;
+            }
+            offset += 1;
+            if byte == 0 {
+                break;
+            }
+        }
+    }
+
+    if crc32::checksum_ieee(&out) != target_crc {
+        return Err(invalid_patch("UPS patch produced a corrupt ROM"));
+    }
+
+    Ok(out)
+}
+
+// BPS and UPS end with the source, target and patch CRCs, the last covering
+// everything before it. Returns the source and target CRCs.
+fn check_footer(patch: &[u8]) -> io::Result<(u32, u32)> {
+    if patch.len() < 16 {
+        return Err(invalid_patch("patch is truncated"));
+    }
+
+    let footer = &patch[patch.len() - 12..];
+    let patch_crc = LittleEndian::read_u32(&footer[8..12]);
+    if crc32::checksum_ieee(&patch[..patch.len() - 4]) != patch_crc {
+        return Err(invalid_patch("patch is corrupt"));
+    }
+
+    Ok((LittleEndian::read_u32(&footer[0..4]), LittleEndian::read_u32(&footer[4..8])))
+}
+
+struct PatchReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        PatchReader { data: data, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn bytes(&mut self, count: usize) -> io::Result<&'a [u8]> {
+        let end = self.pos.saturating_add(count);
+        match self.data.get(self.pos..end) {
+            Some(bytes) => {
+                self.pos += count;
+                Ok(bytes)
+            }
+            None => Err(invalid_patch("patch is truncated")),
+        }
+    }
+
+    // The variable length numbers used by BPS and UPS, seven bits per byte
+    // with the top bit marking the last byte
+    fn varint(&mut self) -> io::Result<usize> {
+        let mut value = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let byte = self.byte()? as usize;
+            value = (byte & 0x7f).checked_mul(shift)
+                .and_then(|bits| value.checked_add(bits))
+                .ok_or_else(|| invalid_patch("patch has a number too big to read"))?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(0x80)
+                .ok_or_else(|| invalid_patch("patch has a number too big to read"))?;
+            value = value.checked_add(shift)
+                .ok_or_else(|| invalid_patch("patch has a number too big to read"))?;
+        }
+    }
+
+    // A varint holding a sign in the bottom bit
+    fn signed_varint(&mut self) -> io::Result<isize> {
+        let value = self.varint()?;
+        let magnitude = (value >> 1) as isize;
+        Ok(if value & 1 != 0 { -magnitude } else { magnitude })
+    }
+}
+
+// Moves a BPS copy offset by a relative amount read from the patch
+fn checked_offset(offset: isize, delta: isize) -> io::Result<isize> {
+    offset.checked_add(delta).ok_or_else(|| invalid_patch("BPS copy offset is out of range"))
+}
+
+fn invalid_patch(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(mut value: usize, out: &mut Vec<u8>) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(byte | 0x80);
+                return;
+            }
+            out.push(byte);
+            value -= 1;
+        }
+    }
+
+    // Appends the source, target and patch CRCs that end BPS and UPS patches
+    fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut crc = [0; 4];
+        LittleEndian::write_u32(&mut crc, crc32::checksum_ieee(source));
+        patch.extend_from_slice(&crc);
+        LittleEndian::write_u32(&mut crc, crc32::checksum_ieee(target));
+        patch.extend_from_slice(&crc);
+        LittleEndian::write_u32(&mut crc, crc32::checksum_ieee(&patch));
+        patch.extend_from_slice(&crc);
+        patch
+    }
+
+    fn bps_patch(source: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut patch = b"BPS1".to_vec();
+        varint(source.len(), &mut patch);
+        varint(target.len(), &mut patch);
+        varint(0, &mut patch);
+        // SourceRead of the first two bytes
+        varint((2 - 1) << 2, &mut patch);
+        // TargetRead of two new bytes
+        varint(((2 - 1) << 2) | 1, &mut patch);
+        patch.extend_from_slice(&[0xaa, 0xbb]);
+        // SourceCopy of two bytes from offset 4
+        varint(((2 - 1) << 2) | 2, &mut patch);
+        varint(4 << 1, &mut patch);
+        // TargetCopy of three bytes from offset 2, overlapping the output
+        varint(((3 - 1) << 2) | 3, &mut patch);
+        varint(2 << 1, &mut patch);
+        with_footer(patch, source, target)
+    }
+
+    fn ups_patch(source: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut patch = b"UPS1".to_vec();
+        varint(source.len(), &mut patch);
+        varint(target.len(), &mut patch);
+
+        let mut last = 0;
+        let mut offset = 0;
+        while offset < target.len() {
+            let differs = |i: usize| source.get(i).cloned().unwrap_or(0) != target[i];
+            if !differs(offset) {
+                offset += 1;
+                continue;
+            }
+            varint(offset - last, &mut patch);
+            while offset < target.len() && differs(offset) {
+                patch.push(source.get(offset).cloned().unwrap_or(0) ^ target[offset]);
+                offset += 1;
+            }
+            patch.push(0);
+            offset += 1;
+            last = offset;
+        }
+        with_footer(patch, source, target)
+    }
+
+    #[test]
+    fn ips_records() {
+        let rom = [0u8; 8];
+        let mut patch = b"PATCH".to_vec();
+        // Two bytes at 1
+        patch.extend_from_slice(&[0, 0, 1, 0, 2, 0x11, 0x22]);
+        // A run of three 0x33s at 4
+        patch.extend_from_slice(&[0, 0, 4, 0, 0, 0, 3, 0x33]);
+        patch.extend_from_slice(b"EOF");
+
+        let out = apply(&rom, &patch).unwrap();
+        assert_eq!(out, [0, 0x11, 0x22, 0, 0x33, 0x33, 0x33, 0]);
+    }
+
+    #[test]
+    fn ips_extend_and_truncate() {
+        let rom = [0u8; 4];
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0, 0, 6, 0, 0, 0, 2, 0x44]);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(apply(&rom, &patch).unwrap(), [0, 0, 0, 0, 0, 0, 0x44, 0x44]);
+
+        patch.extend_from_slice(&[0, 0, 7]);
+        assert_eq!(apply(&rom, &patch).unwrap(), [0, 0, 0, 0, 0, 0, 0x44]);
+    }
+
+    #[test]
+    fn ips_truncated() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0, 0, 1, 0, 4, 0x11]);
+        assert!(apply(&[0; 8], &patch).is_err());
+    }
+
+    #[test]
+    fn bps_commands() {
+        let source = [1, 2, 3, 4, 5, 6];
+        let target = [1, 2, 0xaa, 0xbb, 5, 6, 0xaa, 0xbb, 5];
+        let out = apply(&source, &bps_patch(&source, &target)).unwrap();
+        assert_eq!(out, target);
+    }
+
+    #[test]
+    fn bps_crc_mismatches() {
+        let source = [1, 2, 3, 4, 5, 6];
+        let target = [1, 2, 0xaa, 0xbb, 5, 6, 0xaa, 0xbb, 5];
+
+        // A different ROM
+        let patch = bps_patch(&source, &target);
+        assert!(apply(&[1, 2, 3, 4, 5, 7], &patch).is_err());
+
+        // A damaged patch
+        let mut damaged = patch.clone();
+        damaged[8] ^= 0xff;
+        assert!(apply(&source, &damaged).is_err());
+
+        // A target CRC that doesn't match what the commands produce
+        let patch = bps_patch(&source, &[0; 9]);
+        assert!(apply(&source, &patch).is_err());
+    }
+
+    #[test]
+    fn ups_xor_runs() {
+        let source = [1, 2, 3, 4, 5, 6, 7, 8];
+        let target = [1, 9, 9, 4, 5, 6, 7, 0, 0x10, 0x20];
+        let out = apply(&source, &ups_patch(&source, &target)).unwrap();
+        assert_eq!(out, target);
+    }
+
+    #[test]
+    fn ups_crc_mismatches() {
+        let source = [1, 2, 3, 4, 5, 6, 7, 8];
+        let target = [1, 9, 9, 4, 5, 6, 7, 8];
+        let patch = ups_patch(&source, &target);
+
+        assert!(apply(&[1, 2, 3, 4, 5, 6, 7, 9], &patch).is_err());
+
+        let mut damaged = patch.clone();
+        damaged[7] ^= 0xff;
+        assert!(apply(&source, &damaged).is_err());
+
+        // Rewrite the target CRC, leaving the patch CRC valid
+        let wrong_target = with_footer(patch[..patch.len() - 12].to_vec(), &source, &source);
+        assert!(apply(&source, &wrong_target).is_err());
+    }
+
+    #[test]
+    fn oversized_targets() {
+        let source = [1, 2, 3, 4];
+        for &magic in &[&b"BPS1"[..], &b"UPS1"[..]] {
+            let mut patch = magic.to_vec();
+            varint(source.len(), &mut patch);
+            varint(usize::MAX >> 1, &mut patch);
+            varint(0, &mut patch);
+            assert!(apply(&source, &with_footer(patch, &source, &source)).is_err());
+        }
+    }
+
+    #[test]
+    fn bps_command_past_the_target() {
+        let source = [1, 2, 3, 4];
+        let mut patch = b"BPS1".to_vec();
+        varint(source.len(), &mut patch);
+        varint(4, &mut patch);
+        varint(0, &mut patch);
+        // TargetCopy of a huge run, which would otherwise keep growing the
+        // target
+        varint(((1 << 40) << 2) | 3, &mut patch);
+        varint(0, &mut patch);
+        assert!(apply(&source, &with_footer(patch, &source, &source)).is_err());
+    }
+
+    #[test]
+    fn varint_overflow() {
+        let mut reader = PatchReader::new(&[0x7f; 16]);
+        assert!(reader.varint().is_err());
+    }
+
+    #[test]
+    fn unrecognised_format() {
+        assert!(apply(&[0; 4], b"NOTAPATCH").is_err());
+    }
+}
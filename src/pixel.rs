@@ -0,0 +1,55 @@
+// Which part of the PPU drew a pixel. Sprites are split by the palette
+// register they use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Layer {
+    Background,
+    Window,
+    Obj0,
+    Obj1,
+}
+
+// A pixel as the PPU draws it, a shade from 0 (lightest) to 3 (darkest) after
+// the palette register has been applied, and the layer it came from. A
+// palette::Palette turns these into colours.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Pixel {
+    pub shade: u8,
+    pub layer: Layer,
+}
+
+impl Default for Pixel {
+    fn default() -> Self {
+        Pixel {
+            shade: 0,
+            layer: Layer::Background,
+        }
+    }
+}
+
+// Packed as the shade in the low two bits with the layer above them, for save
+// states
+impl From<u8> for Pixel {
+    fn from(val: u8) -> Self {
+        Pixel {
+            shade: val & 0x03,
+            layer: match (val >> 2) & 0x03 {
+                0 => Layer::Background,
+                1 => Layer::Window,
+                2 => Layer::Obj0,
+                _ => Layer::Obj1,
+            },
+        }
+    }
+}
+
+impl From<Pixel> for u8 {
+    fn from(pixel: Pixel) -> u8 {
+        let layer = match pixel.layer {
+            Layer::Background => 0,
+            Layer::Window => 1,
+            Layer::Obj0 => 2,
+            Layer::Obj1 => 3,
+        };
+        layer << 2 | (pixel.shade & 0x03)
+    }
+}
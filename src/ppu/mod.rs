@@ -1,8 +1,12 @@
 use mem_map::*;
 use device::Device;
 use interrupt::{Irq, Interrupt};
+use ioregs::{on_off, print_field};
+use palette::Palette;
+use pixel::{Layer, Pixel};
+use std::io;
+use state::{SaveState, StateReader, StateWriter, invalid_state};
 
-const COLOUR_MAP: [u32; 4] = [0xff7e8429, 0xff527a4b, 0xff315d4b, 0xff29473e];
 const WIDTH: usize = 160;
 const HEIGHT: usize = 144;
 
@@ -20,7 +24,13 @@ pub enum PpuState {
 pub struct Ppu {
     vram: Box<[u8]>, // VRAM - mapped to 0x8000 - 0x9FFF
     oam: Box<[u8]>, // Obj/Sprite Attribute Table - mapped to 0xfe00 - 0xfea0
+    // The frame as drawn, only turned into colours when each line is output
+    pixels: Box<[Pixel]>,
+    // The background and window colour numbers of the current line before
+    // BGP is applied, which decide whether sprites behind the background show
+    bg_colours: [u8; WIDTH],
     frame_buffer: Box<[u32]>,
+    palette: Palette,
 
     lcd_control: LcdControlReg, // 0xff40 - LCDC
     lcdc_status: LcdcStatusReg, // 0xff41 - STAT
@@ -33,6 +43,10 @@ pub struct Ppu {
     obj1_palette_data: PaletteDataReg, // 0xff49 OBJ1 palette data
     wy: u8, // 0xff4a - window Y position
     wx: u8, // 0xff4b - window X position, offset from screen coords by 7
+    // The row of the window to draw next. It only advances on lines the
+    // window is drawn on, so hiding the window part way down the screen
+    // carries on from the same row when it's shown again.
+    window_line: u8,
 
     state: PpuState,
     cycles: u16,
@@ -46,7 +60,10 @@ impl Ppu {
         Ppu {
             vram: vec![0; VRAM_LENGTH as usize].into_boxed_slice(),
             oam: vec![0; OAM_LENGTH as usize].into_boxed_slice(),
-            frame_buffer: vec![COLOUR_MAP[0]; WIDTH * HEIGHT].into_boxed_slice(),
+            pixels: vec![Pixel::default(); WIDTH * HEIGHT].into_boxed_slice(),
+            bg_colours: [0; WIDTH],
+            frame_buffer: vec![Palette::default().bg[0]; WIDTH * HEIGHT].into_boxed_slice(),
+            palette: Palette::default(),
 
             lcd_control: LcdControlReg::default(),
             lcdc_status: LcdcStatusReg::default(),
@@ -56,6 +73,7 @@ impl Ppu {
             lyc: 0,
             wy: 0,
             wx: 0,
+            window_line: 0,
             bg_palette_data: PaletteDataReg::default(),
             obj0_palette_data: PaletteDataReg::default(),
             obj1_palette_data: PaletteDataReg::default(),
@@ -116,6 +134,7 @@ impl Ppu {
                     self.cycles = 0;
                     self.lcdc_status.mode = 0;
                     self.ly = 0;
+                    self.window_line = 0;
                 } else if self.state == PpuState::Off {
                     self.state = PpuState::Setup(0);
                     self.lcdc_status.mode = 0;
@@ -152,6 +171,80 @@ impl Ppu {
         HEIGHT
     }
 
+    pub fn frame_buffer(&self) -> &[u32] {
+        &self.frame_buffer
+    }
+
+    // The frame as the shades and layers frame_buffer's colours come from
+    pub fn frame_pixels(&self) -> &[Pixel] {
+        &self.pixels
+    }
+
+    pub fn vram(&self) -> &[u8] {
+        &self.vram
+    }
+
+    pub fn oam(&self) -> &[u8] {
+        &self.oam
+    }
+
+    pub fn palette(&self) -> Palette {
+        self.palette
+    }
+
+    pub fn print_registers(&self) {
+        let lcdc = self.lcd_control;
+        let map = |high: bool| if high { "9c00-9fff" } else { "9800-9bff" };
+        println!("LCDC: {:02x}", self.read_reg(0xff40));
+        print_field("7", "LCD", on_off(lcdc.lcd_control_op));
+        print_field("6", "Window tile map", map(lcdc.win_tile_map_display));
+        print_field("5", "Window", on_off(lcdc.window_display));
+        print_field("4", "BG/window tile data", if lcdc.bg_win_tile_data { "8000-8fff" } else { "8800-97ff" });
+        print_field("3", "BG tile map", map(lcdc.bg_tile_map_display));
+        print_field("2", "Sprite size", if lcdc.sprite_size { "8x16" } else { "8x8" });
+        print_field("1", "Sprites", on_off(lcdc.sprite_display));
+        print_field("0", "BG/window", on_off(lcdc.bg_window_display));
+
+        let stat = self.lcdc_status;
+        let mode = match stat.mode {
+            0 => "0 (HBlank)",
+            1 => "1 (VBlank)",
+            2 => "2 (OAM search)",
+            _ => "3 (pixel transfer)",
+        };
+        println!("STAT: {:02x}", self.read_reg(0xff41));
+        print_field("6", "LYC=LY interrupt", on_off(stat.coincidence_interrupt_enable));
+        print_field("5", "OAM interrupt", on_off(stat.oam_interrupt_enable));
+        print_field("4", "VBlank interrupt", on_off(stat.vblank_interrupt_enable));
+        print_field("3", "HBlank interrupt", on_off(stat.hblank_interrupt_enable));
+        print_field("2", "LYC=LY", if stat.coincidence_flag { "yes" } else { "no" });
+        print_field("1-0", "Mode", mode);
+
+        println!("LY: {:02x}, LYC: {:02x}, State: {:?}, Cycles: {}", self.ly, self.lyc, self.state, self.cycles);
+        println!("SCY: {:02x}, SCX: {:02x}, WY: {:02x}, WX: {:02x}", self.scy, self.scx, self.wy, self.wx);
+
+        // Each palette as the shade given to colours 0 to 3
+        let palettes = [("BGP", 0xff47, self.bg_palette_data),
+                        ("OBP0", 0xff48, self.obj0_palette_data),
+                        ("OBP1", 0xff49, self.obj1_palette_data)];
+        for &(name, addr, palette) in &palettes {
+            println!("{}: {:02x}, Shades: {} {} {} {}",
+                     name,
+                     self.read_reg(addr),
+                     palette.col0_shade,
+                     palette.col1_shade,
+                     palette.col2_shade,
+                     palette.col3_shade);
+        }
+    }
+
+    // Redraws the frame in the new colours straight away, rather than
+    // waiting for the next frame
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+        palette.to_rgba(&self.pixels, &mut self.frame_buffer);
+    }
+
     fn inner_step(&mut self, device: &mut Device, irq: &mut Irq) {
         self.cycles += 1;
         self.lcdc_status.coincidence_flag = self.ly == self.lyc;
@@ -191,6 +284,8 @@ impl Ppu {
                         self.render_sprites();
                     }
 
+                    self.output_line();
+
                     self.lcdc_status.mode = 0;
                     self.state = PpuState::HBlank;
                 } else {
@@ -211,8 +306,9 @@ impl Ppu {
                         self.lcdc_status.mode = 2;
                         self.state = PpuState::OamSearchY(0);
                     } else {
-                            self.state = PpuState::VBlank(0);
-                            self.lcdc_status.mode = 1;
+                        self.state = PpuState::VBlank(0);
+                        self.lcdc_status.mode = 1;
+                        self.window_line = 0;
 
                         irq.raise_interrupt(Interrupt::VBlank);
                         if self.lcdc_status.vblank_interrupt_enable {
@@ -247,21 +343,53 @@ impl Ppu {
 
     fn render_background(&mut self) {
         let background_row = self.ly.wrapping_add(self.scy);
+        let background_map = if self.lcd_control.bg_tile_map_display {
+            0x1c00
+        } else {
+            0x1800
+        };
+        let window_map = if self.lcd_control.win_tile_map_display {
+            0x1c00
+        } else {
+            0x1800
+        };
+        // WX above 166 puts the window off the right of the screen
+        let window_visible = self.lcd_control.window_display && self.ly >= self.wy && self.wx < 167;
+        let window_row = self.window_line;
 
         for i in 0..WIDTH as u8 {
-            let background_col = self.scx.wrapping_add(i);
-
-            let tile_offset = self.get_background_tile_offset(background_row / 8,
-                                                              background_col / 8);
-            let colour = self.get_tile_pixel(tile_offset, background_row % 8, background_col % 8);
+            let (colour, layer) = if window_visible && i as u16 + 7 >= self.wx as u16 {
+                let window_col = (i as u16 + 7 - self.wx as u16) as u8;
+                let tile_offset = self.get_tile_offset(window_map, window_row / 8, window_col / 8);
+                (self.get_tile_colour(tile_offset, window_row as usize % 8, window_col as usize % 8),
+                 Layer::Window)
+            } else {
+                let background_col = self.scx.wrapping_add(i);
+                let tile_offset = self.get_tile_offset(background_map,
+                                                       background_row / 8,
+                                                       background_col / 8);
+                (self.get_tile_colour(tile_offset, background_row as usize % 8, background_col as usize % 8),
+                 Layer::Background)
+            };
+
+            self.bg_colours[i as usize] = colour;
+            self.pixels[(self.ly as usize * WIDTH) + i as usize] = Pixel {
+                shade: self.bg_palette_data.shade(colour),
+                layer: layer,
+            };
+        }
 
-            self.frame_buffer[(self.ly as usize * WIDTH) + i as usize] = colour;
+        if window_visible {
+            self.window_line = self.window_line.wrapping_add(1);
         }
     }
 
     fn render_sprites(&mut self) {
         let sprite_height: i16 = if self.lcd_control.sprite_size { 16 } else { 8 };
         let ly = self.ly as i16;
+        // The sprite pixel that wins at each x, along with the sprite's x and
+        // whether it's drawn behind background colours 1-3
+        let mut sprite_pixels: [Option<(i16, bool, Pixel)>; WIDTH] = [None; WIDTH];
 
         for i in 0..40 {
             let sprite_y = (self.oam[i * 4] as i16) - 16;
@@ -278,23 +406,58 @@ impl Ppu {
             let sprite_flags = self.oam[(i * 4) + 3];
 
             let flip_horz = (sprite_flags & (1 << 5)) != 0;
+            let behind_bg = (sprite_flags & (1 << 7)) != 0;
+            let (palette_data, layer) = if (sprite_flags & (1 << 4)) != 0 {
+                (self.obj1_palette_data, Layer::Obj1)
+            } else {
+                (self.obj0_palette_data, Layer::Obj0)
+            };
+
+            for col in 0..8 {
+                let x = sprite_x + col as i16;
+                if x < 0 || x >= WIDTH as i16 {
+                    continue;
+                }
 
-            for i in 0..8 {
-                let x = if flip_horz { 7 - i } else { i };
-                let colour = self.get_sprite_pixel(tile_offset, sprite_row, x);
+                // Where sprites overlap the one furthest left is drawn, then
+                // the one earliest in OAM
+                if let Some((other_x, _, _)) = sprite_pixels[x as usize] {
+                    if other_x <= sprite_x {
+                        continue;
+                    }
+                }
+
+                // Colour 0 is transparent
+                let colour = self.get_tile_colour(tile_offset, sprite_row, if flip_horz { 7 - col } else { col });
+                if colour == 0 {
+                    continue;
+                }
 
-                self.frame_buffer[(ly as usize * WIDTH) + (sprite_x + i as i16) as usize] = colour;
+                sprite_pixels[x as usize] = Some((sprite_x, behind_bg, Pixel {
+                    shade: palette_data.shade(colour),
+                    layer: layer,
+                }));
+            }
+        }
+
+        let line = ly as usize * WIDTH;
+        for (x, sprite_pixel) in sprite_pixels.iter().enumerate() {
+            if let Some((_, behind_bg, pixel)) = *sprite_pixel {
+                if !behind_bg || self.bg_colours[x] == 0 {
+                    self.pixels[line + x] = pixel;
+                }
             }
         }
     }
 
-    // Returns the offset in self.vram of the background tile
-    fn get_background_tile_offset(&self, row: u8, col: u8) -> usize {
-        let tile_idx_base = if self.lcd_control.bg_tile_map_display {
-            0x1c00
-        } else {
-            0x1800
-        };
+    fn output_line(&mut self) {
+        let line = self.ly as usize * WIDTH..(self.ly as usize + 1) * WIDTH;
+        self.palette.to_rgba(&self.pixels[line.clone()], &mut self.frame_buffer[line]);
+    }
+
+    // Returns the offset in self.vram of a background or window tile, from
+    // the tile map at tile_idx_base
+    fn get_tile_offset(&self, tile_idx_base: usize, row: u8, col: u8) -> usize {
         let tile_idx_offset = (row as usize * 32) + col as usize;
         let tile_index = self.vram[tile_idx_base + tile_idx_offset];
 
@@ -305,38 +468,14 @@ impl Ppu {
         }
     }
 
-    fn get_tile_pixel(&self, tile_offset: usize, row: u8, col: u8) -> u32 {
-        let offset = tile_offset + (row as usize * 2);
+    // The colour number, 0-3, of a pixel in a tile before a palette is
+    // applied
+    fn get_tile_colour(&self, tile_offset: usize, row: usize, col: usize) -> u8 {
+        let offset = tile_offset + (row * 2);
 
         let upper_col = self.vram[offset + 1] >> (7 - col) & 1;
         let lower_col = self.vram[offset] >> (7 - col) & 1;
-        let tile_colour = upper_col << 1 | lower_col;
-
-        let col_index = match tile_colour {
-            0 => self.bg_palette_data.col0_shade,
-            1 => self.bg_palette_data.col1_shade,
-            2 => self.bg_palette_data.col2_shade,
-            3 => self.bg_palette_data.col3_shade,
-            _ => unreachable!(),
-        };
-        COLOUR_MAP[col_index]
-    }
-
-    fn get_sprite_pixel(&self, tile_offset: usize, row: usize, col: usize) -> u32 {
-        let offset = tile_offset + (row as usize * 2);
-
-        let upper_col = self.vram[offset + 1] >> (7 - col) & 1;
-        let lower_col = self.vram[offset] >> (7 - col) & 1;
-        let tile_colour = upper_col << 1 | lower_col;
-
-        let col_index = match tile_colour {
-            0 => self.obj0_palette_data.col0_shade,
-            1 => self.obj0_palette_data.col1_shade,
-            2 => self.obj0_palette_data.col2_shade,
-            3 => self.obj0_palette_data.col3_shade,
-            _ => unreachable!(),
-        };
-        COLOUR_MAP[col_index]
+        upper_col << 1 | lower_col
     }
 }
 
@@ -474,9 +613,179 @@ impl From<u8> for PaletteDataReg {
     }
 }
 
+impl PaletteDataReg {
+    fn shade(&self, colour: u8) -> u8 {
+        let shade = match colour {
+            0 => self.col0_shade,
+            1 => self.col1_shade,
+            2 => self.col2_shade,
+            3 => self.col3_shade,
+            _ => unreachable!(),
+        };
+        shade as u8
+    }
+}
+
 impl Into<u8> for PaletteDataReg {
     fn into(self) -> u8 {
         (self.col0_shade | (self.col1_shade << 2) | (self.col2_shade << 4) |
          (self.col3_shade << 6)) as u8
     }
 }
+
+impl SaveState for Ppu {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.vram);
+        state.bytes(&self.oam);
+        let pixels: Vec<u8> = self.pixels.iter().map(|p| u8::from(*p)).collect();
+        state.bytes(&pixels);
+
+        state.u8(self.lcd_control.into());
+        let status: u8 = self.lcdc_status.into();
+        state.u8(status);
+        state.u8(self.scy);
+        state.u8(self.scx);
+        state.u8(self.ly);
+        state.u8(self.lyc);
+        state.u8(self.bg_palette_data.into());
+        state.u8(self.obj0_palette_data.into());
+        state.u8(self.obj1_palette_data.into());
+        state.u8(self.wy);
+        state.u8(self.wx);
+        state.u8(self.window_line);
+
+        // Every state is written with two fields so the size never changes
+        let (tag, a, b) = match self.state {
+            PpuState::Off => (0, 0, 0),
+            PpuState::Setup(n) => (1, n, 0),
+            PpuState::OamSearchY(n) => (2, n, 0),
+            PpuState::OamSearchX(n, y) => (3, n, y),
+            PpuState::PixelTransfer(n) => (4, n, 0),
+            PpuState::HBlank => (5, 0, 0),
+            PpuState::VBlank(n) => (6, n, 0),
+        };
+        state.u8(tag);
+        state.u32(a as u32);
+        state.u16(b);
+        state.u16(self.cycles);
+        state.u16(self.line_length);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        state.bytes(&mut self.vram)?;
+        state.bytes(&mut self.oam)?;
+        let mut pixels = vec![0; WIDTH * HEIGHT];
+        state.bytes(&mut pixels)?;
+        for (pixel, val) in self.pixels.iter_mut().zip(pixels) {
+            *pixel = val.into();
+        }
+
+        self.lcd_control = state.u8()?.into();
+        let status = state.u8()?;
+        self.lcdc_status.set(status);
+        self.lcdc_status.coincidence_flag = status & (1 << 2) != 0;
+        self.lcdc_status.mode = status & 0x03;
+        self.scy = state.u8()?;
+        self.scx = state.u8()?;
+        self.ly = state.u8()?;
+        self.lyc = state.u8()?;
+        self.bg_palette_data = state.u8()?.into();
+        self.obj0_palette_data = state.u8()?.into();
+        self.obj1_palette_data = state.u8()?.into();
+        self.wy = state.u8()?;
+        self.wx = state.u8()?;
+        self.window_line = state.u8()?;
+
+        let tag = state.u8()?;
+        let a = state.u32()? as usize;
+        let b = state.u16()?;
+        self.state = match tag {
+            0 => PpuState::Off,
+            1 => PpuState::Setup(a),
+            2 => PpuState::OamSearchY(a),
+            3 => PpuState::OamSearchX(a, b),
+            4 => PpuState::PixelTransfer(a),
+            5 => PpuState::HBlank,
+            6 => PpuState::VBlank(a),
+            _ => return Err(invalid_state("invalid PPU state")),
+        };
+        self.cycles = state.u16()?;
+        self.line_length = state.u16()?;
+
+        let palette = self.palette;
+        self.set_palette(palette);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A PPU on line 0 with a background of tile 0, whose rows are colour 1 on
+    // the left half and colour 0 on the right, and sprites using tile 1, whose
+    // rows are colour 3 in the middle four pixels and transparent around them
+    fn test_ppu() -> Ppu {
+        let mut ppu = Ppu::new();
+        ppu.write_reg(0xff40, 0x93);
+        ppu.write_reg(0xff47, 0xe4);
+        ppu.write_reg(0xff48, 0xe4);
+        ppu.write_reg(0xff49, 0xe4);
+        for row in 0..8 {
+            ppu.vram[row * 2] = 0xf0;
+            ppu.vram[16 + row * 2] = 0x3c;
+            ppu.vram[16 + row * 2 + 1] = 0x3c;
+        }
+        ppu
+    }
+
+    fn set_sprite(ppu: &mut Ppu, index: usize, x: u8, flags: u8) {
+        ppu.oam[index * 4..index * 4 + 4].copy_from_slice(&[16, x + 8, 1, flags]);
+    }
+
+    fn render_line(ppu: &mut Ppu) -> Vec<(Layer, u8)> {
+        ppu.render_background();
+        ppu.render_sprites();
+        ppu.pixels[..10].iter().map(|p| (p.layer, p.shade)).collect()
+    }
+
+    const BG: (Layer, u8) = (Layer::Background, 1);
+    const BG0: (Layer, u8) = (Layer::Background, 0);
+    const OBJ0: (Layer, u8) = (Layer::Obj0, 3);
+    const OBJ1: (Layer, u8) = (Layer::Obj1, 3);
+
+    #[test]
+    fn sprite_over_background() {
+        let mut ppu = test_ppu();
+        set_sprite(&mut ppu, 0, 0, 0x00);
+        assert_eq!(render_line(&mut ppu), [BG, BG, OBJ0, OBJ0, OBJ0, OBJ0, BG0, BG0, BG, BG]);
+    }
+
+    #[test]
+    fn sprite_behind_background() {
+        let mut ppu = test_ppu();
+        set_sprite(&mut ppu, 0, 0, 0x80);
+        assert_eq!(render_line(&mut ppu), [BG, BG, BG, BG, OBJ0, OBJ0, BG0, BG0, BG, BG]);
+    }
+
+    #[test]
+    fn overlapping_sprites() {
+        let mut ppu = test_ppu();
+        // The sprite further left wins even though it comes later in OAM
+        set_sprite(&mut ppu, 0, 2, 0x10);
+        set_sprite(&mut ppu, 1, 0, 0x00);
+        assert_eq!(render_line(&mut ppu), [BG, BG, OBJ0, OBJ0, OBJ0, OBJ0, OBJ1, OBJ1, BG, BG]);
+
+        // Hiding the winning sprite behind the background doesn't let the
+        // other one show through
+        set_sprite(&mut ppu, 1, 0, 0x80);
+        assert_eq!(render_line(&mut ppu), [BG, BG, BG, BG, OBJ0, OBJ0, OBJ1, OBJ1, BG, BG]);
+    }
+
+    #[test]
+    fn sprite_off_the_left_edge() {
+        let mut ppu = test_ppu();
+        ppu.oam[..4].copy_from_slice(&[16, 4, 1, 0x00]);
+        assert_eq!(render_line(&mut ppu), [OBJ0, OBJ0, BG, BG, BG0, BG0, BG0, BG0, BG, BG]);
+    }
+}
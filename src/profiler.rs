@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use symbols::{BankedAddr, Symbols};
+use vm::CallFrame;
+
+const CLOCK_SPEED: f64 = 4194304.0;
+const REPORT_ROWS: usize = 40;
+
+// Accumulates the cycles spent at each PC and in each call stack so that hot
+// code can be found after a run
+pub struct Profiler {
+    report_path: Option<PathBuf>,
+    stacks_path: Option<PathBuf>,
+
+    pc_cycles: HashMap<BankedAddr, u64>,
+    stack_cycles: HashMap<Vec<BankedAddr>, u64>,
+    halt_cycles: u64,
+    interrupt_cycles: u64,
+    total_cycles: u64,
+
+    // Cycles for the current call stack are batched up until the stack
+    // changes, rather than hashing the whole stack every instruction
+    current_stack: Vec<BankedAddr>,
+    current_stack_cycles: u64,
+}
+
+impl Profiler {
+    pub fn new(report_path: Option<&Path>, stacks_path: Option<&Path>) -> Self {
+        Profiler {
+            report_path: report_path.map(|p| p.to_path_buf()),
+            stacks_path: stacks_path.map(|p| p.to_path_buf()),
+
+            pc_cycles: HashMap::new(),
+            stack_cycles: HashMap::new(),
+            halt_cycles: 0,
+            interrupt_cycles: 0,
+            total_cycles: 0,
+
+            current_stack: Vec::new(),
+            current_stack_cycles: 0,
+        }
+    }
+
+    pub fn record(&mut self, pc: BankedAddr, cycles: u16, halted: bool, in_interrupt: bool, call_stack: &[CallFrame]) {
+        let cycles = cycles as u64;
+
+        *self.pc_cycles.entry(pc).or_insert(0) += cycles;
+        self.total_cycles += cycles;
+        if halted {
+            self.halt_cycles += cycles;
+        }
+        if in_interrupt {
+            self.interrupt_cycles += cycles;
+        }
+
+        let same_stack = self.current_stack.len() == call_stack.len() &&
+            self.current_stack.iter().zip(call_stack).all(|(a, f)| *a == f.target);
+        if !same_stack {
+            self.flush_stack();
+            self.current_stack.clear();
+            self.current_stack.extend(call_stack.iter().map(|f| f.target));
+        }
+        self.current_stack_cycles += cycles;
+    }
+
+    // Writes any output files requested when the profiler was created
+    pub fn save(&mut self, symbols: &Symbols) -> io::Result<()> {
+        self.flush_stack();
+
+        if let Some(ref path) = self.report_path {
+            let mut file = BufWriter::new(File::create(path)?);
+            self.write_report(&mut file, symbols)?;
+        }
+        if let Some(ref path) = self.stacks_path {
+            let mut file = BufWriter::new(File::create(path)?);
+            self.write_collapsed_stacks(&mut file, symbols)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn write_report<W: Write>(&self, out: &mut W, symbols: &Symbols) -> io::Result<()> {
+        writeln!(out,
+                 "Profiled {} cycles ({:.2}s emulated)",
+                 self.total_cycles,
+                 self.total_cycles as f64 / CLOCK_SPEED)?;
+        writeln!(out, "HALT:       {:>12} {:>6.2}%", self.halt_cycles, self.percent(self.halt_cycles))?;
+        writeln!(out, "Interrupts: {:>12} {:>6.2}%", self.interrupt_cycles, self.percent(self.interrupt_cycles))?;
+
+        // Attribute each address to the closest label before it
+        let mut symbol_cycles = HashMap::new();
+        for (pc, cycles) in &self.pc_cycles {
+            let name = match symbols.nearest(*pc) {
+                Some((label, _)) => label.to_owned(),
+                None => format!("{}", pc),
+            };
+            *symbol_cycles.entry(name).or_insert(0) += *cycles;
+        }
+
+        writeln!(out)?;
+        writeln!(out, "Hot symbols:")?;
+        for (name, cycles) in sorted_by_cycles(&symbol_cycles).into_iter().take(REPORT_ROWS) {
+            writeln!(out, "{:>12} {:>6.2}%  {}", cycles, self.percent(cycles), name)?;
+        }
+
+        writeln!(out)?;
+        writeln!(out, "Hot addresses:")?;
+        for (pc, cycles) in sorted_by_cycles(&self.pc_cycles).into_iter().take(REPORT_ROWS) {
+            writeln!(out,
+                     "{:>12} {:>6.2}%  {}  {}",
+                     cycles,
+                     self.percent(cycles),
+                     pc,
+                     symbols.describe(*pc).unwrap_or_default())?;
+        }
+
+        Ok(())
+    }
+
+    // Writes one "outer;inner cycles" line per call stack, the format read by
+    // flamegraph.pl and compatible tools
+    pub fn write_collapsed_stacks<W: Write>(&self, out: &mut W, symbols: &Symbols) -> io::Result<()> {
+        for (stack, cycles) in sorted_by_cycles(&self.stack_cycles) {
+            let mut frames = vec!["(top)".to_owned()];
+            frames.extend(stack.iter().map(|addr| {
+                symbols.get(*addr).map_or_else(|| format!("{}", addr), |s| s.to_owned())
+            }));
+            writeln!(out, "{} {}", frames.join(";"), cycles)?;
+        }
+
+        Ok(())
+    }
+
+    fn flush_stack(&mut self) {
+        if self.current_stack_cycles > 0 {
+            *self.stack_cycles.entry(self.current_stack.clone()).or_insert(0) += self.current_stack_cycles;
+            self.current_stack_cycles = 0;
+        }
+    }
+
+    fn percent(&self, cycles: u64) -> f64 {
+        if self.total_cycles == 0 {
+            0.0
+        } else {
+            cycles as f64 * 100.0 / self.total_cycles as f64
+        }
+    }
+}
+
+fn sorted_by_cycles<K: Ord>(map: &HashMap<K, u64>) -> Vec<(&K, u64)> {
+    let mut entries: Vec<_> = map.iter().map(|(k, v)| (k, *v)).collect();
+    entries.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    entries
+}
@@ -0,0 +1,136 @@
+extern crate byteorder;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use self::byteorder::{LittleEndian, WriteBytesExt};
+use audio::Resampler;
+
+const CYCLES_PER_FRAME: u32 = 70224;
+const SAMPLE_RATE: u32 = 44100;
+
+// CLOCK_RATE / CYCLES_PER_FRAME in lowest terms, about 59.73 fps
+const FRAME_RATE: (u32, u32) = (262144, 4389);
+
+const WAV_HEADER_LENGTH: u32 = 44;
+
+// Records the screen to a YUV4MPEG2 video and the sound to a WAV file next to
+// it, e.g. clip.y4m and clip.wav, which video tools can combine without any
+// conversion. Both are timed by emulated cycles rather than by the frames the
+// PPU finishes, so they stay in sync even while the LCD is off.
+pub struct Recorder {
+    video: BufWriter<File>,
+    audio: BufWriter<File>,
+    video_path: PathBuf,
+
+    width: usize,
+    height: usize,
+    // The last frame the PPU finished, written each time a video frame is due
+    frame: Vec<u32>,
+    video_cycles: u32,
+    resampler: Resampler,
+    samples: u32,
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(video_file: P, width: usize, height: usize) -> io::Result<Recorder> {
+        let video_path = video_file.as_ref().to_path_buf();
+        let mut video = BufWriter::new(File::create(&video_path)?);
+        let mut audio = BufWriter::new(File::create(video_path.with_extension("wav"))?);
+
+        writeln!(video, "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444",
+                 width, height, FRAME_RATE.0, FRAME_RATE.1)?;
+        // The sizes are filled in by finish
+        write_wav_header(&mut audio, 0)?;
+
+        Ok(Recorder {
+            video: video,
+            audio: audio,
+            video_path: video_path,
+
+            width: width,
+            height: height,
+            frame: vec![0xffffffff; width * height],
+            video_cycles: CYCLES_PER_FRAME,
+            resampler: Resampler::new(SAMPLE_RATE),
+            samples: 0,
+        })
+    }
+
+    pub fn video_path(&self) -> &Path {
+        &self.video_path
+    }
+
+    pub fn set_frame(&mut self, frame: &[u32]) {
+        self.frame.copy_from_slice(frame);
+    }
+
+    // Moves the recording on by the given number of cycles, writing out any
+    // video frames and audio samples that have come due
+    pub fn step(&mut self, cycles: u16, sample: (i16, i16)) -> io::Result<()> {
+        self.video_cycles += cycles as u32;
+        while self.video_cycles >= CYCLES_PER_FRAME {
+            self.video_cycles -= CYCLES_PER_FRAME;
+            self.write_frame()?;
+        }
+
+        self.resampler.step(cycles, sample);
+        for val in self.resampler.samples() {
+            self.audio.write_i16::<LittleEndian>(*val)?;
+        }
+        self.samples += self.resampler.samples().len() as u32 / 2;
+        self.resampler.clear();
+
+        Ok(())
+    }
+
+    // Flushes both files and fills in the WAV header now its length is known
+    pub fn finish(mut self) -> io::Result<()> {
+        self.video.flush()?;
+
+        self.audio.seek(SeekFrom::Start(0))?;
+        write_wav_header(&mut self.audio, self.samples * 4)?;
+        self.audio.flush()
+    }
+
+    fn write_frame(&mut self) -> io::Result<()> {
+        let mut planes = vec![0; self.width * self.height * 3];
+        {
+            let (y, chroma) = planes.split_at_mut(self.width * self.height);
+            let (u, v) = chroma.split_at_mut(self.width * self.height);
+
+            // BT.601 with the usual 16-235 range
+            for (i, pixel) in self.frame.iter().enumerate() {
+                let r = ((pixel >> 16) & 0xff) as i32;
+                let g = ((pixel >> 8) & 0xff) as i32;
+                let b = (pixel & 0xff) as i32;
+
+                y[i] = (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8;
+                u[i] = (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8;
+                v[i] = (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8;
+            }
+        }
+
+        self.video.write_all(b"FRAME\n")?;
+        self.video.write_all(&planes)
+    }
+}
+
+// 16 bit stereo PCM
+fn write_wav_header<W: Write>(writer: &mut W, data_length: u32) -> io::Result<()> {
+    writer.write_all(b"RIFF")?;
+    writer.write_u32::<LittleEndian>(WAV_HEADER_LENGTH - 8 + data_length)?;
+    writer.write_all(b"WAVE")?;
+
+    writer.write_all(b"fmt ")?;
+    writer.write_u32::<LittleEndian>(16)?;
+    writer.write_u16::<LittleEndian>(1)?;
+    writer.write_u16::<LittleEndian>(2)?;
+    writer.write_u32::<LittleEndian>(SAMPLE_RATE)?;
+    writer.write_u32::<LittleEndian>(SAMPLE_RATE * 4)?;
+    writer.write_u16::<LittleEndian>(4)?;
+    writer.write_u16::<LittleEndian>(16)?;
+
+    writer.write_all(b"data")?;
+    writer.write_u32::<LittleEndian>(data_length)
+}
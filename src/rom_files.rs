@@ -0,0 +1,51 @@
+extern crate app_dirs;
+
+use std::io;
+use std::path::{Path, PathBuf};
+use config::app_info::APP_INFO;
+use self::app_dirs::{AppDataType, app_dir};
+
+// Names the files saved for a ROM in the user data directory after the ROM
+// e.g. screenshots/game-001.png, recordings/game-001.y4m or states/game.state
+pub struct RomFiles {
+    prefix: String,
+}
+
+impl Default for RomFiles {
+    fn default() -> Self {
+        RomFiles { prefix: "scimitar".to_owned() }
+    }
+}
+
+impl RomFiles {
+    pub fn for_rom<P: AsRef<Path>>(rom_file: P) -> RomFiles {
+        match rom_file.as_ref().file_stem() {
+            Some(stem) => RomFiles { prefix: stem.to_string_lossy().into_owned() },
+            None => RomFiles::default(),
+        }
+    }
+
+    // The next unused numbered file name in the given user data directory
+    pub fn next_path(&self, dir_name: &str, extension: &str) -> io::Result<PathBuf> {
+        let dir = data_dir(dir_name)?;
+
+        let mut index = 1;
+        let mut path = dir.join(format!("{}-{:03}.{}", self.prefix, index, extension));
+        while path.exists() {
+            index += 1;
+            path = dir.join(format!("{}-{:03}.{}", self.prefix, index, extension));
+        }
+        Ok(path)
+    }
+
+    // The ROM's quick save file, which each save state hotkey press overwrites
+    pub fn quick_save_path(&self) -> io::Result<PathBuf> {
+        Ok(data_dir("states")?.join(format!("{}.state", self.prefix)))
+    }
+}
+
+// Creates the directory in the user data directory if it doesn't exist yet
+fn data_dir(dir_name: &str) -> io::Result<PathBuf> {
+    app_dir(AppDataType::UserData, &APP_INFO, dir_name)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+}
@@ -0,0 +1,70 @@
+extern crate png;
+
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use rom_files::RomFiles;
+
+// Writes a frame of 0xAARRGGBB pixels in rows of width to an RGB PNG, with
+// each pixel drawn as a scale by scale block
+pub fn save_png<P: AsRef<Path>>(file_name: P,
+                                pixels: &[u32],
+                                width: usize,
+                                height: usize,
+                                scale: usize)
+                                -> io::Result<()> {
+    let scale = scale.max(1);
+    let mut data = Vec::with_capacity(width * height * scale * scale * 3);
+    for row in pixels.chunks(width).take(height) {
+        for _ in 0..scale {
+            for pixel in row {
+                for _ in 0..scale {
+                    data.push((pixel >> 16) as u8);
+                    data.push((pixel >> 8) as u8);
+                    data.push(*pixel as u8);
+                }
+            }
+        }
+    }
+
+    let file = BufWriter::new(File::create(file_name)?);
+    let mut encoder = png::Encoder::new(file, (width * scale) as u32, (height * scale) as u32);
+    encoder.set_color(png::ColorType::RGB);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    Ok(())
+}
+
+// Where the screenshot hotkey saves to, numbering the files after the ROM
+// e.g. screenshots/game-001.png in the user data directory
+pub struct Screenshots {
+    files: RomFiles,
+    pub scale: usize,
+}
+
+impl Default for Screenshots {
+    fn default() -> Self {
+        Screenshots {
+            files: RomFiles::default(),
+            scale: 1,
+        }
+    }
+}
+
+impl Screenshots {
+    pub fn for_rom<P: AsRef<Path>>(rom_file: P) -> Screenshots {
+        Screenshots {
+            files: RomFiles::for_rom(rom_file),
+            ..Screenshots::default()
+        }
+    }
+
+    // Saves the frame under the next unused name, returning the path used
+    pub fn save(&self, pixels: &[u32], width: usize, height: usize) -> io::Result<PathBuf> {
+        let path = self.files.next_path("screenshots", "png")?;
+        save_png(&path, pixels, width, height, self.scale)?;
+        Ok(path)
+    }
+}
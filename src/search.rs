@@ -0,0 +1,121 @@
+use std::borrow::Cow;
+use mem_map::*;
+use interconnect::Interconnect;
+use symbols::BankedAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchWidth {
+    Byte,
+    Word,
+}
+
+impl SearchWidth {
+    pub fn bytes(&self) -> u16 {
+        match *self {
+            SearchWidth::Byte => 1,
+            SearchWidth::Word => 2,
+        }
+    }
+}
+
+// How a candidate's current value must relate to the value it had at the
+// previous snapshot for it to stay in the search
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchFilter {
+    Equal,
+    Changed,
+    Increased,
+    Decreased,
+    Value(u16),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Candidate {
+    pub addr: BankedAddr,
+    pub value: u16,
+}
+
+// Narrows down the RAM address holding a value, e.g. a game's lives counter,
+// by repeatedly comparing memory against the previous snapshot
+pub struct MemorySearch {
+    width: SearchWidth,
+    candidates: Vec<Candidate>,
+}
+
+impl MemorySearch {
+    // Snapshots WRAM, HRAM and any mapped cartridge RAM, every address starts
+    // as a candidate
+    pub fn new(inter: &Interconnect, width: SearchWidth) -> MemorySearch {
+        let mut regions = vec![(INTERNAL_RAM_START, INTERNAL_RAM_LENGTH),
+                               (HIGH_RAM_START, HIGH_RAM_LENGTH)];
+        let cart_ram = inter.cart_ram_size();
+        if cart_ram > 0 {
+            regions.insert(0, (CRAM_START, cart_ram));
+        }
+
+        let mut candidates = Vec::new();
+        for (start, length) in regions {
+            // Word values are little endian and must fit inside the region
+            for addr in start..(start + length + 1 - width.bytes()) {
+                candidates.push(Candidate {
+                    addr: inter.banked_addr(addr),
+                    value: read_value(inter, addr, width),
+                });
+            }
+        }
+
+        MemorySearch {
+            width: width,
+            candidates: candidates,
+        }
+    }
+
+    pub fn width(&self) -> SearchWidth {
+        self.width
+    }
+
+    pub fn candidates(&self) -> &[Candidate] {
+        &self.candidates
+    }
+
+    // Drops every candidate that doesn't match the filter and takes a new
+    // snapshot of the rest. Candidates in a cart RAM bank that isn't mapped
+    // can't be checked, so they are kept as they are. A value too wide for
+    // the search is rejected without changing anything.
+    pub fn filter(&mut self, inter: &Interconnect, filter: SearchFilter) -> Result<(), Cow<'static, str>> {
+        let width = self.width;
+        if let SearchFilter::Value(v) = filter {
+            if width == SearchWidth::Byte && v > 0xff {
+                return Err(format!("{:x} doesn't fit in a byte, use searchstart 16 to search for words", v).into());
+            }
+        }
+
+        self.candidates.retain(|c| inter.bank_at(c.addr.addr) != c.addr.bank || {
+            let value = read_value(inter, c.addr.addr, width);
+            match filter {
+                SearchFilter::Equal => value == c.value,
+                SearchFilter::Changed => value != c.value,
+                SearchFilter::Increased => value > c.value,
+                SearchFilter::Decreased => value < c.value,
+                SearchFilter::Value(v) => value == v,
+            }
+        });
+
+        for c in &mut self.candidates {
+            if inter.bank_at(c.addr.addr) == c.addr.bank {
+                c.value = read_value(inter, c.addr.addr, width);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn read_value(inter: &Interconnect, addr: u16, width: SearchWidth) -> u16 {
+    match width {
+        SearchWidth::Byte => inter.read_byte(addr) as u16,
+        SearchWidth::Word => {
+            inter.read_byte(addr) as u16 | (inter.read_byte(addr.wrapping_add(1)) as u16) << 8
+        }
+    }
+}
@@ -0,0 +1,231 @@
+extern crate byteorder;
+
+use std::io;
+use self::byteorder::{ByteOrder, LittleEndian};
+
+// Implemented by every part of the machine that a save state covers. Fields
+// are written in a fixed order without any tagging, so the state version has
+// to change whenever any component changes what it writes.
+pub trait SaveState {
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()>;
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter::default()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.data.push(val);
+    }
+
+    pub fn bool(&mut self, val: bool) {
+        self.data.push(val as u8);
+    }
+
+    pub fn u16(&mut self, val: u16) {
+        let mut buf = [0; 2];
+        LittleEndian::write_u16(&mut buf, val);
+        self.data.extend_from_slice(&buf);
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        let mut buf = [0; 4];
+        LittleEndian::write_u32(&mut buf, val);
+        self.data.extend_from_slice(&buf);
+    }
+
+    pub fn u64(&mut self, val: u64) {
+        let mut buf = [0; 8];
+        LittleEndian::write_u64(&mut buf, val);
+        self.data.extend_from_slice(&buf);
+    }
+
+    // Writes a buffer along with its length, for buffers whose size depends on
+    // the cartridge
+    pub fn bytes(&mut self, val: &[u8]) {
+        self.u32(val.len() as u32);
+        self.data.extend_from_slice(val);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader {
+            data: data,
+            pos: 0,
+        }
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> io::Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        Ok(LittleEndian::read_u16(self.take(2)?))
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        Ok(LittleEndian::read_u32(self.take(4)?))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(LittleEndian::read_u64(self.take(8)?))
+    }
+
+    // Reads a buffer written by StateWriter::bytes into one of the same size
+    pub fn bytes(&mut self, val: &mut [u8]) -> io::Result<()> {
+        self.check_len(val.len())?;
+        val.copy_from_slice(self.take(val.len())?);
+        Ok(())
+    }
+
+    fn check_len(&mut self, len: usize) -> io::Result<()> {
+        if self.u32()? as usize != len {
+            return Err(invalid_state("state doesn't match this cartridge's memory sizes"));
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, count: usize) -> io::Result<&'a [u8]> {
+        match self.data.get(self.pos..self.pos + count) {
+            Some(bytes) => {
+                self.pos += count;
+                Ok(bytes)
+            }
+            None => Err(invalid_state("state is truncated")),
+        }
+    }
+}
+
+pub fn invalid_state(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bootrom::Bootrom;
+    use cartridge::Cartridge;
+    use device::{Device, Key};
+    use interconnect::Interconnect;
+    use symbols::Symbols;
+    use vm::VM;
+
+    struct TestDevice;
+
+    impl Device for TestDevice {
+        fn update(&mut self) {}
+
+        fn set_frame_buffer(&mut self, _: &[u32]) {}
+
+        fn key_down(&self, _: Key) -> bool {
+            false
+        }
+
+        fn running(&self) -> bool {
+            true
+        }
+    }
+
+    // A VM running a small ROM that turns the LCD and timer on and then keeps
+    // incrementing WRAM, so that every frame changes the state
+    fn test_vm() -> VM {
+        // LD A,$91; LDH ($40),A; LD A,1; LDH ($50),A
+        let boot_rom = [0x3e, 0x91, 0xe0, 0x40, 0x3e, 0x01, 0xe0, 0x50];
+
+        let mut rom = vec![0; 0x8000];
+        // JP $0150
+        rom[0x08..0x0b].copy_from_slice(&[0xc3, 0x50, 0x01]);
+        // LD A,5; LDH ($07),A; LD HL,$C000; INC (HL); INC L; JR -4
+        rom[0x150..0x15d].copy_from_slice(&[0x3e, 0x05, 0xe0, 0x07, 0x21, 0x00, 0xc0,
+                                            0x34, 0x2c, 0x18, 0xfc, 0x00, 0x00]);
+
+        let inter = Interconnect::new(Bootrom::from_bytes(&boot_rom), Cartridge::from_bytes(&rom));
+        VM::without_console(inter, Symbols::default())
+    }
+
+    #[test]
+    fn values() {
+        let mut writer = StateWriter::new();
+        writer.u8(0x12);
+        writer.bool(true);
+        writer.u16(0x3456);
+        writer.u32(0x789a_bcde);
+        writer.u64(0x0123_4567_89ab_cdef);
+        writer.bytes(&[1, 2, 3]);
+        let data = writer.into_bytes();
+
+        let mut reader = StateReader::new(&data);
+        assert_eq!(reader.u8().unwrap(), 0x12);
+        assert!(reader.bool().unwrap());
+        assert_eq!(reader.u16().unwrap(), 0x3456);
+        assert_eq!(reader.u32().unwrap(), 0x789a_bcde);
+        assert_eq!(reader.u64().unwrap(), 0x0123_4567_89ab_cdef);
+        let mut bytes = [0; 3];
+        reader.bytes(&mut bytes).unwrap();
+        assert_eq!(bytes, [1, 2, 3]);
+        assert!(reader.u8().is_err());
+
+        let mut reader = StateReader::new(&data[data.len() - 7..]);
+        assert!(reader.bytes(&mut [0; 4]).is_err());
+    }
+
+    #[test]
+    fn vm_round_trip() {
+        let mut vm = test_vm();
+        let mut device = TestDevice;
+        vm.run_cycles(&mut device, 100_000);
+
+        let state = vm.save_state();
+        let frame = vm.frame_buffer().to_vec();
+        vm.run_cycles(&mut device, 100_000);
+        let later = vm.save_state();
+        assert!(vm.read_byte(0xc000) != 0);
+        assert!(later != state);
+
+        // Loading puts back exactly what was saved
+        vm.load_state(&state).unwrap();
+        assert!(vm.save_state() == state);
+        assert!(vm.frame_buffer() == &frame[..]);
+
+        // And the machine carries on just as it did the first time
+        vm.run_cycles(&mut device, 100_000);
+        assert!(vm.save_state() == later);
+    }
+
+    #[test]
+    fn vm_rejects_bad_states() {
+        let mut vm = test_vm();
+        vm.run_cycles(&mut TestDevice, 10_000);
+        let state = vm.save_state();
+
+        assert!(vm.load_state(&state[..state.len() - 1]).is_err());
+        assert!(vm.load_state(b"not a state").is_err());
+
+        // The version follows the "SCIMSTATE" magic number
+        let mut other_version = state.clone();
+        other_version[9] ^= 0xff;
+        assert!(vm.load_state(&other_version).is_err());
+        assert!(vm.save_state() == state);
+    }
+}
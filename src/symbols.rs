@@ -1,20 +1,49 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fmt;
 use std::fs::File;
 use std::io::{self, BufReader, BufRead, Write};
 use std::path::{Path, PathBuf};
 
+// An address qualified by the ROM/RAM bank mapped there, matching the
+// bank:addr pairs used by .sym files
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BankedAddr {
+    pub bank: u16,
+    pub addr: u16,
+}
+
+impl BankedAddr {
+    pub fn new(bank: u16, addr: u16) -> Self {
+        BankedAddr {
+            bank: bank,
+            addr: addr,
+        }
+    }
+}
+
+impl fmt::Display for BankedAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:04x}", self.bank, self.addr)
+    }
+}
+
 pub struct Symbols {
-    symbol_map: HashMap<u16, String>,
+    // Several labels can share an address, e.g. a function and the first
+    // local label inside it
+    symbol_map: BTreeMap<BankedAddr, Vec<String>>,
     file_path: PathBuf,
     can_save: bool,
+    modified: bool,
 }
 
 impl Default for Symbols {
     fn default() -> Self {
         Symbols {
-            symbol_map: HashMap::new(),
+            symbol_map: BTreeMap::new(),
             file_path: PathBuf::new(),
             can_save: false,
+            modified: false,
         }
     }
 }
@@ -22,84 +51,120 @@ impl Default for Symbols {
 impl Symbols {
     pub fn new<P: AsRef<Path>>(file_name: P) -> Self {
         Symbols {
-            symbol_map: HashMap::new(),
+            symbol_map: BTreeMap::new(),
             file_path: file_name.as_ref().to_path_buf(),
             can_save: true,
+            modified: false,
         }
     }
 
+    // Loads a symbol file, picking the format from the extension. .map files
+    // are read as RGBDS link maps, anything else as a .sym file.
     pub fn load<P: AsRef<Path>>(file_name: P) -> io::Result<Symbols> {
-        let file = File::open(file_name.as_ref().clone());
+        let file = File::open(file_name.as_ref());
         let file = match file {
             Ok(f) => f,
             Err(_) => return Ok(Self::new(file_name)),
         };
 
         let reader = BufReader::new(&file);
+        let is_map = file_name.as_ref().extension() == Some(OsStr::new("map"));
 
-        let mut symbol_map = HashMap::new();
-        let mut in_symbols = false;
-
-        for line in reader.lines() {
-            let line = line?;
-            if line.trim() == "[labels]" {
-                in_symbols = true;
-                continue;
-            }
-
-            if in_symbols {
-                let mut parts = line.trim().split_whitespace();
-                let mut addr_parts = parts.nth(0).unwrap().split(':');
-
-                let (bank, addr) = (addr_parts.nth(0).unwrap(), addr_parts.nth(0).unwrap());
+        let mut symbols = Self::new(file_name);
+        if is_map {
+            symbols.load_map(reader)?;
+            // We can't write .map files back out, so leave the original alone
+            symbols.can_save = false;
+        } else {
+            symbols.load_sym(reader)?;
+        }
 
-                // TODO - take into account rom/ram banking
-                #[allow(unused_variables)]
-                let bank = bank.parse::<u8>().unwrap();
-                let addr = addr.parse::<u16>().unwrap();
+        Ok(symbols)
+    }
 
-                if symbol_map.contains_key(&addr) {
-                    panic!("Duplicate addresses for symbols: {}", addr);
-                }
-                symbol_map.insert(addr, parts.nth(0).unwrap().to_owned());
+    // Looks for a symbol file alongside the ROM, e.g. game.sym for game.gb.
+    // These are usually written by the game's toolchain, so they're never
+    // saved over, only files given explicitly are.
+    pub fn discover<P: AsRef<Path>>(rom_file: P) -> io::Result<Symbols> {
+        for ext in &["sym", "map"] {
+            let path = rom_file.as_ref().with_extension(ext);
+            if path.is_file() {
+                println!("Loading symbols from {}", path.to_string_lossy());
+                let mut symbols = Self::load(path)?;
+                symbols.can_save = false;
+                return Ok(symbols);
             }
         }
 
-
-
-        Ok(Symbols {
-            symbol_map: symbol_map,
-            file_path: file_name.as_ref().to_path_buf(),
-            can_save: true,
-        })
+        Ok(Self::default())
     }
 
     pub fn save(&self) -> io::Result<()> {
-        if !self.can_save {
+        if !self.modified {
             return Ok(());
         }
+        if !self.can_save {
+            // Say where the new labels went rather than dropping them quietly
+            // when they were added on top of a file
+            if self.file_path.as_os_str().is_empty() {
+                return Ok(());
+            }
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      format!("new labels aren't written to {}, pass a .sym file with --sym to keep them",
+                                              self.file_path.to_string_lossy())));
+        }
 
         let mut file = File::create(&self.file_path)?;
 
         write!(file, "[labels]\n")?;
 
-        for (k, v) in &self.symbol_map {
-            write!(file, "00:{} {}\n", k, v)?;
+        for (k, labels) in &self.symbol_map {
+            for v in labels {
+                write!(file, "{} {}\n", k, v)?;
+            }
         }
 
         Ok(())
     }
 
-    pub fn get(&self, addr: u16) -> Option<&str> {
+    // Returns the label at addr, preferring global labels over local ones
+    pub fn get(&self, addr: BankedAddr) -> Option<&str> {
         match self.symbol_map.get(&addr) {
-            Some(s) => Some(s),
+            Some(labels) => Some(preferred_label(labels)),
             None => None,
         }
     }
 
-    pub fn reverse_get(&self, symbol: &str) -> Option<u16> {
-        for (k, v) in &self.symbol_map {
-            if v == symbol {
+    pub fn get_all(&self, addr: BankedAddr) -> &[String] {
+        match self.symbol_map.get(&addr) {
+            Some(labels) => labels,
+            None => &[],
+        }
+    }
+
+    // Returns the closest label at or before addr in the same bank and memory
+    // region, along with the offset of addr from it
+    pub fn nearest(&self, addr: BankedAddr) -> Option<(&str, u16)> {
+        match self.symbol_map.range(..=addr).next_back() {
+            Some((k, labels)) if k.bank == addr.bank && region(k.addr) == region(addr.addr) => {
+                Some((preferred_label(labels), addr.addr - k.addr))
+            }
+            _ => None,
+        }
+    }
+
+    // Formats addr as label or label+offset
+    pub fn describe(&self, addr: BankedAddr) -> Option<String> {
+        match self.nearest(addr) {
+            Some((label, 0)) => Some(label.to_owned()),
+            Some((label, offset)) => Some(format!("{}+0x{:x}", label, offset)),
+            None => None,
+        }
+    }
+
+    pub fn reverse_get(&self, symbol: &str) -> Option<BankedAddr> {
+        for (k, labels) in &self.symbol_map {
+            if labels.iter().any(|v| v == symbol) {
                 return Some(*k);
             }
         }
@@ -107,16 +172,128 @@ impl Symbols {
         None
     }
 
-    pub fn insert(&mut self, addr: u16, symbol: &str) {
-        if self.symbol_map.contains_key(&addr) {
-            // TODO - better error handling
-            panic!("Duplicate symbol for addr: {}", addr);
+    pub fn insert(&mut self, addr: BankedAddr, symbol: &str) {
+        let labels = self.symbol_map.entry(addr).or_default();
+        if !labels.iter().any(|v| v == symbol) {
+            labels.push(symbol.to_owned());
+            self.modified = true;
         }
+    }
 
-        self.symbol_map.insert(addr, symbol.to_owned());
+    pub fn remove(&mut self, addr: BankedAddr) {
+        if self.symbol_map.remove(&addr).is_some() {
+            self.modified = true;
+        }
     }
 
-    pub fn remove(&mut self, addr: u16) {
-        self.symbol_map.remove(&addr);
+    // RGBDS, wla and no$gmb .sym files all list one "bank:addr label" pair per
+    // line in hex, no$gmb allows the bank to be left off. wla files split the
+    // file into sections of which only [labels] holds symbols.
+    fn load_sym<R: BufRead>(&mut self, reader: R) -> io::Result<()> {
+        let mut in_symbols = true;
+
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.split(';').next().unwrap().trim();
+
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') {
+                in_symbols = line == "[labels]";
+                continue;
+            }
+
+            if in_symbols {
+                let mut parts = line.split_whitespace();
+                let (addr, label) = match (parts.next(), parts.next()) {
+                    (Some(addr), Some(label)) => (addr, label),
+                    _ => return Err(invalid_line(line)),
+                };
+                let addr = match parse_banked_addr(addr) {
+                    Some(addr) => addr,
+                    None => return Err(invalid_line(line)),
+                };
+
+                self.symbol_map.entry(addr).or_default().push(label.to_owned());
+            }
+        }
+
+        Ok(())
     }
+
+    // RGBDS link maps group sections under "ROMX bank #1:" style headers, with
+    // each label inside a section listed as "$4000 = Label"
+    fn load_map<R: BufRead>(&mut self, reader: R) -> io::Result<()> {
+        let mut bank = 0;
+
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+
+            if line.ends_with(':') && line.contains(" bank #") {
+                let number = line.rsplit('#').next().unwrap().trim_end_matches(':');
+                bank = match number.parse::<u16>() {
+                    Ok(b) => b,
+                    Err(_) => return Err(invalid_line(line)),
+                };
+            } else if line.starts_with('$') && line.contains(" = ") {
+                let mut parts = line[1..].splitn(2, " = ");
+                let (addr, label) = (parts.next().unwrap(), parts.next().unwrap().trim());
+                let addr = match u16::from_str_radix(addr.trim(), 16) {
+                    Ok(a) => a,
+                    Err(_) => return Err(invalid_line(line)),
+                };
+
+                self.symbol_map.entry(BankedAddr::new(bank, addr)).or_default().push(label.to_owned());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Local labels are scoped to the previous global label, .loop or Func.loop in
+// RGBDS and _loop or @loop in wla
+fn is_local_label(label: &str) -> bool {
+    label.contains('.') || label.starts_with('_') || label.starts_with('@')
+}
+
+fn preferred_label(labels: &[String]) -> &str {
+    labels.iter().find(|l| !is_local_label(l)).unwrap_or(&labels[0])
+}
+
+// Returns the start of the memory region containing addr so that offsets are
+// never reported relative to a label in an unrelated region
+fn region(addr: u16) -> u16 {
+    match addr {
+        0x0000...0x3fff => 0x0000,
+        0x4000...0x7fff => 0x4000,
+        0x8000...0x9fff => 0x8000,
+        0xa000...0xbfff => 0xa000,
+        0xc000...0xfdff => 0xc000,
+        0xfe00...0xfeff => 0xfe00,
+        0xff00...0xff7f => 0xff00,
+        _ => 0xff80,
+    }
+}
+
+fn parse_banked_addr(s: &str) -> Option<BankedAddr> {
+    let mut parts = s.split(':');
+
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(bank), Some(addr), None) => {
+            match (u16::from_str_radix(bank, 16), u16::from_str_radix(addr, 16)) {
+                (Ok(bank), Ok(addr)) => Some(BankedAddr::new(bank, addr)),
+                _ => None,
+            }
+        }
+        (Some(addr), None, None) => u16::from_str_radix(addr, 16).ok().map(|a| BankedAddr::new(0, a)),
+        _ => None,
+    }
+}
+
+fn invalid_line(line: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   format!("Unrecognised symbol line: {}", line))
 }
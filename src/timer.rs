@@ -1,5 +1,8 @@
 use device::Device;
 use interrupt::{Irq, Interrupt};
+use ioregs::{on_off, print_field};
+use std::io;
+use state::{SaveState, StateReader, StateWriter};
 
 pub struct Timer {
     pub divider: u16,
@@ -77,6 +80,19 @@ impl Timer {
         self.timer_clock_select | (enabled << 2)
     }
 
+    pub fn print_registers(&self) {
+        println!("DIV: {:02x} (internal {:04x}), TIMA: {:02x}, TMA: {:02x}",
+                 self.divider >> 8, self.divider, self.timer_counter, self.timer_modulo);
+        println!("TAC: {:02x}", self.read_reg(0xff07));
+        print_field("2", "Timer", on_off(self.timer_enable));
+        print_field("1-0", "Clock", match self.timer_clock_select {
+            0 => "4096 Hz",
+            1 => "262144 Hz",
+            2 => "65536 Hz",
+            _ => "16384 Hz",
+        });
+    }
+
     fn divider_change(&mut self) -> bool {
         let new_delay = if !self.timer_enable {
             0
@@ -109,3 +125,26 @@ impl Timer {
         self.timer_clock_select = val & 0x3;
     }
 }
+
+impl SaveState for Timer {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.divider);
+        state.u8(self.timer_counter);
+        state.u8(self.timer_modulo);
+        state.u8(self.timer_clock_select);
+        state.bool(self.timer_enable);
+        state.u8(self.tac_edge_delay);
+        state.u8(self.tac_reload_delay);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> io::Result<()> {
+        self.divider = state.u16()?;
+        self.timer_counter = state.u8()?;
+        self.timer_modulo = state.u8()?;
+        self.timer_clock_select = state.u8()?;
+        self.timer_enable = state.bool()?;
+        self.tac_edge_delay = state.u8()?;
+        self.tac_reload_delay = state.u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,295 @@
+use std::borrow::Cow;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use palette::Palette;
+use pixel::{Layer, Pixel};
+use screenshot;
+
+const TILE_COUNT: usize = 384;
+const TILES_PER_ROW: usize = 16;
+const MAP_SIZE: usize = 256;
+const MAP_GAP: usize = 8;
+const SCREEN_WIDTH: usize = 160;
+const SCREEN_HEIGHT: usize = 144;
+
+const OAM_ENTRIES: usize = 40;
+const OAM_COLUMNS: usize = 2;
+const OAM_ROW_HEIGHT: usize = 18;
+// The index, the sprite, then Y, X, tile and flags, each in their own column
+const OAM_FIELD_WIDTH: usize = 12;
+const OAM_COLUMN_WIDTH: usize = OAM_FIELD_WIDTH * 6 + 4;
+
+const VIEWPORT_COLOUR: u32 = 0xffff0000;
+const WINDOW_COLOUR: u32 = 0xff0000ff;
+const TABLE_BACKGROUND: u32 = 0xff202020;
+const TABLE_TEXT: u32 = 0xffe0e0e0;
+// Shows through where sprites are transparent
+const SPRITE_BACKGROUND: u32 = 0xff808080;
+
+// 3x5 hex digits, a row of three bits per line from the top
+const FONT: [u16; 16] = [0x7b6f, 0x2c97, 0x73e7, 0x73cf, 0x5bc9, 0x79cf, 0x79ef, 0x7249,
+                         0x7bef, 0x7bcf, 0x7bed, 0x6bae, 0x7927, 0x6b6e, 0x79e7, 0x79e4];
+
+// Debug views of what the PPU has in memory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum View {
+    // All 384 tiles, drawn with the background palette
+    Tiles,
+    // Both 32x32 tile maps, with the part on screen outlined in red and the
+    // window in blue
+    TileMaps,
+    // The 40 OAM entries: index, sprite, then Y, X, tile and flags in hex
+    Oam,
+}
+
+impl FromStr for View {
+    type Err = Cow<'static, str>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "tiles" => Ok(View::Tiles),
+            "maps" => Ok(View::TileMaps),
+            "oam" => Ok(View::Oam),
+            _ => Err(format!("Unknown viewer {}, expected tiles, maps or oam", s).into()),
+        }
+    }
+}
+
+impl View {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            View::Tiles => "tiles",
+            View::TileMaps => "maps",
+            View::Oam => "oam",
+        }
+    }
+
+    // Views are always the same size, so they can be shown in fixed windows
+    pub fn dimensions(&self) -> (usize, usize) {
+        match *self {
+            View::Tiles => (TILES_PER_ROW * 8, TILE_COUNT / TILES_PER_ROW * 8),
+            View::TileMaps => (MAP_SIZE * 2 + MAP_GAP, MAP_SIZE),
+            View::Oam => {
+                (OAM_COLUMN_WIDTH * OAM_COLUMNS,
+                 OAM_ENTRIES / OAM_COLUMNS * OAM_ROW_HEIGHT)
+            }
+        }
+    }
+
+    pub fn render(&self, state: &VideoState) -> Image {
+        match *self {
+            View::Tiles => state.tiles(),
+            View::TileMaps => state.tile_maps(),
+            View::Oam => state.oam_table(),
+        }
+    }
+}
+
+// A frame of 0xAARRGGBB pixels in rows of width
+pub struct Image {
+    pub pixels: Vec<u32>,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Image {
+    fn new(width: usize, height: usize, colour: u32) -> Image {
+        Image {
+            pixels: vec![colour; width * height],
+            width: width,
+            height: height,
+        }
+    }
+
+    pub fn save_png<P: AsRef<Path>>(&self, file_name: P) -> io::Result<()> {
+        screenshot::save_png(file_name, &self.pixels, self.width, self.height, 1)
+    }
+
+    fn set(&mut self, x: usize, y: usize, colour: u32) {
+        if x < self.width && y < self.height {
+            self.pixels[y * self.width + x] = colour;
+        }
+    }
+
+    fn draw_hex(&mut self, x: usize, y: usize, val: u8) {
+        for (i, digit) in [val >> 4, val & 0x0f].iter().enumerate() {
+            let glyph = FONT[*digit as usize];
+            for row in 0..5 {
+                for col in 0..3 {
+                    if glyph & (1 << (14 - (row * 3 + col))) != 0 {
+                        self.set(x + i * 4 + col, y + row, TABLE_TEXT);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// VRAM, OAM and the PPU registers the views are drawn from, along with the
+// palette the screen is shown in. Borrowed from the PPU, so building one is
+// cheap enough to do every frame.
+#[derive(Clone, Copy)]
+pub struct VideoState<'a> {
+    pub vram: &'a [u8],
+    pub oam: &'a [u8],
+    pub lcdc: u8,
+    pub scy: u8,
+    pub scx: u8,
+    pub wy: u8,
+    pub wx: u8,
+    pub bgp: u8,
+    pub obp0: u8,
+    pub obp1: u8,
+    pub palette: Palette,
+}
+
+impl<'a> VideoState<'a> {
+    pub fn tiles(&self) -> Image {
+        let (width, height) = View::Tiles.dimensions();
+        let mut image = Image::new(width, height, 0);
+
+        for tile in 0..TILE_COUNT {
+            let (tile_x, tile_y) = ((tile % TILES_PER_ROW) * 8, (tile / TILES_PER_ROW) * 8);
+            for row in 0..8 {
+                for col in 0..8 {
+                    let colour = self.background_colour(tile * 16, row, col);
+                    image.set(tile_x + col, tile_y + row, colour);
+                }
+            }
+        }
+
+        image
+    }
+
+    pub fn tile_maps(&self) -> Image {
+        let (width, height) = View::TileMaps.dimensions();
+        let mut image = Image::new(width, height, TABLE_BACKGROUND);
+
+        let bg_map = if self.lcdc & (1 << 3) != 0 { 1 } else { 0 };
+        let window_map = if self.lcdc & (1 << 6) != 0 { 1 } else { 0 };
+
+        for map in 0..2 {
+            let left = map * (MAP_SIZE + MAP_GAP);
+            for y in 0..MAP_SIZE {
+                for x in 0..MAP_SIZE {
+                    let tile_index = self.vram[0x1800 + map * 0x400 + (y / 8) * 32 + x / 8];
+                    let colour = self.background_colour(self.tile_offset(tile_index), y % 8, x % 8);
+                    image.set(left + x, y, colour);
+                }
+            }
+        }
+
+        // The screen wraps around the background map
+        let left = bg_map * (MAP_SIZE + MAP_GAP);
+        let (scx, scy) = (self.scx as usize, self.scy as usize);
+        for x in 0..SCREEN_WIDTH {
+            image.set(left + (scx + x) % MAP_SIZE, scy, VIEWPORT_COLOUR);
+            image.set(left + (scx + x) % MAP_SIZE, (scy + SCREEN_HEIGHT - 1) % MAP_SIZE, VIEWPORT_COLOUR);
+        }
+        for y in 0..SCREEN_HEIGHT {
+            image.set(left + scx, (scy + y) % MAP_SIZE, VIEWPORT_COLOUR);
+            image.set(left + (scx + SCREEN_WIDTH - 1) % MAP_SIZE, (scy + y) % MAP_SIZE, VIEWPORT_COLOUR);
+        }
+
+        // The window shows the top left of its map, as much as fits between
+        // its position and the bottom right of the screen
+        let window_x = self.wx as usize;
+        let window_y = self.wy as usize;
+        if self.lcdc & (1 << 5) != 0 && window_x < SCREEN_WIDTH + 7 && window_y < SCREEN_HEIGHT {
+            let left = window_map * (MAP_SIZE + MAP_GAP);
+            let width = SCREEN_WIDTH + 7 - window_x.max(7);
+            let height = SCREEN_HEIGHT - window_y;
+            for x in 0..width {
+                image.set(left + x, 0, WINDOW_COLOUR);
+                image.set(left + x, height - 1, WINDOW_COLOUR);
+            }
+            for y in 0..height {
+                image.set(left, y, WINDOW_COLOUR);
+                image.set(left + width - 1, y, WINDOW_COLOUR);
+            }
+        }
+
+        image
+    }
+
+    pub fn oam_table(&self) -> Image {
+        let (width, height) = View::Oam.dimensions();
+        let mut image = Image::new(width, height, TABLE_BACKGROUND);
+        let tall = self.lcdc & (1 << 2) != 0;
+        let rows = OAM_ENTRIES / OAM_COLUMNS;
+
+        for entry in 0..OAM_ENTRIES {
+            let left = (entry / rows) * OAM_COLUMN_WIDTH;
+            let top = (entry % rows) * OAM_ROW_HEIGHT + 1;
+            let attributes = &self.oam[entry * 4..entry * 4 + 4];
+
+            image.draw_hex(left, top + 5, entry as u8);
+            for (i, val) in attributes.iter().enumerate() {
+                image.draw_hex(left + OAM_FIELD_WIDTH * (i + 2), top + 5, *val);
+            }
+
+            let (y_flip, x_flip) = (attributes[3] & (1 << 6) != 0, attributes[3] & (1 << 5) != 0);
+            let (obp, layer) = if attributes[3] & (1 << 4) != 0 {
+                (self.obp1, Layer::Obj1)
+            } else {
+                (self.obp0, Layer::Obj0)
+            };
+            let (tile, height) = if tall {
+                (attributes[2] & 0xfe, 16)
+            } else {
+                (attributes[2], 8)
+            };
+
+            for row in 0..height {
+                for col in 0..8 {
+                    let tile_row = if y_flip { height - 1 - row } else { row };
+                    let tile_col = if x_flip { 7 - col } else { col };
+                    let colour_index = self.tile_colour(tile as usize * 16, tile_row, tile_col);
+                    let colour = if colour_index == 0 {
+                        SPRITE_BACKGROUND
+                    } else {
+                        self.palette.colour(Pixel {
+                            shade: shade(obp, colour_index),
+                            layer: layer,
+                        })
+                    };
+                    image.set(left + OAM_FIELD_WIDTH + col, top + row, colour);
+                }
+            }
+        }
+
+        image
+    }
+
+    // The offset in VRAM of a background or window tile, which depends on
+    // which tile data area LCDC selects
+    fn tile_offset(&self, tile_index: u8) -> usize {
+        if self.lcdc & (1 << 4) != 0 {
+            tile_index as usize * 16
+        } else {
+            (((tile_index as i8) as isize) + 256) as usize * 16
+        }
+    }
+
+    // The 2 bit colour number of a pixel, before any palette. Rows past the
+    // first 8 carry on into the next tile.
+    fn tile_colour(&self, tile_offset: usize, row: usize, col: usize) -> u8 {
+        let offset = tile_offset + row * 2;
+        let upper = self.vram[offset + 1] >> (7 - col) & 1;
+        let lower = self.vram[offset] >> (7 - col) & 1;
+        upper << 1 | lower
+    }
+
+    fn background_colour(&self, tile_offset: usize, row: usize, col: usize) -> u32 {
+        self.palette.colour(Pixel {
+            shade: shade(self.bgp, self.tile_colour(tile_offset, row, col)),
+            layer: Layer::Background,
+        })
+    }
+}
+
+// Looks a colour number up in a palette register
+fn shade(palette_data: u8, colour: u8) -> u8 {
+    (palette_data >> (colour * 2)) & 0x03
+}
@@ -1,26 +1,119 @@
+extern crate crc;
+
 use std::thread;
 use std::sync::mpsc::{channel, Receiver};
-use std::io::{stdin, stdout, Write};
+use std::fs::File;
+use std::io::{self, stdin, stdout, Read, Write};
 use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::borrow::Cow;
+use std::str::FromStr;
 use interconnect::Interconnect;
+use cheats::Cheats;
 use cpu::{Cpu, Bus};
 use device::Device;
 use time::{self, SteadyTime};
+use self::crc::crc32::{self, Hasher32};
 use command::*;
 use opcodes::*;
-use symbols::Symbols;
+use symbols::{BankedAddr, Symbols};
 use events::Event;
+use input::{Buttons, Hotkey};
+use movie::{Movie, MovieHeader, MovieState, PlaybackDevice};
+use palette::Palette;
+use pixel::Pixel;
+use profiler::Profiler;
+use recorder::Recorder;
+use audio::Resampler;
+use rom_files::RomFiles;
+use screenshot::{self, Screenshots};
+use search::{Candidate, MemorySearch, SearchWidth};
+use state::{SaveState, StateReader, StateWriter, invalid_state};
+use viewer::VideoState;
 
 // The Game Boy runs at 4194304 Hz which is 8192 clocks every 1953125 nanoseconds
 const SYNC_PERIOD_NS: i64 = 1953125;
 const SYNC_PERIOD_CLOCKS: i64 = 8192;
 
+// Unthrottled runs this many sync periods between checks on the window
+const UNTHROTTLED_SYNC_PERIODS: i64 = 64;
+
+const SLOW_MOTION_SPEED: f64 = 0.5;
+
+// run_frame gives up after this many cycles if the LCD is off and there's no
+// VBlank to stop at
+const CYCLES_PER_FRAME: u32 = 70224;
+
+const STATE_MAGIC: &[u8] = b"SCIMSTATE";
+const STATE_VERSION: u8 = 4;
+
+// Only list a search's results once there are few enough to read through
+const MAX_LISTED_RESULTS: usize = 32;
+
+// Games don't always return from every call (e.g. resetting SP to restart the
+// main loop) so cap the tracked depth rather than growing forever
+const MAX_CALL_DEPTH: usize = 1024;
+
 #[derive(PartialEq, Eq, Debug)]
 enum Mode {
     Running,
+    // Stopped without the debugger, the window stays live for hotkeys
+    Paused,
     Debugging,
 }
 
+// How fast emulation runs compared to a real Game Boy
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Speed {
+    Multiplier(f64),
+    Unthrottled,
+}
+
+impl Speed {
+    // Multipliers so big a sync period would be under a nanosecond can't be
+    // synced to real time, so they run unthrottled
+    pub fn from_multiplier(m: f64) -> Speed {
+        if m >= SYNC_PERIOD_NS as f64 {
+            Speed::Unthrottled
+        } else {
+            Speed::Multiplier(m)
+        }
+    }
+}
+
+impl FromStr for Speed {
+    type Err = Cow<'static, str>;
+
+    // Either a multiplier such as 2 or 0.5, or max for unthrottled
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "max" {
+            return Ok(Speed::Unthrottled);
+        }
+
+        match s.parse::<f64>() {
+            Ok(m) if m > 0.0 && m.is_finite() => Ok(Speed::from_multiplier(m)),
+            _ => Err(format!("Invalid speed {}, expected a multiplier or max", s).into()),
+        }
+    }
+}
+
+// What happened during a run_frame or run_cycles call
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RunStatus {
+    pub cycles: u32,
+    // The PPU finished a frame, which frame_buffer holds
+    pub frame_complete: bool,
+    // A breakpoint or watchpoint stopped execution early
+    pub breakpoint: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CallFrame {
+    pub target: BankedAddr,
+    pub return_addr: BankedAddr,
+    pub interrupt: bool,
+}
+
 pub struct VM {
     cpu: Cpu,
     inter: Interconnect,
@@ -28,16 +121,33 @@ pub struct VM {
     mode: Mode,
     start_time: SteadyTime,
 
-    breakpoints: HashSet<u16>,
-    temp_breakpoints: HashSet<u16>,
+    breakpoints: HashSet<BankedAddr>,
+    temp_breakpoints: HashSet<BankedAddr>,
+    call_stack: Vec<CallFrame>,
 
     cursor: u16,
     last_command: Option<Command>,
-    stdin_receiver: Receiver<String>,
+    // Debugger commands typed on stdin, None when there's no console
+    stdin_receiver: Option<Receiver<String>>,
 
     symbols: Symbols,
+    profiler: Option<Profiler>,
+    search: Option<MemorySearch>,
+    movie: Option<MovieState>,
+    // Describes the machine for movies started with the recordmovie command
+    movie_header: Option<MovieHeader>,
+    screenshots: Screenshots,
+    rom_files: RomFiles,
+    recorder: Option<Recorder>,
+    audio: Option<Resampler>,
+
+    speed: Speed,
+    // Set by the fast-forward and slow-motion hotkeys, overriding speed
+    speed_override: Option<Speed>,
+    frames_to_advance: usize,
 
     total_cycles: u64,
+    frame_count: u64,
 }
 
 impl VM {
@@ -50,55 +160,200 @@ impl VM {
                           stdin_sender.send(read_stdin()).unwrap();
                       });
 
+        let mut vm = VM::without_console(interconnect, symbols);
+        vm.stdin_receiver = Some(stdin_receiver);
+
+        if start_in_debug {
+            vm.mode = Mode::Debugging;
+            vm.disassemble_instruction();
+            vm.print_cursor();
+        }
+        vm
+    }
+
+    // Creates a VM without the stdin debugger console, for frontends that
+    // drive it with run_frame or run_cycles
+    pub fn without_console(interconnect: Interconnect, symbols: Symbols) -> VM {
         let mut cpu = Cpu::new();
-        let interconnect = interconnect;
         cpu.pc = 0x0000;
 
         let cursor = cpu.pc;
 
-        let vm = VM {
+        VM {
             inter: interconnect,
             cpu: cpu,
 
-            mode: if start_in_debug{ Mode::Debugging } else { Mode::Running },
+            mode: Mode::Running,
             start_time: SteadyTime::now(),
 
             breakpoints: HashSet::new(),
             temp_breakpoints: HashSet::new(),
+            call_stack: Vec::new(),
 
             cursor: cursor,
             last_command: None,
-            stdin_receiver: stdin_receiver,
+            stdin_receiver: None,
 
             symbols: symbols,
+            profiler: None,
+            search: None,
+            movie: None,
+            movie_header: None,
+            screenshots: Screenshots::default(),
+            rom_files: RomFiles::default(),
+            recorder: None,
+            audio: None,
+
+            speed: Speed::Multiplier(1.0),
+            speed_override: None,
+            frames_to_advance: 0,
 
             total_cycles: 0,
-        };
-        if vm.mode == Mode::Debugging {
-            vm.disassemble_instruction();
-            vm.print_cursor();
+            frame_count: 0,
         }
-        vm
+    }
+
+    // Starts recording a profile, written to the given files when the VM stops
+    pub fn enable_profiling(&mut self, report_path: Option<&Path>, stacks_path: Option<&Path>) {
+        self.profiler = Some(Profiler::new(report_path, stacks_path));
+    }
+
+    pub fn set_screenshots(&mut self, screenshots: Screenshots) {
+        self.screenshots = screenshots;
+    }
+
+    pub fn set_rom_files(&mut self, rom_files: RomFiles) {
+        self.rom_files = rom_files;
+    }
+
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.inter.set_palette(palette);
+    }
+
+    // Starts collecting sound at the given sample rate, for frontends to take
+    // with audio_samples after each run_frame or run_cycles
+    pub fn enable_audio(&mut self, sample_rate: u32) {
+        self.audio = Some(Resampler::new(sample_rate));
+    }
+
+    // The stereo samples, left and right interleaved, generated since the
+    // last clear_audio_samples. Empty unless enable_audio has been called.
+    pub fn audio_samples(&self) -> &[i16] {
+        match self.audio {
+            Some(ref audio) => audio.samples(),
+            None => &[],
+        }
+    }
+
+    pub fn clear_audio_samples(&mut self) {
+        if let Some(ref mut audio) = self.audio {
+            audio.clear();
+        }
+    }
+
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+        self.speed_override = None;
+        self.start_time = SteadyTime::now();
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed_override.unwrap_or(self.speed)
+    }
+
+    pub fn set_movie_header(&mut self, header: MovieHeader) {
+        self.movie_header = Some(header);
+    }
+
+    // Records the joypad state every frame, written to the given file when
+    // the VM stops. Once the machine has run, the movie starts from a save
+    // state of it rather than from power-on.
+    pub fn record_movie(&mut self, mut movie: Movie, path: &Path) {
+        if self.total_cycles > 0 {
+            movie.start_state = Some(self.save_state());
+        }
+        self.movie = Some(MovieState::Recording {
+            movie: movie,
+            path: path.to_path_buf(),
+        });
+    }
+
+    // Replaces the device's buttons with the movie's until it runs out,
+    // first loading the state the movie starts from if it has one
+    pub fn play_movie(&mut self, movie: Movie) -> io::Result<()> {
+        if let Some(ref state) = movie.start_state {
+            self.load_state(state)?;
+        }
+        self.movie = Some(MovieState::Playing {
+            movie: movie,
+            frame: 0,
+            desynced: false,
+        });
+        Ok(())
     }
 
     pub fn step(&mut self, device: &mut Device) -> (u16, bool) {
+        let start_pc = self.inter.banked_addr(self.cpu.pc);
+        let halted = self.cpu.halted == 1;
+
         let mut events = Vec::new();
-        let mut bus = Bus{
-            interconnect: &mut self.inter,
-            device: device,
-            events: &mut events,
-            cycles: 0,
+        let cycles = match self.movie {
+            Some(MovieState::Playing { ref movie, frame, .. }) => {
+                let mut playback = PlaybackDevice {
+                    device: device,
+                    movie: movie,
+                    frame: frame,
+                };
+                step_cpu(&mut self.cpu, &mut self.inter, &mut playback, &mut events)
+            }
+            _ => step_cpu(&mut self.cpu, &mut self.inter, device, &mut events),
         };
-        self.cpu.step(&mut bus);
 
-        let breakpoint = self.breakpoints.contains(&self.cpu.pc) || self.temp_breakpoints.contains(&self.cpu.pc);
-        let watchpoint = bus.events.iter().any(|x| *x == Event::Watchpoint);
+        let pc = self.inter.banked_addr(self.cpu.pc);
+        let breakpoint = self.breakpoints.contains(&pc) || self.temp_breakpoints.contains(&pc);
+        let watchpoint = events.iter().any(|x| *x == Event::Watchpoint);
 
-        self.temp_breakpoints.remove(&self.cpu.pc);
+        self.temp_breakpoints.remove(&pc);
 
-        self.total_cycles += bus.cycles as u64;
+        if self.profiler.is_some() {
+            // Interrupt dispatch is charged to the handler rather than the
+            // instruction that was interrupted
+            let dispatched = events.iter().filter_map(|e| match *e {
+                Event::Interrupt(addr) => Some(addr),
+                _ => None,
+            }).next();
+            let in_interrupt = dispatched.is_some() || self.call_stack.iter().any(|f| f.interrupt);
+            let profile_pc = dispatched.map_or(start_pc, |addr| BankedAddr::new(0, addr));
+
+            if let Some(ref mut profiler) = self.profiler {
+                profiler.record(profile_pc, cycles, halted, in_interrupt, &self.call_stack);
+            }
+        }
+
+        self.update_call_stack(&events);
+
+        for event in &events {
+            match *event {
+                Event::Hotkey(hotkey) => self.handle_hotkey(hotkey),
+                Event::InputPolled(buttons) => self.update_movie(buttons),
+                Event::VBlank => self.frame_count += 1,
+                _ => {}
+            }
+        }
+
+        self.total_cycles += cycles as u64;
+
+        if events.contains(&Event::VBlank) {
+            device.set_video_state(&self.inter.video_state());
+        }
+        if let Some(ref mut audio) = self.audio {
+            audio.step(cycles, self.inter.audio_output());
+        }
+        if self.recorder.is_some() {
+            self.update_recording(cycles, events.contains(&Event::VBlank));
+        }
 
-        (bus.cycles, breakpoint || watchpoint)
+        (cycles, breakpoint || watchpoint)
     }
 
     pub fn run(&mut self, device: &mut Device) {
@@ -113,23 +368,53 @@ impl VM {
                     nsecs_elapsed += elapsed.num_nanoseconds().expect("Loop took too long");
                     self.start_time = now;
 
-                    while device.running() && nsecs_elapsed > SYNC_PERIOD_NS {
+                    // Slower or faster speeds stretch or shrink the real time
+                    // each period of clocks is synced to
+                    let sync_periods = match self.speed() {
+                        Speed::Multiplier(m) => {
+                            let period_ns = ((SYNC_PERIOD_NS as f64 / m) as i64).max(1);
+                            let periods = nsecs_elapsed / period_ns;
+                            nsecs_elapsed -= periods * period_ns;
+                            periods
+                        }
+                        Speed::Unthrottled => {
+                            nsecs_elapsed = 0;
+                            UNTHROTTLED_SYNC_PERIODS
+                        }
+                    };
+
+                    for _ in 0..sync_periods {
                         cycles_to_run += SYNC_PERIOD_CLOCKS;
                         while device.running() && cycles_to_run > 0 {
                             let (cycles_run, start_debugger) = self.step(device);
                             if start_debugger {
-                                self.mode = Mode::Debugging;
-                                cycles_to_run = 0;
-                                self.cursor = self.cpu.pc;
-                                self.print_cursor();
-                                nsecs_elapsed = 0;
+                                self.break_into_debugger();
+                            }
+                            if self.mode != Mode::Running {
                                 break;
                             }
                             cycles_to_run -= cycles_run as i64;
                             device.update();
                         }
-                        nsecs_elapsed -= SYNC_PERIOD_NS;
+                        if self.mode != Mode::Running {
+                            cycles_to_run = 0;
+                            nsecs_elapsed = 0;
+                            break;
+                        }
+                    }
+                }
+                Mode::Paused => {
+                    for hotkey in self.inter.poll_hotkeys(device) {
+                        self.handle_hotkey(hotkey);
+                    }
+                    while self.mode == Mode::Paused && self.frames_to_advance > 0 {
+                        self.frames_to_advance -= 1;
+                        if self.run_frame(device).breakpoint {
+                            self.break_into_debugger();
+                        }
                     }
+
+                    device.update();
                 }
                 Mode::Debugging => {
                     if self.run_debug_commands(device) {
@@ -140,10 +425,173 @@ impl VM {
                 }
             }
 
-            thread::sleep(time::Duration::milliseconds(3).to_std().unwrap());
+            if self.mode != Mode::Running || self.speed() != Speed::Unthrottled {
+                thread::sleep(time::Duration::milliseconds(3).to_std().unwrap());
+            }
+        }
+
+        // Everything is saved even if something before it fails, so that a
+        // bad symbol file can't lose a movie or leave a recording unfinished
+        let results = vec![
+            ("symbols", self.symbols.save()),
+            ("cheats", self.inter.cheats.save()),
+            ("profile", self.save_profile()),
+            ("movie", self.save_movie()),
+            ("recording", self.stop_recording()),
+        ];
+        for (what, result) in results {
+            if let Err(e) = result {
+                println!("Failed to save {}: {}", what, e);
+            }
+        }
+    }
+
+    // Writes out the profile, if enabled. run() does this when it finishes,
+    // callers driving step() themselves need to call it directly.
+    pub fn save_profile(&mut self) -> io::Result<()> {
+        match self.profiler {
+            Some(ref mut profiler) => profiler.save(&self.symbols),
+            None => Ok(()),
+        }
+    }
+
+    // Writes out the movie being recorded, if there is one
+    pub fn save_movie(&self) -> io::Result<()> {
+        match self.movie {
+            Some(MovieState::Recording { ref movie, ref path }) => {
+                println!("Saving {} frame movie to {}", movie.len(), path.to_string_lossy());
+                movie.save(path)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Starts recording the screen to the given .y4m file and the sound to a
+    // .wav file alongside it, stopping any recording already running
+    pub fn start_recording<P: AsRef<Path>>(&mut self, video_file: P) -> io::Result<()> {
+        self.stop_recording()?;
+
+        let (width, height) = self.get_dimensions();
+        let mut recorder = Recorder::create(video_file, width, height)?;
+        recorder.set_frame(self.frame_buffer());
+        println!("Recording to {}", recorder.video_path().to_string_lossy());
+        self.recorder = Some(recorder);
+        Ok(())
+    }
+
+    // Finishes the recording, if there is one. run() does this when it
+    // finishes, callers driving step() themselves need to call it directly.
+    pub fn stop_recording(&mut self) -> io::Result<()> {
+        match self.recorder.take() {
+            Some(recorder) => {
+                println!("Saved recording to {}", recorder.video_path().to_string_lossy());
+                recorder.finish()
+            }
+            None => Ok(()),
+        }
+    }
+
+    // Runs until the next VBlank starts, or for a frame's worth of cycles if
+    // the LCD is off, without syncing to real time
+    pub fn run_frame(&mut self, device: &mut Device) -> RunStatus {
+        self.run_until(device, CYCLES_PER_FRAME, true)
+    }
+
+    // Runs for at least the given number of cycles, finishing the instruction
+    // that crosses the limit, without syncing to real time
+    pub fn run_cycles(&mut self, device: &mut Device, cycles: u32) -> RunStatus {
+        self.run_until(device, cycles, false)
+    }
+
+    // Snapshots the whole machine. The cartridge ROM isn't included, so the
+    // state can only be loaded with the same ROM.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        for byte in STATE_MAGIC {
+            state.u8(*byte);
         }
+        state.u8(STATE_VERSION);
+
+        // The cartridge comes first so a state for another ROM is rejected
+        // before anything is loaded
+        self.inter.save_state(&mut state);
+        self.cpu.save_state(&mut state);
+        state.u64(self.total_cycles);
+        state.u64(self.frame_count);
 
-        self.symbols.save().unwrap();
+        state.into_bytes()
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> io::Result<()> {
+        if !data.starts_with(STATE_MAGIC) {
+            return Err(invalid_state("not a save state"));
+        }
+        if data.get(STATE_MAGIC.len()) != Some(&STATE_VERSION) {
+            return Err(invalid_state("save state is from an incompatible version"));
+        }
+        // Every field has a fixed size for a given cartridge, so checking the
+        // size up front stops a bad state being half loaded
+        if data.len() != self.save_state().len() {
+            return Err(invalid_state("save state is for a different cartridge"));
+        }
+
+        let mut state = StateReader::new(&data[STATE_MAGIC.len() + 1..]);
+        self.inter.load_state(&mut state)?;
+        self.cpu.load_state(&mut state)?;
+        self.total_cycles = state.u64()?;
+        self.frame_count = state.u64()?;
+
+        self.call_stack.clear();
+        self.cursor = self.cpu.pc;
+        Ok(())
+    }
+
+    // Writes the current frame to a PNG, scaled up as the screenshot hotkey
+    // would
+    pub fn save_screenshot<P: AsRef<Path>>(&self, file_name: P) -> io::Result<()> {
+        let (width, height) = self.get_dimensions();
+        screenshot::save_png(file_name, self.frame_buffer(), width, height, self.screenshots.scale)
+    }
+
+    // The frame being drawn, one 0xAARRGGBB pixel per u32 in rows of
+    // get_dimensions().0 pixels. Holds a complete frame after run_frame
+    // reports one, the PPU draws over it line by line otherwise.
+    pub fn frame_buffer(&self) -> &[u32] {
+        self.inter.frame_buffer()
+    }
+
+    // The same frame as the shades and layers the PPU drew, which unlike the
+    // colours don't change with how the frame is displayed
+    pub fn frame_pixels(&self) -> &[Pixel] {
+        self.inter.frame_pixels()
+    }
+
+    // VRAM, OAM and the PPU registers, for drawing viewer::View debug views
+    pub fn video_state(&self) -> VideoState<'_> {
+        self.inter.video_state()
+    }
+
+    // The cartridge RAM, for frontends that load and store battery saves
+    // themselves. Empty for cartridges without RAM.
+    pub fn cart_ram_mut(&mut self) -> &mut [u8] {
+        self.inter.cart_ram_mut()
+    }
+
+    pub fn work_ram_mut(&mut self) -> &mut [u8] {
+        self.inter.work_ram_mut()
+    }
+
+    pub fn cheats_mut(&mut self) -> &mut Cheats {
+        &mut self.inter.cheats
+    }
+
+    // Memory as the CPU sees it, so writes to ROM go to the MBC registers
+    pub fn read_byte(&self, addr: u16) -> u8 {
+        self.inter.read_byte(addr)
+    }
+
+    pub fn write_byte(&mut self, addr: u16, val: u8) {
+        self.inter.write_byte(addr, val);
     }
 
     pub fn get_next_instruction(&self) -> u8 {
@@ -160,7 +608,12 @@ impl VM {
 
     #[cfg_attr(feature = "cargo-clippy", allow(match_same_arms))]
     fn run_debug_commands(&mut self, device: &mut Device) -> bool {
-        while let Ok(command_string) = self.stdin_receiver.try_recv() {
+        let commands: Vec<String> = match self.stdin_receiver {
+            Some(ref receiver) => receiver.try_iter().collect(),
+            None => Vec::new(),
+        };
+
+        for command_string in commands {
             let command = match (command_string.parse(), self.last_command.clone()) {
                 (Ok(Command::Repeat), Some(c)) => Ok(c),
                 (Ok(Command::Repeat), None) => Err("No last command".into()),
@@ -177,17 +630,9 @@ impl VM {
                     println!("HL: {:04x}", self.cpu.hl());
                     println!("SP: {:04x}", self.cpu.sp);
                 }
-                Ok(Command::ShowIORegs) => {
+                Ok(Command::ShowIORegs(group)) => {
                     println!("Total Cycles: {}", self.total_cycles);
-                    // TODO - more complete list
-                    let timer = &self.inter.get_timer();
-                    println!("Timer:");
-                    println!("DIV: {:04x}, TIMA: {:02x}, TMA: {:02x}, TAC: {:02x}",
-                             timer.divider, timer.timer_counter, timer.timer_modulo, timer.timer_control());
-                    println!("Interrupts:");
-                    println!("IE: {:02x}, IF: {:02x}", self.inter.ie_register, self.inter.if_register);
-                    println!("OAM DMA");
-                    println!("Source: {:04x}, State: {:?}, Slot: {:02x}", self.inter.dma_source, self.inter.dma_state, self.inter.dma_slot);
+                    self.inter.print_io_registers(group);
                 }
                 Ok(Command::Step(count)) => {
                     for _ in 0..count {
@@ -196,6 +641,57 @@ impl VM {
                         self.disassemble_instruction();
                     }
                 }
+                Ok(Command::Next(count)) => {
+                    for _ in 0..count {
+                        let stopped = self.step_over(device);
+                        self.cursor = self.cpu.pc;
+                        self.disassemble_instruction();
+                        if stopped {
+                            break;
+                        }
+                    }
+                }
+                Ok(Command::Finish) => {
+                    if let Some(frame) = self.call_stack.last().cloned() {
+                        println!("Run till exit from {} {}",
+                                 frame.target,
+                                 self.symbols.describe(frame.target).unwrap_or_default());
+                        self.finish(device);
+                        self.cursor = self.cpu.pc;
+                        self.disassemble_instruction();
+                    } else {
+                        println!("Not inside a call");
+                    }
+                }
+                Ok(Command::Backtrace) => {
+                    self.print_backtrace();
+                }
+                Ok(Command::Profile) => {
+                    if let Some(ref profiler) = self.profiler {
+                        profiler.write_report(&mut stdout(), &self.symbols).unwrap();
+                    } else {
+                        println!("Profiling is not enabled");
+                    }
+                }
+                Ok(Command::FrameAdvance(count)) => {
+                    for _ in 0..count {
+                        if self.run_frame(device).breakpoint {
+                            break;
+                        }
+                    }
+                    println!("Frame {}", self.frame_count);
+                    self.cursor = self.cpu.pc;
+                    self.disassemble_instruction();
+                }
+                Ok(Command::Speed(Some(Speed::Multiplier(m)))) if m <= 0.0 => {
+                    println!("Speed must be greater than zero");
+                }
+                Ok(Command::Speed(speed)) => {
+                    if let Some(speed) = speed {
+                        self.set_speed(speed);
+                    }
+                    print_speed(self.speed());
+                }
                 Ok(Command::Continue) => {
                     self.mode = Mode::Running;
                     self.start_time = SteadyTime::now();
@@ -232,14 +728,15 @@ impl VM {
                 }
                 Ok(Command::Breakpoint) => {
                     for addr in &self.breakpoints {
-                        println!("* 0x{:04x}", addr);
+                        println!("* {}", addr);
                     }
                     println!("");
                     for addr in &self.temp_breakpoints {
-                        println!("# 0x{:04x}", addr);
+                        println!("# {}", addr);
                     }
                 }
-                Ok(Command::AddBreakpoint(addr)) => {
+                Ok(Command::AddBreakpoint(bank, addr)) => {
+                    let addr = self.resolve_addr(bank, addr);
                     self.breakpoints.insert(addr);
                 }
                 Ok(Command::AddSymBreakpoint(ref sym)) => {
@@ -249,7 +746,8 @@ impl VM {
                         println!("Unrecognized symbol {}", sym);
                     }
                 }
-                Ok(Command::AddTempBreakpoint(addr)) => {
+                Ok(Command::AddTempBreakpoint(bank, addr)) => {
+                    let addr = self.resolve_addr(bank, addr);
                     self.temp_breakpoints.insert(addr);
                 }
                 Ok(Command::AddTempSymBreakpoint(ref sym)) => {
@@ -259,14 +757,16 @@ impl VM {
                         println!("Unrecognized symbol {}", sym);
                     }
                 }
-                Ok(Command::RemoveBreakpoint(addr)) => {
+                Ok(Command::RemoveBreakpoint(bank, addr)) => {
+                    let addr = self.resolve_addr(bank, addr);
                     if !self.breakpoints.remove(&addr) {
-                        println!("Breakpoint at 0x{:04x} does not exist", addr);
+                        println!("Breakpoint at {} does not exist", addr);
                     }
                 }
-                Ok(Command::RemoveTempBreakpoint(addr)) => {
+                Ok(Command::RemoveTempBreakpoint(bank, addr)) => {
+                    let addr = self.resolve_addr(bank, addr);
                     if !self.temp_breakpoints.remove(&addr) {
-                        println!("Temporary breakpoint at 0x{:04x} does not exist", addr);
+                        println!("Temporary breakpoint at {} does not exist", addr);
                     }
                 }
                 Ok(Command::Watchpoint) => {
@@ -282,12 +782,104 @@ impl VM {
                         println!("Watchpoint at 0x{:04x} does not exist", addr);
                     }
                 }
-                Ok(Command::AddSymbol(addr, ref sym)) => {
+                Ok(Command::AddSymbol(bank, addr, ref sym)) => {
+                    let addr = self.resolve_addr(bank, addr);
                     self.symbols.insert(addr, &sym);
                 }
-                Ok(Command::RemoveSymbol(addr)) => {
+                Ok(Command::RemoveSymbol(bank, addr)) => {
+                    let addr = self.resolve_addr(bank, addr);
                     self.symbols.remove(addr);
                 }
+                Ok(Command::SearchStart(width)) => {
+                    let search = MemorySearch::new(&self.inter, width);
+                    println!("{} candidates", search.candidates().len());
+                    self.search = Some(search);
+                }
+                Ok(Command::SearchFilter(filter)) => {
+                    let result = match self.search {
+                        Some(ref mut search) => search.filter(&self.inter, filter),
+                        None => Ok(()),
+                    };
+                    match result {
+                        Ok(()) => self.print_search_results(),
+                        Err(e) => println!("{}", e),
+                    }
+                }
+                Ok(Command::SearchResults) => {
+                    self.print_search_results();
+                }
+                Ok(Command::SearchWatch(index)) => {
+                    if let Some((candidate, width)) = self.search_result(index) {
+                        for offset in 0..width.bytes() {
+                            self.inter.watchpoints.insert(candidate.addr.addr + offset);
+                        }
+                    }
+                }
+                Ok(Command::SearchSymbol(index, ref sym)) => {
+                    if let Some((candidate, _)) = self.search_result(index) {
+                        self.symbols.insert(candidate.addr, &sym);
+                    }
+                }
+                Ok(Command::SearchFreeze(index)) => {
+                    if let Some((candidate, width)) = self.search_result(index) {
+                        for offset in 0..width.bytes() {
+                            let addr = BankedAddr::new(candidate.addr.bank, candidate.addr.addr + offset);
+                            self.inter.freeze(addr, (candidate.value >> (offset * 8)) as u8);
+                        }
+                    }
+                }
+                Ok(Command::Frozen) => {
+                    let mut frozen: Vec<_> = self.inter.frozen().iter().collect();
+                    frozen.sort();
+                    for (addr, val) in frozen {
+                        println!("* {} = {:02x} {}", addr, val, self.symbols.describe(*addr).unwrap_or_default());
+                    }
+                }
+                Ok(Command::Thaw(bank, addr)) => {
+                    let addr = self.resolve_addr(bank, addr);
+                    if !self.inter.thaw(addr) {
+                        println!("{} is not frozen", addr);
+                    }
+                }
+                Ok(Command::Cheats) => {
+                    for (i, cheat) in self.inter.cheats.all().iter().enumerate() {
+                        println!("{:>3}: {} {:<12} {:<32} {}",
+                                 i,
+                                 if cheat.enabled { "*" } else { " " },
+                                 cheat.code,
+                                 cheat.cheat.to_string(),
+                                 cheat.name);
+                    }
+                }
+                Ok(Command::AddCheat(ref code, ref name)) => {
+                    match self.inter.cheats.add(code, name) {
+                        Ok(index) => println!("Added cheat {}", index),
+                        Err(e) => println!("{}", e),
+                    }
+                }
+                Ok(Command::EnableCheat(index)) => {
+                    if !self.inter.cheats.set_enabled(index, true) {
+                        println!("Cheat {} does not exist", index);
+                    }
+                }
+                Ok(Command::DisableCheat(index)) => {
+                    if !self.inter.cheats.set_enabled(index, false) {
+                        println!("Cheat {} does not exist", index);
+                    }
+                }
+                Ok(Command::RemoveCheat(index)) => {
+                    if !self.inter.cheats.remove(index) {
+                        println!("Cheat {} does not exist", index);
+                    }
+                }
+                Ok(Command::Screenshot(None)) => self.handle_hotkey(Hotkey::Screenshot),
+                Ok(Command::Screenshot(Some(ref file_name))) => {
+                    match self.save_screenshot(file_name) {
+                        Ok(()) => println!("Saved screenshot to {}", file_name),
+                        Err(e) => println!("Failed to save screenshot: {}", e),
+                    }
+                }
+                Ok(Command::RecordMovie(ref file_name)) => self.start_movie(file_name),
                 Ok(Command::Exit) => {
                     return true;
                 }
@@ -307,27 +899,355 @@ impl VM {
         false
     }
 
+    fn print_search_results(&self) {
+        let search = match self.search {
+            Some(ref search) => search,
+            None => {
+                println!("No search in progress");
+                return;
+            }
+        };
+
+        let candidates = search.candidates();
+        println!("{} candidates", candidates.len());
+        if candidates.len() > MAX_LISTED_RESULTS {
+            return;
+        }
+
+        for (i, c) in candidates.iter().enumerate() {
+            let value = match search.width() {
+                SearchWidth::Byte => format!("{:02x}", c.value),
+                SearchWidth::Word => format!("{:04x}", c.value),
+            };
+            println!("{:>3}: {} = {} {}", i, c.addr, value, self.symbols.describe(c.addr).unwrap_or_default());
+        }
+    }
+
+    fn search_result(&self, index: usize) -> Option<(Candidate, SearchWidth)> {
+        match self.search {
+            Some(ref search) => match search.candidates().get(index) {
+                Some(c) => Some((*c, search.width())),
+                None => {
+                    println!("No search result {}", index);
+                    None
+                }
+            },
+            None => {
+                println!("No search in progress");
+                None
+            }
+        }
+    }
+
+    fn handle_hotkey(&mut self, hotkey: Hotkey) {
+        match hotkey {
+            Hotkey::Pause => {
+                match self.mode {
+                    Mode::Running => {
+                        self.mode = Mode::Paused;
+                        println!("Paused");
+                    }
+                    Mode::Paused => {
+                        self.mode = Mode::Running;
+                        self.start_time = SteadyTime::now();
+                        println!("Resumed");
+                    }
+                    Mode::Debugging => {}
+                }
+            }
+            // Pauses when running, then each press runs one more frame
+            Hotkey::FrameAdvance => {
+                match self.mode {
+                    Mode::Running => {
+                        self.mode = Mode::Paused;
+                        println!("Paused");
+                    }
+                    Mode::Paused => self.frames_to_advance += 1,
+                    Mode::Debugging => {}
+                }
+            }
+            Hotkey::FastForward => self.toggle_speed_override(Speed::Unthrottled),
+            Hotkey::SlowMotion => self.toggle_speed_override(Speed::Multiplier(SLOW_MOTION_SPEED)),
+            Hotkey::Screenshot => {
+                let (width, height) = self.get_dimensions();
+                match self.screenshots.save(self.frame_buffer(), width, height) {
+                    Ok(path) => println!("Saved screenshot to {}", path.to_string_lossy()),
+                    Err(e) => println!("Failed to save screenshot: {}", e),
+                }
+            }
+            Hotkey::Record => {
+                let result = if self.recorder.is_some() {
+                    self.stop_recording()
+                } else {
+                    self.rom_files.next_path("recordings", "y4m").and_then(|path| self.start_recording(path))
+                };
+                if let Err(e) = result {
+                    println!("Failed to record: {}", e);
+                }
+            }
+            Hotkey::SaveState => {
+                match self.save_quick_state() {
+                    Ok(path) => println!("Saved state to {}", path.to_string_lossy()),
+                    Err(e) => println!("Failed to save state: {}", e),
+                }
+            }
+            Hotkey::LoadState => {
+                // Jumping to another point would leave the movie out of step
+                // with the machine
+                if self.movie_in_progress() {
+                    println!("Can't load a state while a movie is recording or playing");
+                } else {
+                    match self.load_quick_state() {
+                        Ok(path) => println!("Loaded state from {}", path.to_string_lossy()),
+                        Err(e) => println!("Failed to load state: {}", e),
+                    }
+                }
+            }
+        }
+    }
+
+    fn save_quick_state(&self) -> io::Result<PathBuf> {
+        let path = self.rom_files.quick_save_path()?;
+        File::create(&path)?.write_all(&self.save_state())?;
+        Ok(path)
+    }
+
+    fn load_quick_state(&mut self) -> io::Result<PathBuf> {
+        let path = self.rom_files.quick_save_path()?;
+        let mut data = Vec::new();
+        File::open(&path)?.read_to_end(&mut data)?;
+        self.load_state(&data)?;
+        Ok(path)
+    }
+
+    // The frame written is the last one the PPU finished, so the video keeps
+    // showing it while the LCD is off
+    fn update_recording(&mut self, cycles: u16, vblank: bool) {
+        let result = match self.recorder {
+            Some(ref mut recorder) => {
+                if vblank {
+                    recorder.set_frame(self.inter.frame_buffer());
+                }
+                recorder.step(cycles, self.inter.audio_output())
+            }
+            None => Ok(()),
+        };
+
+        if let Err(e) = result {
+            println!("Stopped recording: {}", e);
+            self.recorder = None;
+        }
+    }
+
+    fn toggle_speed_override(&mut self, speed: Speed) {
+        self.speed_override = if self.speed_override == Some(speed) {
+            None
+        } else {
+            Some(speed)
+        };
+        self.start_time = SteadyTime::now();
+        print_speed(self.speed());
+    }
+
+    fn run_until(&mut self, device: &mut Device, cycles: u32, stop_at_frame: bool) -> RunStatus {
+        let frame = self.frame_count;
+        let mut status = RunStatus::default();
+
+        while status.cycles < cycles && device.running() {
+            let (cycles_run, stopped) = self.step(device);
+            status.cycles += cycles_run as u32;
+            status.frame_complete = self.frame_count != frame;
+            device.update();
+
+            if stopped {
+                status.breakpoint = true;
+                break;
+            }
+            if stop_at_frame && status.frame_complete {
+                break;
+            }
+        }
+
+        status
+    }
+
+    fn break_into_debugger(&mut self) {
+        self.mode = Mode::Debugging;
+        self.cursor = self.cpu.pc;
+        self.print_cursor();
+    }
+
+    // Records a movie from here on, finishing any movie being recorded
+    fn start_movie(&mut self, file_name: &str) {
+        let header = match self.movie_header {
+            Some(ref header) => header.clone(),
+            None => {
+                println!("Movies can't be recorded without the ROM's details");
+                return;
+            }
+        };
+        if let Err(e) = self.save_movie() {
+            println!("Failed to save movie: {}", e);
+        }
+
+        self.record_movie(Movie::new(header), Path::new(file_name));
+        println!("Recording movie to {}", file_name);
+    }
+
+    fn movie_in_progress(&self) -> bool {
+        match self.movie {
+            Some(MovieState::Recording { .. }) => true,
+            Some(MovieState::Playing { ref movie, frame, .. }) => frame < movie.len(),
+            None => false,
+        }
+    }
+
+    fn update_movie(&mut self, buttons: Buttons) {
+        let frame = match self.movie {
+            Some(MovieState::Recording { ref movie, .. }) => movie.len(),
+            Some(MovieState::Playing { frame, .. }) => frame,
+            None => return,
+        };
+        let hash = if Movie::is_hashed_frame(frame) {
+            Some(self.state_hash())
+        } else {
+            None
+        };
+
+        match self.movie {
+            Some(MovieState::Recording { ref mut movie, .. }) => movie.push(buttons, hash),
+            Some(MovieState::Playing { ref movie, ref mut frame, ref mut desynced }) => {
+                if let (Some(recorded), Some(hash)) = (movie.hash(*frame), hash) {
+                    if !*desynced && recorded != hash {
+                        println!("Movie desynced at frame {}, state hash {:08x} was {:08x} when recorded",
+                                 *frame, hash, recorded);
+                        *desynced = true;
+                    }
+                }
+
+                *frame += 1;
+                if *frame == movie.len() {
+                    println!("Movie finished after {} frames", movie.len());
+                }
+            }
+            None => {}
+        }
+    }
+
+    // A hash of the CPU registers and the memory games keep their state in,
+    // for spotting where a movie's playback diverged from its recording
+    fn state_hash(&self) -> u32 {
+        let mut digest = crc32::Digest::new(crc32::IEEE);
+        let regs = [self.cpu.af(), self.cpu.bc(), self.cpu.de(), self.cpu.hl(), self.cpu.sp, self.cpu.pc];
+        for reg in &regs {
+            digest.write(&[(reg >> 8) as u8, *reg as u8]);
+        }
+
+        let ranges = [0x8000..0xa000, 0xc000..0xe000, 0xfe00..0xfea0, 0xff00..0x10000];
+        for range in ranges.iter().cloned() {
+            let bytes: Vec<u8> = range.map(|addr: u32| self.inter.read_byte(addr as u16)).collect();
+            digest.write(&bytes);
+        }
+
+        digest.sum32()
+    }
+
+    fn update_call_stack(&mut self, events: &[Event]) {
+        for event in events {
+            match *event {
+                Event::Call { target: addr, return_addr: ret } => {
+                    if self.call_stack.len() >= MAX_CALL_DEPTH {
+                        self.call_stack.remove(0);
+                    }
+                    self.call_stack.push(CallFrame {
+                        target: self.inter.banked_addr(addr),
+                        return_addr: self.inter.banked_addr(ret),
+                        interrupt: false,
+                    });
+                }
+                Event::Interrupt(_) => {
+                    if let Some(frame) = self.call_stack.last_mut() {
+                        frame.interrupt = true;
+                    }
+                }
+                Event::Return(addr) => {
+                    // Unwind to the frame that expected this return address, a
+                    // return to anywhere else was a manipulated stack so leave
+                    // the frames alone
+                    if let Some(index) = self.call_stack.iter().rposition(|f| f.return_addr.addr == addr) {
+                        self.call_stack.truncate(index);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Executes one instruction, running any call it makes through to its
+    // return. Returns true if a breakpoint or watchpoint stopped execution early.
+    fn step_over(&mut self, device: &mut Device) -> bool {
+        let depth = self.call_stack.len();
+        let (_, mut stopped) = self.step(device);
+
+        while !stopped && device.running() && self.call_stack.len() > depth {
+            stopped = self.step(device).1;
+            device.update();
+        }
+
+        stopped
+    }
+
+    // Runs until the current frame returns to its caller
+    fn finish(&mut self, device: &mut Device) {
+        let depth = self.call_stack.len();
+
+        while device.running() && self.call_stack.len() >= depth {
+            if self.step(device).1 {
+                break;
+            }
+            device.update();
+        }
+    }
+
+    fn print_backtrace(&self) {
+        let pc = self.inter.banked_addr(self.cpu.pc);
+        println!("#0  {}  {}", pc, self.symbols.describe(pc).unwrap_or_default());
+
+        for (i, frame) in self.call_stack.iter().rev().enumerate() {
+            println!("#{:<2} {}  {}",
+                     i + 1,
+                     frame.return_addr,
+                     self.symbols.describe(frame.return_addr).unwrap_or_default());
+        }
+    }
+
+    // Addresses given without a bank refer to whichever bank is mapped there now
+    fn resolve_addr(&self, bank: Option<u16>, addr: u16) -> BankedAddr {
+        match bank {
+            Some(bank) => BankedAddr::new(bank, addr),
+            None => self.inter.banked_addr(addr),
+        }
+    }
+
     fn print_cursor(&self) {
         print!("gb-rs 0x{:04x} >>> ", self.cursor);
         stdout().flush().unwrap();
     }
 
     fn disassemble_instruction(&self) -> u16 {
-        if self.breakpoints.contains(&self.cursor) {
+        let cursor = self.inter.banked_addr(self.cursor);
+
+        if self.breakpoints.contains(&cursor) {
             print!("* ");
         } else {
             print!("  ");
         }
 
-        let symbol = if let Some(sym) = self.symbols.get(self.cursor) {
-            sym
-        } else {
-            ""
-        };
+        let symbol = self.symbols.describe(cursor).unwrap_or_default();
 
         print!("{:20}", symbol);
 
-        print!("0x{:04x}  ", self.cursor);
+        print!("{}  ", cursor);
         let opcode = decode_instr(&self.inter, &self.symbols, self.cursor);
 
         println!("{}", opcode);
@@ -337,6 +1257,24 @@ impl VM {
 }
 
 
+fn print_speed(speed: Speed) {
+    match speed {
+        Speed::Multiplier(m) => println!("Running at {}x speed", m),
+        Speed::Unthrottled => println!("Running unthrottled"),
+    }
+}
+
+fn step_cpu(cpu: &mut Cpu, interconnect: &mut Interconnect, device: &mut Device, events: &mut Vec<Event>) -> u16 {
+    let mut bus = Bus{
+        interconnect: interconnect,
+        device: device,
+        events: events,
+        cycles: 0,
+    };
+    cpu.step(&mut bus);
+    bus.cycles
+}
+
 fn read_stdin() -> String {
     let mut input = String::new();
     stdin().read_line(&mut input).unwrap();
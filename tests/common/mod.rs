@@ -1,10 +1,10 @@
-extern crate byteorder;
-extern crate crc;
 extern crate gameboy;
+extern crate png;
 
+use std::env;
+use std::fs::{self, File};
+use std::io::BufWriter;
 use std::path::Path;
-use self::byteorder::{ByteOrder, LittleEndian};
-use self::crc::crc32::checksum_ieee;
 use self::gameboy::bootrom::Bootrom;
 use self::gameboy::cartridge::Cartridge;
 use self::gameboy::config::model::{Model, DEFAULT_MODEL_PRIORITY};
@@ -12,65 +12,92 @@ use self::gameboy::interconnect::Interconnect;
 use self::gameboy::vm::VM;
 use self::gameboy::device::{self, Device};
 use self::gameboy::symbols::Symbols;
+use self::gameboy::input::Buttons;
+use self::gameboy::screenshot::save_png;
 
-struct TestDevice {
-    buffer: Box<[u32]>,
-}
+const REFERENCE_DIR: &str = "tests/reference";
+const SCREENSHOT_DIR: &str = "target/test-screenshots";
 
-impl TestDevice {
-    fn new(width: usize, height: usize) -> Self {
-        TestDevice {
-            buffer: vec![0; width * height].into_boxed_slice(),
-        }
-    }
-}
+struct TestDevice;
 
 impl Device for TestDevice {
     fn update(&mut self) {}
 
-    fn set_frame_buffer(&mut self, buffer: &[u32]) {
-        #[cfg_attr(feature = "cargo-clippy", allow(needless_range_loop))]
-        for i in 0..self.buffer.len() {
-            self.buffer[i] = buffer[i];
-        }
-    }
+    fn set_frame_buffer(&mut self, _: &[u32]) {}
 
     fn key_down(&self, _: device::Key) -> bool {
         false
     }
 
+    fn buttons(&mut self) -> Option<Buttons> {
+        Some(Buttons::default())
+    }
+
     fn running(&self) -> bool {
         true
     }
 }
 
+// Runs the ROM and compares the last frame against a reference image in
+// tests/reference named after the ROM, e.g. tests/reference/cpu_instrs.png.
+// References are greyscale PNGs with one grey level per shade, so they don't
+// depend on the colours the frame is shown in. On a mismatch the frame and an
+// image with the differing pixels in red are written to
+// target/test-screenshots. Setting SCIMITAR_BLESS=1 writes the frames as the
+// new references instead.
 #[allow(dead_code)]
-pub fn run_test_with_hash<P: AsRef<Path>>(file_name: P, model: Model, hash: u32) {
+pub fn run_test_with_reference<P: AsRef<Path>>(file_name: P, model: Model) {
     let cartridge = Cartridge::load(file_name.as_ref()).unwrap();
     let bootrom = Bootrom::lookup(&[model]);
     let interconnect = Interconnect::new(bootrom, cartridge);
 
-    let mut device = TestDevice::new(interconnect.get_width(), interconnect.get_height());
+    let (width, height) = (interconnect.get_width(), interconnect.get_height());
+    let mut device = TestDevice;
 
-    let mut vm = VM::new(interconnect, false, Symbols::default());
+    let mut vm = VM::without_console(interconnect, Symbols::default());
 
     for _ in 0..30000000 {
         vm.step(&mut device);
     }
+    // Finish the frame being drawn so the whole screen is compared
+    while !vm.run_frame(&mut device).frame_complete {}
+    let shades: Vec<u8> = vm.frame_pixels().iter().map(|p| p.shade).collect();
 
-    let mut bytes = Vec::new();
-
-    for b in device.buffer.iter() {
-        let mut parts = vec![0;4];
-        // BigEndian::write_u32(&mut parts, *b);
-        LittleEndian::write_u32(&mut parts, *b);
+    let name = file_name.as_ref().file_stem().unwrap().to_string_lossy().into_owned();
+    let reference = Path::new(REFERENCE_DIR).join(format!("{}.png", name));
 
-        for p in parts {
-            bytes.push(p);
-        }
+    if env::var_os("SCIMITAR_BLESS").is_some() {
+        fs::create_dir_all(REFERENCE_DIR).unwrap();
+        save_shades(&reference, &shades, width, height);
+        return;
     }
 
-    assert_eq!(hash, checksum_ieee(&bytes));
+    let expected = match load_shades(&reference, width, height) {
+        Ok(expected) => expected,
+        Err(e) => panic!("Couldn't load reference {}: {}, run with SCIMITAR_BLESS=1 to create it",
+                         reference.to_string_lossy(), e),
+    };
+
+    let mismatches = shades.iter().zip(&expected).filter(|&(a, b)| a != b).count();
+    if mismatches > 0 {
+        let dir = Path::new(SCREENSHOT_DIR);
+        fs::create_dir_all(dir).unwrap();
+        let actual_path = dir.join(format!("{}.png", name));
+        let diff_path = dir.join(format!("{}-diff.png", name));
+
+        save_shades(&actual_path, &shades, width, height);
+        let diff: Vec<u32> = shades.iter()
+            .zip(&expected)
+            .map(|(&a, &e)| if a == e { grey(e) | 0xff000000 } else { 0xffff0000 })
+            .collect();
+        save_png(&diff_path, &diff, width, height, 1).unwrap();
+
+        panic!("{} pixels differ from {}, frame saved to {} and differences to {}",
+               mismatches,
+               reference.to_string_lossy(),
+               actual_path.to_string_lossy(),
+               diff_path.to_string_lossy());
+    }
 }
 
 #[allow(dead_code)]
@@ -79,9 +106,9 @@ pub fn run_test_till_ed<P: AsRef<Path>>(file_name: P, model: Model) {
     let bootrom = Bootrom::lookup(&[model]);
     let interconnect = Interconnect::new(bootrom, cartridge);
 
-    let mut device = TestDevice::new(interconnect.get_width(), interconnect.get_height());
+    let mut device = TestDevice;
 
-    let mut vm = VM::new(interconnect, false, Symbols::default());
+    let mut vm = VM::without_console(interconnect, Symbols::default());
 
     while vm.get_next_instruction() != 0xed {
         vm.step(&mut device);
@@ -102,3 +129,40 @@ pub fn run_all_models_till_ed<P: AsRef<Path>>(file_name: P) {
         run_test_till_ed(file_name.as_ref(), *m);
     }
 }
+
+// Shade 0 is white and shade 3 black, with the others evenly between
+fn grey(shade: u8) -> u32 {
+    let level = 255 - 85 * shade as u32;
+    (level << 16) | (level << 8) | level
+}
+
+fn save_shades(file_name: &Path, shades: &[u8], width: usize, height: usize) {
+    let file = BufWriter::new(File::create(file_name).unwrap());
+    let mut encoder = png::Encoder::new(file, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let data: Vec<u8> = shades.iter().map(|&s| grey(s) as u8).collect();
+    encoder.write_header().unwrap().write_image_data(&data).unwrap();
+}
+
+fn load_shades(file_name: &Path, width: usize, height: usize) -> Result<Vec<u8>, String> {
+    let file = File::open(file_name).map_err(|e| e.to_string())?;
+    let (info, mut reader) = png::Decoder::new(file).read_info().map_err(|e| e.to_string())?;
+    if info.color_type != png::ColorType::Grayscale || info.bit_depth != png::BitDepth::Eight {
+        return Err("expected an 8 bit greyscale image".to_owned());
+    }
+    if info.width as usize != width || info.height as usize != height {
+        return Err(format!("expected a {}x{} image", width, height));
+    }
+
+    let mut data = vec![0; info.buffer_size()];
+    reader.next_frame(&mut data).map_err(|e| e.to_string())?;
+
+    data.iter()
+        .map(|&level| match (0..4).find(|&s| grey(s) as u8 == level) {
+            Some(shade) => Ok(shade),
+            None => Err(format!("grey level {} isn't one of the four shades", level)),
+        })
+        .collect()
+}